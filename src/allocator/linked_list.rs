@@ -1,5 +1,7 @@
+use alloc::vec::Vec;
 use core::alloc::{AllocErr, GlobalAlloc, Layout};
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use linked_list_allocator::Heap;
 use spin::Mutex;
 
@@ -7,12 +9,39 @@ use paging::ActivePageTable;
 
 static HEAP: Mutex<Option<Heap>> = Mutex::new(None);
 
+/// Bytes allocated from the heap
+static HEAP_USED: AtomicUsize = ATOMIC_USIZE_INIT;
+
 pub struct Allocator;
 
 impl Allocator {
     pub unsafe fn init(offset: usize, size: usize) {
         *HEAP.lock() = Some(Heap::new(offset, size));
     }
+
+    /// Size of the heap, which grows when full
+    pub fn size() -> usize {
+        if let Some(ref heap) = *HEAP.lock() {
+            heap.size()
+        } else {
+            0
+        }
+    }
+
+    /// Bytes allocated from the heap
+    pub fn used() -> usize {
+        HEAP_USED.load(Ordering::SeqCst)
+    }
+
+    /// Allocations in use for each slab size class, empty without slabs
+    pub fn size_classes() -> Vec<(usize, usize)> {
+        Vec::new()
+    }
+
+    /// Allocations in use that are too large for every slab, not counted without slabs
+    pub fn large_allocations() -> Option<usize> {
+        None
+    }
 }
 
 unsafe impl GlobalAlloc for Allocator {
@@ -40,14 +69,18 @@ unsafe impl GlobalAlloc for Allocator {
                         panic!("__rust_allocate: heap not initialized");
                     }
                 },
-                other => return other.ok().map_or(0 as *mut u8, |allocation| allocation.as_ptr()),
+                other => return other.ok().map_or(0 as *mut u8, |allocation| {
+                    HEAP_USED.fetch_add(layout.size(), Ordering::SeqCst);
+                    allocation.as_ptr()
+                }),
             }
         }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         if let Some(ref mut heap) = *HEAP.lock() {
-            heap.deallocate(NonNull::new_unchecked(ptr), layout)
+            heap.deallocate(NonNull::new_unchecked(ptr), layout);
+            HEAP_USED.fetch_sub(layout.size(), Ordering::SeqCst);
         } else {
             panic!("__rust_deallocate: heap not initialized");
         }
@@ -1,21 +1,72 @@
+use alloc::vec::Vec;
 use core::alloc::{Alloc, AllocErr, Layout};
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use spin::Mutex;
 use slab_allocator::Heap;
 
 static HEAP: Mutex<Option<Heap>> = Mutex::new(None);
 
+/// Size of the heap
+static HEAP_SIZE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Bytes allocated from the heap
+static HEAP_USED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Sizes of the slabs, larger allocations come from a linked list
+const SLAB_SIZES: [usize; 7] = [64, 128, 256, 512, 1024, 2048, 4096];
+
+/// Allocations in use in each slab, and last from the linked list
+static SLAB_USED: [AtomicUsize; 8] = [
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT
+];
+
+/// The slab used for `layout`, as chosen by the heap
+fn slab_index(layout: &Layout) -> usize {
+    let size = if layout.size() > layout.align() { layout.size() } else { layout.align() };
+    SLAB_SIZES.iter().position(|&slab_size| size <= slab_size).unwrap_or(SLAB_SIZES.len())
+}
+
 pub struct Allocator;
 
 impl Allocator {
     pub unsafe fn init(offset: usize, size: usize) {
         *HEAP.lock() = Some(Heap::new(offset, size));
+        HEAP_SIZE.store(size, Ordering::SeqCst);
+    }
+
+    /// Size of the heap
+    pub fn size() -> usize {
+        HEAP_SIZE.load(Ordering::SeqCst)
+    }
+
+    /// Bytes allocated from the heap
+    pub fn used() -> usize {
+        HEAP_USED.load(Ordering::SeqCst)
+    }
+
+    /// Allocations in use for each slab size class
+    pub fn size_classes() -> Vec<(usize, usize)> {
+        SLAB_SIZES.iter().zip(SLAB_USED.iter()).map(|(&size, used)| {
+            (size, used.load(Ordering::SeqCst))
+        }).collect()
+    }
+
+    /// Allocations in use that are too large for every slab, taken from the linked list
+    pub fn large_allocations() -> Option<usize> {
+        Some(SLAB_USED[SLAB_SIZES.len()].load(Ordering::SeqCst))
     }
 }
 
 unsafe impl<'a> Alloc for &'a Allocator {
     unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
         if let Some(ref mut heap) = *HEAP.lock() {
-            heap.allocate(layout)
+            let res = heap.allocate(layout.clone());
+            if res.is_ok() {
+                HEAP_USED.fetch_add(layout.size(), Ordering::SeqCst);
+                SLAB_USED[slab_index(&layout)].fetch_add(1, Ordering::SeqCst);
+            }
+            res
         } else {
             panic!("__rust_allocate: heap not initialized");
         }
@@ -23,6 +74,8 @@ unsafe impl<'a> Alloc for &'a Allocator {
 
     unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
         if let Some(ref mut heap) = *HEAP.lock() {
+            HEAP_USED.fetch_sub(layout.size(), Ordering::SeqCst);
+            SLAB_USED[slab_index(&layout)].fetch_sub(1, Ordering::SeqCst);
             heap.deallocate(ptr, layout)
         } else {
             panic!("__rust_deallocate: heap not initialized");
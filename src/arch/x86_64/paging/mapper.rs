@@ -1,7 +1,8 @@
 use core::mem;
 use core::ptr::Unique;
+use core::sync::atomic::Ordering;
 
-use memory::{allocate_frames, deallocate_frames, Frame};
+use memory::{allocate_frames, deallocate_frames, Frame, PAGE_TABLE_FRAMES};
 
 use super::{ActivePageTable, Page, PAGE_SIZE, PhysicalAddress, VirtualAddress};
 use super::entry::EntryFlags;
@@ -162,6 +163,7 @@ impl Mapper {
                     p2.decrement_entry_count();
                     p2[page.p2_index()].set_unused();
                     deallocate_frames(p1_frame, 1);
+                    PAGE_TABLE_FRAMES.fetch_sub(1, Ordering::SeqCst);
                 } else {
                     panic!("unmap_inner({:X}): p1_frame not found", page.start_address().get());
                 }
@@ -178,6 +180,7 @@ impl Mapper {
                 p3.decrement_entry_count();
                 p3[page.p3_index()].set_unused();
                 deallocate_frames(p2_frame, 1);
+                PAGE_TABLE_FRAMES.fetch_sub(1, Ordering::SeqCst);
             } else {
                 panic!("unmap_inner({:X}): p2_frame not found", page.start_address().get());
             }
@@ -194,6 +197,7 @@ impl Mapper {
             p4.decrement_entry_count();
             p4[page.p4_index()].set_unused();
             deallocate_frames(p3_frame, 1);
+            PAGE_TABLE_FRAMES.fetch_sub(1, Ordering::SeqCst);
         } else {
             panic!("unmap_inner({:X}): p3_frame not found", page.start_address().get());
         }
@@ -3,9 +3,10 @@
 
 use core::{mem, ptr};
 use core::ops::{Deref, DerefMut};
+use core::sync::atomic::Ordering;
 use x86::shared::{control_regs, msr, tlb};
 
-use memory::{allocate_frames, Frame};
+use memory::{allocate_frames, Frame, PAGE_TABLE_FRAMES};
 
 use self::entry::EntryFlags;
 use self::mapper::Mapper;
@@ -78,7 +79,7 @@ unsafe fn init_tcb(cpu_id: usize) -> usize {
 /// Initialize paging
 ///
 /// Returns page table and thread control block offset
-pub unsafe fn init(cpu_id: usize, kernel_start: usize, kernel_end: usize, stack_start: usize, stack_end: usize) -> (ActivePageTable, usize) {
+pub unsafe fn init(cpu_id: usize, kernel_start: usize, kernel_end: usize, stack_start: usize, stack_end: usize, initfs_start: usize, initfs_end: usize) -> (ActivePageTable, usize) {
     extern {
         /// The starting byte of the text (code) data segment.
         static mut __text_start: u8;
@@ -114,6 +115,7 @@ pub unsafe fn init(cpu_id: usize, kernel_start: usize, kernel_end: usize, stack_
 
     let mut new_table = {
         let frame = allocate_frames(1).expect("no more frames in paging::init new_table");
+        PAGE_TABLE_FRAMES.fetch_add(1, Ordering::SeqCst);
         InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
     };
 
@@ -130,6 +132,18 @@ pub unsafe fn init(cpu_id: usize, kernel_start: usize, kernel_end: usize, stack_
             }
         }
 
+        // Remap initfs read-only, no execute
+        if initfs_start < initfs_end {
+            let start_frame = Frame::containing_address(PhysicalAddress::new(initfs_start - ::KERNEL_OFFSET));
+            let end_frame = Frame::containing_address(PhysicalAddress::new(initfs_end - ::KERNEL_OFFSET - 1));
+            for frame in Frame::range_inclusive(start_frame, end_frame) {
+                let page = Page::containing_address(VirtualAddress::new(frame.start_address().get() + ::KERNEL_OFFSET));
+                let result = mapper.map_to(page, frame, EntryFlags::PRESENT | EntryFlags::GLOBAL | EntryFlags::NO_EXECUTE);
+                // The flush can be ignored as this is not the active table. See later active_table.switch
+                /* unsafe */ { result.ignore(); }
+            }
+        }
+
         // Map all frames in kernel
         {
             let start_frame = Frame::containing_address(PhysicalAddress::new(kernel_start));
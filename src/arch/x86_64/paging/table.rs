@@ -3,8 +3,9 @@
 
 use core::marker::PhantomData;
 use core::ops::{Index, IndexMut};
+use core::sync::atomic::Ordering;
 
-use memory::allocate_frames;
+use memory::{allocate_frames, PAGE_TABLE_FRAMES};
 
 use super::entry::{EntryFlags, Entry};
 use super::ENTRY_COUNT;
@@ -95,6 +96,7 @@ impl<L> Table<L> where L: HierarchicalLevel {
             assert!(!self[index].flags().contains(EntryFlags::HUGE_PAGE),
                     "next_table_create does not support huge pages");
             let frame = allocate_frames(1).expect("no frames available");
+            PAGE_TABLE_FRAMES.fetch_add(1, Ordering::SeqCst);
             self.increment_entry_count();
             self[index].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE /* Allow users to go down the page table, implement permissions at the page level */);
             self.next_table_mut(index).unwrap().zero();
@@ -3,7 +3,7 @@
 /// It must create the IDT with the correct entries, those entries are
 /// defined in other files inside of the `arch` module
 
-use core::slice;
+use core::{mem, slice};
 use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 
 use allocator;
@@ -44,12 +44,21 @@ pub struct KernelArgs {
     stack_size: u64,
     env_base: u64,
     env_size: u64,
+    /// Size of the arguments written by the bootloader, the fields after it are only read if
+    /// they are covered
+    args_size: u64,
+    /// A cpio archive in the "newc" format for `initfs:`, or zero base or size for none. Like
+    /// `stack_base`, the base is the physical address of the archive plus `KERNEL_OFFSET`, and
+    /// the kernel maps it there read-only. It is read in place for as long as the kernel runs,
+    /// so the bootloader must leave its frames out of the free areas of the memory map
+    initfs_base: u64,
+    initfs_size: u64,
 }
 
 /// The entry to Rust, all things must be initialized
 #[no_mangle]
 pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
-    let env = {
+    let (env, initfs) = {
         let args = &*args_ptr;
 
         let kernel_base = args.kernel_base as usize;
@@ -58,6 +67,13 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
         let stack_size = args.stack_size as usize;
         let env_base = args.env_base as usize;
         let env_size = args.env_size as usize;
+        let (initfs_base, initfs_size) = if args.args_size as usize >= mem::size_of::<KernelArgs>()
+            && args.initfs_base != 0 && args.initfs_size != 0
+        {
+            (args.initfs_base as usize, args.initfs_size as usize)
+        } else {
+            (0, 0)
+        };
 
         // BSS should already be zero
         {
@@ -71,6 +87,7 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
         println!("Kernel: {:X}:{:X}", kernel_base, kernel_base + kernel_size);
         println!("Stack: {:X}:{:X}", stack_base, stack_base + stack_size);
         println!("Env: {:X}:{:X}", env_base, env_base + env_size);
+        println!("Initfs: {:X}:{:X}", initfs_base, initfs_base + initfs_size);
 
         // Set up GDT before paging
         gdt::init();
@@ -82,7 +99,7 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
         memory::init(0, kernel_base + ((kernel_size + 4095)/4096) * 4096);
 
         // Initialize paging
-        let (mut active_table, tcb_offset) = paging::init(0, kernel_base, kernel_base + kernel_size, stack_base, stack_base + stack_size);
+        let (mut active_table, tcb_offset) = paging::init(0, kernel_base, kernel_base + kernel_size, stack_base, stack_base + stack_size, initfs_base, initfs_base + initfs_size);
 
         // Set up GDT after paging with TLS
         gdt::init_paging(tcb_offset, stack_base + stack_size);
@@ -131,10 +148,19 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
 
         BSP_READY.store(true, Ordering::SeqCst);
 
-        slice::from_raw_parts(env_base as *const u8, env_size)
+        let initfs: &'static [u8] = if initfs_size == 0 {
+            &[]
+        } else {
+            slice::from_raw_parts(initfs_base as *const u8, initfs_size)
+        };
+
+        (
+            slice::from_raw_parts(env_base as *const u8, env_size),
+            initfs
+        )
     };
 
-    ::kmain(CPU_COUNT.load(Ordering::SeqCst), env);
+    ::kmain(CPU_COUNT.load(Ordering::SeqCst), env, initfs);
 }
 
 #[repr(packed)]
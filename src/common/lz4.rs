@@ -0,0 +1,326 @@
+//! LZ4 decompression, for images loaded with the kernel
+//!
+//! Only frames with independent blocks are supported, so that any block can be
+//! decompressed on its own. Checksums are not verified
+
+use alloc::sync::Arc;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::cmp;
+use spin::Mutex;
+
+use syscall::error::*;
+
+/// Magic number at the start of an LZ4 frame
+pub const LZ4_MAGIC: u32 = 0x184D_2204;
+
+/// Bytes of decompressed blocks kept by each frame, at least one block is kept
+pub const LZ4_CACHE_SIZE: usize = 1024 * 1024;
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data.get(offset .. offset + 4).ok_or(Error::new(EINVAL))?;
+    Ok(bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24)
+}
+
+/// Read the extra bytes of a literal or match length
+fn read_length(src: &[u8], s: &mut usize, mut length: usize) -> Result<usize> {
+    loop {
+        let byte = *src.get(*s).ok_or(Error::new(EIO))?;
+        *s += 1;
+        length += byte as usize;
+        if byte != 255 {
+            return Ok(length);
+        }
+    }
+}
+
+/// Decompress an LZ4 block into `dst`, returning the decompressed size
+pub fn decompress_block(src: &[u8], dst: &mut [u8]) -> Result<usize> {
+    let mut s = 0;
+    let mut d = 0;
+    while s < src.len() {
+        let token = src[s];
+        s += 1;
+
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals = read_length(src, &mut s, literals)?;
+        }
+        {
+            let literal = src.get(s .. s + literals).ok_or(Error::new(EIO))?;
+            dst.get_mut(d .. d + literals).ok_or(Error::new(EIO))?.copy_from_slice(literal);
+        }
+        s += literals;
+        d += literals;
+
+        // The last sequence only has literals
+        if s == src.len() {
+            break;
+        }
+
+        let offset = {
+            let bytes = src.get(s .. s + 2).ok_or(Error::new(EIO))?;
+            bytes[0] as usize | (bytes[1] as usize) << 8
+        };
+        s += 2;
+        if offset == 0 || offset > d {
+            return Err(Error::new(EIO));
+        }
+
+        let mut length = (token & 0xF) as usize;
+        if length == 15 {
+            length = read_length(src, &mut s, length)?;
+        }
+        length += 4;
+        if d + length > dst.len() {
+            return Err(Error::new(EIO));
+        }
+
+        // Matches may overlap the bytes they produce
+        for i in d .. d + length {
+            dst[i] = dst[i - offset];
+        }
+        d += length;
+    }
+
+    Ok(d)
+}
+
+/// A block of a frame
+struct Block {
+    offset: usize,
+    size: usize,
+    /// Stored without compression
+    raw: bool,
+}
+
+/// An LZ4 frame that can be read at any offset, decompressing one block at a time
+pub struct Lz4Frame {
+    data: &'static [u8],
+    block_size: usize,
+    blocks: Vec<Block>,
+    len: usize,
+    /// Recently used blocks by index, the most recent last
+    cache: Mutex<VecDeque<(usize, Arc<Vec<u8>>)>>,
+}
+
+impl Lz4Frame {
+    /// Check if `data` starts with an LZ4 frame
+    pub fn is_frame(data: &[u8]) -> bool {
+        read_u32(data, 0).ok() == Some(LZ4_MAGIC)
+    }
+
+    pub fn new(data: &'static [u8]) -> Result<Lz4Frame> {
+        if read_u32(data, 0)? != LZ4_MAGIC {
+            return Err(Error::new(EINVAL));
+        }
+
+        let flags = *data.get(4).ok_or(Error::new(EINVAL))?;
+        let block_desc = *data.get(5).ok_or(Error::new(EINVAL))?;
+        if flags >> 6 != 0b01 || flags & (1 << 5) == 0 {
+            // Unknown version, or blocks that depend on earlier ones
+            return Err(Error::new(EINVAL));
+        }
+        let block_checksum = flags & (1 << 4) != 0;
+        let content_size = flags & (1 << 3) != 0;
+        let dict_id = flags & 1 != 0;
+
+        let block_size = match (block_desc >> 4) & 0x7 {
+            4 => 64 * 1024,
+            5 => 256 * 1024,
+            6 => 1024 * 1024,
+            7 => 4 * 1024 * 1024,
+            _ => return Err(Error::new(EINVAL))
+        };
+
+        // Skip the optional content size and dictionary ID, and the header checksum
+        let mut offset = 6;
+        if content_size {
+            offset += 8;
+        }
+        if dict_id {
+            offset += 4;
+        }
+        offset += 1;
+
+        let mut blocks = Vec::new();
+        loop {
+            let header = read_u32(data, offset)?;
+            offset += 4;
+            if header == 0 {
+                break;
+            }
+
+            let size = (header & 0x7FFF_FFFF) as usize;
+            if offset + size > data.len() {
+                return Err(Error::new(EINVAL));
+            }
+            blocks.push(Block {
+                offset: offset,
+                size: size,
+                raw: header & 0x8000_0000 != 0,
+            });
+
+            offset += size;
+            if block_checksum {
+                offset += 4;
+            }
+        }
+
+        let mut frame = Lz4Frame {
+            data: data,
+            block_size: block_size,
+            blocks: blocks,
+            len: 0,
+            cache: Mutex::new(VecDeque::new()),
+        };
+
+        // Every block but the last is full
+        if let Some(last) = frame.blocks.len().checked_sub(1) {
+            let last_len = frame.block(last)?.len();
+            frame.len = last * block_size + last_len;
+        }
+
+        Ok(frame)
+    }
+
+    /// Size of the decompressed data
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Decompress a block into `data`, which may be a buffer of an evicted block
+    fn decompress(&self, index: usize, data: &mut Vec<u8>) -> Result<()> {
+        let block = self.blocks.get(index).ok_or(Error::new(EINVAL))?;
+        let src = &self.data[block.offset .. block.offset + block.size];
+
+        data.clear();
+        if block.raw {
+            data.extend_from_slice(src);
+        } else {
+            data.resize(self.block_size, 0);
+            let count = decompress_block(src, data)?;
+            data.truncate(count);
+        }
+
+        if data.len() > self.block_size || (index + 1 < self.blocks.len() && data.len() != self.block_size) {
+            return Err(Error::new(EIO));
+        }
+
+        Ok(())
+    }
+
+    fn block(&self, index: usize) -> Result<Arc<Vec<u8>>> {
+        {
+            let mut cache = self.cache.lock();
+            if let Some(i) = cache.iter().position(|&(cached, _)| cached == index) {
+                let entry = cache.remove(i).unwrap();
+                let data = entry.1.clone();
+                cache.push_back(entry);
+                return Ok(data);
+            }
+        }
+
+        // Make room for the block, reusing the buffer of an evicted block that is not being read
+        let mut data = Vec::new();
+        {
+            let mut cache = self.cache.lock();
+            let mut cached: usize = cache.iter().map(|&(_, ref data)| data.len()).sum();
+            while ! cache.is_empty() && cached + self.block_size > LZ4_CACHE_SIZE {
+                if let Some((_, evicted)) = cache.pop_front() {
+                    cached -= evicted.len();
+                    if let Ok(evicted) = Arc::try_unwrap(evicted) {
+                        data = evicted;
+                    }
+                }
+            }
+        }
+
+        self.decompress(index, &mut data)?;
+        let data = Arc::new(data);
+
+        self.cache.lock().push_back((index, data.clone()));
+
+        Ok(data)
+    }
+
+    /// Read decompressed data starting at `offset`
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buf.len() && offset + i < self.len {
+            let position = offset + i;
+            let block = self.block(position / self.block_size)?;
+            let start = position % self.block_size;
+            let count = cmp::min(buf.len() - i, block.len().saturating_sub(start));
+            if count == 0 {
+                break;
+            }
+            buf[i .. i + count].copy_from_slice(&block[start .. start + count]);
+            i += count;
+        }
+        Ok(i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decompress_block, Lz4Frame};
+    use syscall::error::{Error, EIO};
+
+    #[test]
+    fn literals() {
+        let mut dst = [0; 32];
+        assert_eq!(decompress_block(&[0x50, b'h', b'e', b'l', b'l', b'o'], &mut dst), Ok(5));
+        assert_eq!(&dst[..5], b"hello");
+
+        // Lengths of 15 and more continue in the following bytes
+        let mut src = vec![0xF0, 5];
+        src.extend_from_slice(b"twenty bytes of text");
+        assert_eq!(decompress_block(&src, &mut dst), Ok(20));
+        assert_eq!(&dst[..20], b"twenty bytes of text");
+    }
+
+    #[test]
+    fn matches() {
+        // A match that overlaps the bytes it produces, then the last literals
+        let mut dst = [0; 32];
+        assert_eq!(decompress_block(&[0x35, b'a', b'b', b'c', 3, 0, 0x10, b'!'], &mut dst), Ok(13));
+        assert_eq!(&dst[..13], b"abcabcabcabc!");
+    }
+
+    #[test]
+    fn invalid() {
+        let mut dst = [0; 4];
+        // Does not fit
+        assert_eq!(decompress_block(&[0x50, b'h', b'e', b'l', b'l', b'o'], &mut dst), Err(Error::new(EIO)));
+        // Truncated literals
+        assert_eq!(decompress_block(&[0x50, b'h'], &mut dst), Err(Error::new(EIO)));
+        // Offset before the start of the block
+        assert_eq!(decompress_block(&[0x10, b'a', 2, 0], &mut dst), Err(Error::new(EIO)));
+        // Offset of zero
+        assert_eq!(decompress_block(&[0x10, b'a', 0, 0], &mut dst), Err(Error::new(EIO)));
+    }
+
+    #[test]
+    fn frame() {
+        static FRAME: [u8; 20] = [
+            0x04, 0x22, 0x4D, 0x18,
+            // Independent blocks of 64 KiB, then the header checksum
+            0x60, 0x40, 0x82,
+            // Block stored without compression
+            0x05, 0x00, 0x00, 0x80, b'h', b'e', b'l', b'l', b'o',
+            // End mark
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        assert!(Lz4Frame::is_frame(&FRAME));
+
+        let frame = Lz4Frame::new(&FRAME).unwrap();
+        assert_eq!(frame.len(), 5);
+        let mut buf = [0; 3];
+        assert_eq!(frame.read_at(1, &mut buf), Ok(3));
+        assert_eq!(&buf, b"ell");
+
+        // Missing end mark
+        assert!(Lz4Frame::new(&FRAME[..16]).is_err());
+    }
+}
@@ -1,2 +1,4 @@
 #[macro_use]
 pub mod int_like;
+
+pub mod lz4;
@@ -2,7 +2,8 @@
 // The lower 256 PML4 entries are reserved for userspace
 // Each PML4 entry references up to 512 GB of memory
 // The top (511) PML4 is reserved for recursive mapping
-// The second from the top (510) PML4 is reserved for the kernel
+// The second from the top (510) PML4 is reserved for the kernel, its upper half holds the
+// kernel windows of memory shared with user contexts
     /// The size of a single PML4
     pub const PML4_SIZE: usize = 0x0000_0080_0000_0000;
     pub const PML4_MASK: usize = 0x0000_ff80_0000_0000;
@@ -27,6 +28,13 @@
     /// Size of kernel percpu variables
     pub const KERNEL_PERCPU_SIZE: usize = 64 * 1024; // 64 KB
 
+    /// Offset to kernel windows of memory shared with user contexts
+    pub const KERNEL_SHARED_OFFSET: usize = KERNEL_OFFSET + PML4_SIZE/2;
+    /// Size of each kernel window of shared memory
+    pub const KERNEL_SHARED_SIZE: usize = 1024 * 1024; // 1 MB
+    /// Number of kernel windows of shared memory, which fill the rest of the kernel PML4
+    pub const KERNEL_SHARED_COUNT: usize = (PML4_SIZE/2)/KERNEL_SHARED_SIZE;
+
     /// Offset to user image
     pub const USER_OFFSET: usize = 0;
     pub const USER_PML4: usize = (USER_OFFSET & PML4_MASK)/PML4_SIZE;
@@ -1,16 +1,17 @@
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 use alloc::collections::VecDeque;
 use core::cmp::Ordering;
 use core::mem;
-use spin::Mutex;
+use spin::{Mutex, RwLock};
 
 use context::arch;
-use context::file::FileDescriptor;
+use context::file::{FileDescription, FileDescriptor};
 use context::memory::{Grant, Memory, SharedMemory, Tls};
 use ipi::{ipi, IpiKind, IpiTarget};
 use scheme::{SchemeNamespace, FileHandle};
+use scheme::signalfd::SignalFd;
 use syscall::data::SigAction;
 use syscall::flag::SIG_DFL;
 use sync::WaitMap;
@@ -125,6 +126,8 @@ pub struct Context {
     pub waitpid: Arc<WaitMap<WaitpidKey, (ContextId, usize)>>,
     /// Context should handle pending signals
     pub pending: VecDeque<u8>,
+    /// Signal descriptors opened by the context, which receive signals instead of handlers
+    pub signalfds: Vec<Weak<SignalFd>>,
     /// Context should wake up at specified time
     pub wake: Option<(u64, u64)>,
     /// The architecture specific context
@@ -149,6 +152,8 @@ pub struct Context {
     pub tls: Option<Tls>,
     /// User grants
     pub grants: Arc<Mutex<Vec<Grant>>>,
+    /// User address of the robust futex list head, if registered
+    pub robust_list: Option<usize>,
     /// The name of the context
     pub name: Arc<Mutex<Box<[u8]>>>,
     /// The current working directory
@@ -179,6 +184,7 @@ impl Context {
             vfork: false,
             waitpid: Arc::new(WaitMap::new()),
             pending: VecDeque::new(),
+            signalfds: Vec::new(),
             wake: None,
             arch: arch::Context::new(),
             kfx: None,
@@ -191,6 +197,7 @@ impl Context {
             sigstack: None,
             tls: None,
             grants: Arc::new(Mutex::new(Vec::new())),
+            robust_list: None,
             name: Arc::new(Mutex::new(Vec::new().into_boxed_slice())),
             cwd: Arc::new(Mutex::new(Vec::new())),
             files: Arc::new(Mutex::new(Vec::new())),
@@ -211,11 +218,16 @@ impl Context {
     /// "/foo" will turn into "scheme:/foo"
     /// "bar:/foo" will be used directly, as it is already absolute
     pub fn canonicalize(&self, path: &[u8]) -> Vec<u8> {
-        let mut canon = if path.iter().position(|&b| b == b':').is_none() {
-            let cwd = self.cwd.lock();
+        let cwd = self.cwd.lock();
+        Context::canonicalize_in(&cwd, path)
+    }
 
+    /// Make a relative path absolute, like `canonicalize`, but relative to the
+    /// directory `cwd` instead of the current working directory
+    pub fn canonicalize_in(cwd: &[u8], path: &[u8]) -> Vec<u8> {
+        let mut canon = if path.iter().position(|&b| b == b':').is_none() {
             let mut canon = if !path.starts_with(b"/") {
-                let mut c = cwd.clone();
+                let mut c = cwd.to_vec();
                 if ! c.ends_with(b"/") {
                     c.push(b'/');
                 }
@@ -371,4 +383,18 @@ impl Context {
             None
         }
     }
+
+    /// Remove a file, only if the slot still holds the description at `description`
+    pub fn remove_file_if(&self, i: FileHandle, description: *const RwLock<FileDescription>) -> Option<FileDescriptor> {
+        let mut files = self.files.lock();
+        let matches = match files.get(i.into()) {
+            Some(&Some(ref current)) => &*current.description as *const RwLock<FileDescription> == description,
+            _ => false
+        };
+        if matches {
+            files[i.into()].take()
+        } else {
+            None
+        }
+    }
 }
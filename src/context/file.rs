@@ -5,6 +5,7 @@ use event;
 use spin::RwLock;
 use scheme::{self, SchemeId};
 use syscall::error::{Result, Error, EBADF};
+use syscall::flock;
 
 /// A file description
 #[derive(Debug)]
@@ -28,9 +29,15 @@ pub struct FileDescriptor {
 
 impl FileDescriptor {
     pub fn close(self) -> Result<usize> {
+        // Closing any descriptor to a file drops the process' byte-range locks on it
+        flock::release_process_file(&self.description.read());
+
+        let description_ptr = &*self.description as *const _ as usize;
         if let Ok(file) = Arc::try_unwrap(self.description) {
             let file = file.into_inner();
 
+            flock::release_description(description_ptr);
+
             event::unregister_file(file.scheme, file.number);
 
             let scheme = {
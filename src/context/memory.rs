@@ -1,21 +1,29 @@
 use alloc::sync::{Arc, Weak};
 use alloc::collections::VecDeque;
-use core::intrinsics;
-use spin::Mutex;
+use alloc::vec::Vec;
+use core::{cmp, intrinsics};
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use spin::{Mutex, Once, RwLock};
 
+use context::{self, Context};
 use ipi::{ipi, IpiKind, IpiTarget};
-use memory::Frame;
+use memory::{allocate_frames, deallocate_frames, Frame};
 use paging::{ActivePageTable, InactivePageTable, Page, PageIter, PhysicalAddress, VirtualAddress};
 use paging::entry::EntryFlags;
 use paging::mapper::MapperFlushAll;
 use paging::temporary_page::TemporaryPage;
+use syscall::driver;
+use syscall::error::{Error, Result, EINVAL, ENOMEM, ESRCH};
+use syscall::flag::MAP_WRITE;
 
 #[derive(Debug)]
 pub struct Grant {
     start: VirtualAddress,
     size: usize,
     flags: EntryFlags,
-    mapped: bool
+    mapped: bool,
+    /// The frames were allocated for the grant, and are freed when it is unmapped
+    owned: bool
 }
 
 impl Grant {
@@ -38,7 +46,8 @@ impl Grant {
             start: to,
             size: size,
             flags: flags,
-            mapped: true
+            mapped: true,
+            owned: false
         }
     }
 
@@ -71,7 +80,8 @@ impl Grant {
             start: to,
             size: size,
             flags: flags,
-            mapped: true
+            mapped: true,
+            owned: false
         }
     }
 
@@ -97,7 +107,10 @@ impl Grant {
         let start_page = Page::containing_address(self.start);
         let end_page = Page::containing_address(VirtualAddress::new(self.start.get() + self.size - 1));
         for page in Page::range_inclusive(start_page, end_page) {
-            let (result, _frame) = active_table.unmap_return(page, false);
+            let (result, frame) = active_table.unmap_return(page, false);
+            if self.owned {
+                deallocate_frames(frame, 1);
+            }
             flush_all.consume(result);
         }
 
@@ -111,11 +124,15 @@ impl Grant {
 
         let mut active_table = unsafe { ActivePageTable::new() };
 
+        let owned = self.owned;
         active_table.with(new_table, temporary_page, |mapper| {
             let start_page = Page::containing_address(self.start);
             let end_page = Page::containing_address(VirtualAddress::new(self.start.get() + self.size - 1));
             for page in Page::range_inclusive(start_page, end_page) {
-                let (result, _frame) = mapper.unmap_return(page, false);
+                let (result, frame) = mapper.unmap_return(page, false);
+                if owned {
+                    deallocate_frames(frame, 1);
+                }
                 // This is not the active table, so the flush can be ignored
                 unsafe { result.ignore(); }
             }
@@ -125,6 +142,116 @@ impl Grant {
 
         self.mapped = false;
     }
+
+    /// Replace the frames of a grant in an inactive table with new frames holding a copy of
+    /// their contents. The grant then owns its frames, so it stays valid after the memory it
+    /// was made from is unmapped or freed
+    pub fn bounce_inactive(&mut self, new_table: &mut InactivePageTable, temporary_page: &mut TemporaryPage) -> Result<()> {
+        assert!(self.mapped);
+        if self.owned {
+            return Ok(());
+        }
+
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        let start_page = Page::containing_address(self.start);
+        let end_page = Page::containing_address(VirtualAddress::new(self.start.get() + self.size - 1));
+
+        let mut old_frames = Vec::new();
+        active_table.with(new_table, temporary_page, |mapper| {
+            for page in Page::range_inclusive(start_page, end_page) {
+                old_frames.push(mapper.translate_page(page).map(|frame| frame.start_address()));
+            }
+        });
+
+        let mut new_frames = Vec::with_capacity(old_frames.len());
+        for _ in 0..old_frames.len() {
+            match allocate_frames(1) {
+                Some(frame) => new_frames.push(frame.start_address()),
+                None => {
+                    for address in new_frames {
+                        deallocate_frames(Frame::containing_address(address), 1);
+                    }
+                    return Err(Error::new(ENOMEM));
+                }
+            }
+        }
+
+        // Copied one page at a time, as only one temporary page is available
+        let mut page_buf = vec![0; 4096];
+        for (old_opt, &new) in old_frames.iter().zip(new_frames.iter()) {
+            match *old_opt {
+                Some(old) => {
+                    let base = temporary_page.map(Frame::containing_address(old), EntryFlags::PRESENT | EntryFlags::NO_EXECUTE, &mut active_table);
+                    unsafe { intrinsics::copy(base.get() as *const u8, page_buf.as_mut_ptr(), 4096); }
+                    temporary_page.unmap(&mut active_table);
+                },
+                None => for b in page_buf.iter_mut() {
+                    *b = 0;
+                }
+            }
+
+            let base = temporary_page.map(Frame::containing_address(new), EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, &mut active_table);
+            unsafe { intrinsics::copy(page_buf.as_ptr(), base.get() as *mut u8, 4096); }
+            temporary_page.unmap(&mut active_table);
+        }
+
+        let flags = self.flags;
+        active_table.with(new_table, temporary_page, |mapper| {
+            for (page, &new) in Page::range_inclusive(start_page, end_page).zip(new_frames.iter()) {
+                let (result, _frame) = mapper.unmap_return(page, false);
+                // This is not the active table, so the flush can be ignored
+                unsafe { result.ignore(); }
+                let result = mapper.map_to(page, Frame::containing_address(new), flags);
+                unsafe { result.ignore(); }
+            }
+        });
+
+        ipi(IpiKind::Tlb, IpiTarget::Other);
+
+        self.owned = true;
+        Ok(())
+    }
+}
+
+/// Copy `data` to `address` in the address space of `context`, which does not have to be
+/// active. Stops at the first page that the context cannot write, returning the bytes copied
+pub fn copy_to_context(context: &Context, address: usize, data: &[u8]) -> usize {
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let mut new_table = unsafe { InactivePageTable::from_address(context.arch.get_page_table()) };
+    let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(::USER_TMP_GRANT_OFFSET)));
+
+    let mut i = 0;
+    while i < data.len() {
+        let position = match address.checked_add(i) {
+            Some(position) => position,
+            None => break
+        };
+        let page = Page::containing_address(VirtualAddress::new(position));
+        let offset = position % 4096;
+        let count = cmp::min(4096 - offset, data.len() - i);
+
+        let mut frame_opt = None;
+        active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+            let writable = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE;
+            if mapper.translate_page_flags(page).map_or(false, |flags| flags.contains(writable)) {
+                frame_opt = mapper.translate_page(page).map(|frame| frame.start_address());
+            }
+        });
+
+        let frame = match frame_opt {
+            Some(frame) => frame,
+            None => break
+        };
+
+        let base = temporary_page.map(Frame::containing_address(frame), EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, &mut active_table);
+        unsafe { intrinsics::copy(data[i..].as_ptr(), (base.get() + offset) as *mut u8, count); }
+        temporary_page.unmap(&mut active_table);
+
+        i += count;
+    }
+
+    i
 }
 
 impl Drop for Grant {
@@ -133,6 +260,126 @@ impl Drop for Grant {
     }
 }
 
+/// Kernel windows that are not used by any `SharedFrames`
+static FREE_WINDOWS: Once<Mutex<Vec<usize>>> = Once::new();
+/// Next kernel window that has never been used
+static NEXT_WINDOW: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Frames mapped into a context as a grant, and into a kernel window that is present in every
+/// address space, so that the kernel can access them while any context is active
+#[derive(Debug)]
+pub struct SharedFrames {
+    physical: usize,
+    window: usize,
+    user: usize,
+    size: usize,
+    context: Weak<RwLock<Context>>,
+}
+
+impl SharedFrames {
+    /// Allocate zeroed frames, and map them into the current context
+    pub fn new(size: usize) -> Result<SharedFrames> {
+        let size = ((size + 4095)/4096) * 4096;
+        if size == 0 || size > ::KERNEL_SHARED_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+
+        let context = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+            Arc::downgrade(&context_lock)
+        };
+
+        let physical = driver::inner_physalloc(size)?;
+
+        let window = FREE_WINDOWS.call_once(|| Mutex::new(Vec::new())).lock().pop().unwrap_or_else(|| {
+            NEXT_WINDOW.fetch_add(1, Ordering::SeqCst)
+        });
+        if window >= ::KERNEL_SHARED_COUNT {
+            let _ = driver::inner_physfree(physical, size);
+            return Err(Error::new(ENOMEM));
+        }
+
+        {
+            let mut active_table = unsafe { ActivePageTable::new() };
+            let mut flush_all = MapperFlushAll::new();
+
+            let start = ::KERNEL_SHARED_OFFSET + window * ::KERNEL_SHARED_SIZE;
+            for i in 0..size/4096 {
+                let page = Page::containing_address(VirtualAddress::new(start + i * 4096));
+                let frame = Frame::containing_address(PhysicalAddress::new(physical + i * 4096));
+                let result = active_table.map_to(page, frame, EntryFlags::PRESENT | EntryFlags::GLOBAL | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+                flush_all.consume(result);
+            }
+
+            flush_all.flush(&mut active_table);
+
+            unsafe { intrinsics::write_bytes(start as *mut u8, 0, size); }
+        }
+
+        let mut frames = SharedFrames {
+            physical: physical,
+            window: window,
+            user: 0,
+            size: size,
+            context: context,
+        };
+
+        frames.user = driver::inner_physmap(physical, size, MAP_WRITE)?;
+
+        Ok(frames)
+    }
+
+    /// Address of the frames in the kernel window
+    pub fn kernel_address(&self) -> usize {
+        ::KERNEL_SHARED_OFFSET + self.window * ::KERNEL_SHARED_SIZE
+    }
+
+    /// Address of the frames in the context that created them
+    pub fn user_address(&self) -> usize {
+        self.user
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for SharedFrames {
+    fn drop(&mut self) {
+        // The context must lose access before the frames are freed
+        if self.user != 0 {
+            if let Some(context_lock) = self.context.upgrade() {
+                let context = context_lock.read();
+                let mut grants = context.grants.lock();
+                if let Some(i) = grants.iter().position(|grant| grant.start_address().get() == self.user) {
+                    let mut new_table = unsafe { InactivePageTable::from_address(context.arch.get_page_table()) };
+                    let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(::USER_TMP_GRANT_OFFSET)));
+                    grants.remove(i).unmap_inactive(&mut new_table, &mut temporary_page);
+                }
+            }
+        }
+
+        {
+            let mut active_table = unsafe { ActivePageTable::new() };
+            let mut flush_all = MapperFlushAll::new();
+
+            let start = self.kernel_address();
+            for i in 0..self.size/4096 {
+                let page = Page::containing_address(VirtualAddress::new(start + i * 4096));
+                let (result, _frame) = active_table.unmap_return(page, false);
+                flush_all.consume(result);
+            }
+
+            flush_all.flush(&mut active_table);
+            ipi(IpiKind::Tlb, IpiTarget::Other);
+        }
+
+        FREE_WINDOWS.call_once(|| Mutex::new(Vec::new())).lock().push(self.window);
+        let _ = driver::inner_physfree(self.physical, self.size);
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum SharedMemory {
     Owned(Arc<Mutex<Memory>>),
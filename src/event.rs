@@ -1,58 +1,177 @@
 use alloc::sync::Arc;
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
-use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use spin::{Mutex, Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use context;
 use scheme::{self, SchemeId};
 use sync::WaitQueue;
 use syscall::data::Event;
-use syscall::error::{Error, Result, EBADF, ESRCH};
+use syscall::error::{Error, Result, EBADF, EINVAL, ENOENT, ESRCH};
+use syscall::flag::{EVENT_EDGE, EVENT_INTEREST, EVENT_LEVEL, EVENT_MODE, EVENT_MODIFY, EVENT_ONESHOT, EVENT_REMOVE};
 
 int_like!(EventQueueId, AtomicEventQueueId, usize, AtomicUsize);
 
 pub struct EventQueue {
     id: EventQueueId,
     queue: WaitQueue<Event>,
+    /// Level triggered registrations of this queue, by the file they are registered on
+    level: Mutex<BTreeMap<QueueKey, RegKey>>,
 }
 
 impl EventQueue {
     pub fn new(id: EventQueueId) -> EventQueue {
         EventQueue {
             id: id,
-            queue: WaitQueue::new()
+            queue: WaitQueue::new(),
+            level: Mutex::new(BTreeMap::new())
         }
     }
 
+    pub fn id(&self) -> EventQueueId {
+        self.id
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
     pub fn read(&self, events: &mut [Event]) -> Result<usize> {
-        Ok(self.queue.receive_into(events, true))
+        let count = self.queue.receive_into(events, true);
+        self.requeue_level(&events[..count]);
+        Ok(count)
     }
 
-    pub fn write(&self, events: &[Event]) -> Result<usize> {
-        for event in events {
-            let file = {
-                let contexts = context::contexts();
-                let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
-                let context = context_lock.read();
-                let mut files = context.files.lock();
-                match files.get(event.id).ok_or(Error::new(EBADF))? {
-                    Some(file) => file.clone(),
-                    None => return Err(Error::new(EBADF))
+    /// Read queued events without blocking
+    pub fn read_nonblock(&self, events: &mut [Event]) -> usize {
+        let count = self.queue.receive_into(events, false);
+        self.requeue_level(&events[..count]);
+        count
+    }
+
+    /// Block until an event is sent, returns false if interrupted
+    pub fn wait(&self) -> bool {
+        self.queue.condition.wait()
+    }
+
+    /// Send an event, merging it into an undelivered event of the same registration if requested
+    fn send(&self, event: Event, coalesce: bool) {
+        if coalesce {
+            let mut inner = self.queue.inner.lock();
+            if let Some(queued) = inner.iter_mut().find(|queued| queued.id == event.id && queued.data == event.data) {
+                queued.flags |= event.flags;
+                return;
+            }
+        }
+
+        self.queue.send(event);
+    }
+
+    /// Queue delivered level triggered events again if their files are still ready
+    fn requeue_level(&self, events: &[Event]) {
+        let level = self.level.lock();
+        if level.is_empty() {
+            return;
+        }
+
+        let registry = registry();
+        for event in events.iter() {
+            let queue_key = QueueKey { queue: self.id, id: event.id, data: event.data };
+            let entry_opt = level.get(&queue_key).and_then(|reg_key| registry.get(reg_key));
+            if let Some(entry) = entry_opt {
+                let interest = entry.queues.get(&queue_key).map_or(0, |flags| flags.load(Ordering::SeqCst));
+                let ready = entry.ready.load(Ordering::SeqCst) & interest & EVENT_INTEREST;
+                if ready != 0 {
+                    self.send(Event {
+                        id: event.id,
+                        flags: ready,
+                        data: event.data
+                    }, true);
                 }
-            };
+            }
+        }
+    }
 
-            let (scheme, number) = {
-                let description = file.description.read();
-                (description.scheme, description.number)
-            };
+    /// Add or replace the registration described by `event`
+    pub fn add(&self, event: &Event) -> Result<usize> {
+        let reg_key = self.reg_key(event.id)?;
+        self.register(reg_key, event)?;
+        send_flags(reg_key)?;
+        Ok(0)
+    }
 
-            register(
-                RegKey { scheme, number },
-                QueueKey { queue: self.id, id: event.id, data: event.data },
-                event.flags
-            );
+    /// Change the flags of an existing registration
+    pub fn modify(&self, event: &Event) -> Result<usize> {
+        let reg_key = self.reg_key(event.id)?;
+        if ! is_registered(reg_key, QueueKey { queue: self.id, id: event.id, data: event.data }) {
+            return Err(Error::new(ENOENT));
+        }
+        self.register(reg_key, event)?;
+        send_flags(reg_key)?;
+        Ok(0)
+    }
 
-            send_flags(RegKey { scheme, number })?;
+    /// Remove an existing registration
+    pub fn remove(&self, event: &Event) -> Result<usize> {
+        let reg_key = self.reg_key(event.id)?;
+        let queue_key = QueueKey { queue: self.id, id: event.id, data: event.data };
+        if ! is_registered(reg_key, queue_key) {
+            return Err(Error::new(ENOENT));
+        }
+        self.level.lock().remove(&queue_key);
+        register(reg_key, queue_key, 0);
+        send_flags(reg_key)?;
+        Ok(0)
+    }
+
+    fn register(&self, reg_key: RegKey, event: &Event) -> Result<()> {
+        let flags = event.flags & !(EVENT_MODIFY | EVENT_REMOVE);
+        let queue_key = QueueKey { queue: self.id, id: event.id, data: event.data };
+
+        let mode = flags & EVENT_MODE;
+        if mode != 0 && mode != EVENT_EDGE && mode != EVENT_LEVEL && mode != EVENT_ONESHOT {
+            return Err(Error::new(EINVAL));
+        }
+
+        if flags & EVENT_LEVEL == EVENT_LEVEL && flags & EVENT_INTEREST != 0 {
+            self.level.lock().insert(queue_key, reg_key);
+        } else {
+            self.level.lock().remove(&queue_key);
+        }
+
+        register(reg_key, queue_key, flags);
+        Ok(())
+    }
+
+    fn reg_key(&self, fd: usize) -> Result<RegKey> {
+        let file = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+            let context = context_lock.read();
+            let files = context.files.lock();
+            match files.get(fd).ok_or(Error::new(EBADF))? {
+                Some(file) => file.clone(),
+                None => return Err(Error::new(EBADF))
+            }
+        };
+
+        let description = file.description.read();
+        Ok(RegKey {
+            scheme: description.scheme,
+            number: description.number
+        })
+    }
+
+    pub fn write(&self, events: &[Event]) -> Result<usize> {
+        for event in events {
+            if event.flags & EVENT_REMOVE == EVENT_REMOVE {
+                self.remove(event)?;
+            } else if event.flags & EVENT_MODIFY == EVENT_MODIFY {
+                self.modify(event)?;
+            } else {
+                self.add(event)?;
+            }
         }
 
         Ok(events.len())
@@ -87,20 +206,28 @@ pub fn queues_mut() -> RwLockWriteGuard<'static, EventQueueList> {
     QUEUES.call_once(init_queues).write()
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct RegKey {
     pub scheme: SchemeId,
     pub number: usize,
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct QueueKey {
     pub queue: EventQueueId,
     pub id: usize,
     pub data: usize
 }
 
-type Registry = BTreeMap<RegKey, BTreeMap<QueueKey, usize>>;
+/// Registrations on one file
+pub struct RegEntry {
+    /// Readiness flags reported by the scheme, used by level triggered registrations
+    ready: AtomicUsize,
+    /// Interest and mode flags of each queue registered on the file
+    queues: BTreeMap<QueueKey, AtomicUsize>,
+}
+
+type Registry = BTreeMap<RegKey, RegEntry>;
 
 static REGISTRY: Once<RwLock<Registry>> = Once::new();
 
@@ -119,17 +246,30 @@ pub fn registry_mut() -> RwLockWriteGuard<'static, Registry> {
     REGISTRY.call_once(init_registry).write()
 }
 
+fn is_registered(reg_key: RegKey, queue_key: QueueKey) -> bool {
+    registry().get(&reg_key).map_or(false, |entry| entry.queues.contains_key(&queue_key))
+}
+
 pub fn register(reg_key: RegKey, queue_key: QueueKey, flags: usize) {
     let mut registry = registry_mut();
 
-    let entry = registry.entry(reg_key).or_insert_with(|| {
-        BTreeMap::new()
-    });
+    let remove = {
+        let entry = registry.entry(reg_key).or_insert_with(|| RegEntry {
+            ready: AtomicUsize::new(0),
+            queues: BTreeMap::new()
+        });
+
+        if flags & EVENT_INTEREST == 0 {
+            entry.queues.remove(&queue_key);
+        } else {
+            entry.queues.insert(queue_key, AtomicUsize::new(flags));
+        }
+
+        entry.queues.is_empty()
+    };
 
-    if flags == 0 {
-        entry.remove(&queue_key);
-    } else {
-        entry.insert(queue_key, flags);
+    if remove {
+        registry.remove(&reg_key);
     }
 }
 
@@ -139,9 +279,9 @@ pub fn send_flags(reg_key: RegKey) -> Result<()> {
     {
         let registry = registry();
 
-        if let Some(queue_list) = registry.get(&reg_key) {
-            for (_queue_key, queue_flags) in queue_list.iter() {
-                flags |= queue_flags;
+        if let Some(entry) = registry.get(&reg_key) {
+            for (_queue_key, queue_flags) in entry.queues.iter() {
+                flags |= queue_flags.load(Ordering::SeqCst) & EVENT_INTEREST;
             }
         }
     }
@@ -169,27 +309,68 @@ pub fn unregister_file(scheme: SchemeId, number: usize) {
     registry.remove(&RegKey { scheme, number });
 }
 
-//TODO: Implement unregister_queue
-// pub fn unregister_queue(scheme: SchemeId, number: usize) {
-//
-// }
+/// Remove all registrations of `queue`, returning the files it was registered on
+pub fn unregister_queue(queue: EventQueueId) -> Vec<RegKey> {
+    let mut registry = registry_mut();
+
+    let mut reg_keys = Vec::new();
+    for (reg_key, entry) in registry.iter_mut() {
+        let queue_keys: Vec<QueueKey> = entry.queues.keys()
+            .filter(|queue_key| queue_key.queue == queue)
+            .cloned()
+            .collect();
 
+        if ! queue_keys.is_empty() {
+            for queue_key in queue_keys.iter() {
+                entry.queues.remove(queue_key);
+            }
+            reg_keys.push(*reg_key);
+        }
+    }
+
+    for reg_key in reg_keys.iter() {
+        if registry.get(reg_key).map_or(false, |entry| entry.queues.is_empty()) {
+            registry.remove(reg_key);
+        }
+    }
+
+    reg_keys
+}
+
+/// Signal that the file is ready for `flags`
 pub fn trigger(scheme: SchemeId, number: usize, flags: usize) {
     let registry = registry();
 
-    if let Some(queue_list) = registry.get(&RegKey { scheme, number }) {
-        for (queue_key, queue_flags) in queue_list.iter() {
-            let common_flags = flags & queue_flags;
+    if let Some(entry) = registry.get(&RegKey { scheme, number }) {
+        entry.ready.fetch_or(flags, Ordering::SeqCst);
+
+        for (queue_key, queue_flags) in entry.queues.iter() {
+            let current_flags = queue_flags.load(Ordering::SeqCst);
+            let common_flags = flags & current_flags & EVENT_INTEREST;
             if common_flags != 0 {
+                // One shot registrations stay registered, but are disarmed until modified
+                if current_flags & EVENT_ONESHOT == EVENT_ONESHOT {
+                    queue_flags.fetch_and(!EVENT_INTEREST, Ordering::SeqCst);
+                }
+
                 let queues = queues();
                 if let Some(queue) = queues.get(&queue_key.queue) {
-                    queue.queue.send(Event {
+                    queue.send(Event {
                         id: queue_key.id,
                         flags: common_flags,
                         data: queue_key.data
-                    });
+                    }, current_flags & EVENT_MODE != 0);
                 }
             }
         }
     }
 }
+
+/// Signal that the file is no longer ready for `flags`
+pub fn clear(scheme: SchemeId, number: usize, flags: usize) {
+    let registry = registry();
+
+    if let Some(entry) = registry.get(&RegKey { scheme, number }) {
+        entry.ready.fetch_and(!flags, Ordering::SeqCst);
+    }
+}
@@ -158,11 +158,14 @@ pub extern fn userspace_init() {
 }
 
 /// This is the kernel entry point for the primary CPU. The arch crate is responsible for calling this
-pub fn kmain(cpus: usize, env: &'static [u8]) -> ! {
+pub fn kmain(cpus: usize, env: &'static [u8], initfs: &'static [u8]) -> ! {
     CPU_ID.store(0, Ordering::SeqCst);
     CPU_COUNT.store(cpus, Ordering::SeqCst);
     unsafe { INIT_ENV = env };
 
+    // Parse the initfs archive before `initfs:` is first used
+    scheme::initfs::init(initfs);
+
     //Initialize the first context, stored in kernel/src/context/mod.rs
     context::init();
 
@@ -6,6 +6,7 @@ pub use paging::{PAGE_SIZE, PhysicalAddress};
 use self::bump::BumpAllocator;
 use self::recycle::RecycleAllocator;
 
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use spin::Mutex;
 
 pub mod bump;
@@ -68,6 +69,9 @@ impl Iterator for MemoryAreaIter {
 
 static ALLOCATOR: Mutex<Option<RecycleAllocator<BumpAllocator>>> = Mutex::new(None);
 
+/// Frames holding page tables, counted by the paging code
+pub static PAGE_TABLE_FRAMES: AtomicUsize = ATOMIC_USIZE_INIT;
+
 /// Init memory module
 /// Must be called once, and only once,
 pub unsafe fn init(kernel_start: usize, kernel_end: usize) {
@@ -110,6 +114,21 @@ pub fn used_frames() -> usize {
     }
 }
 
+/// Get the number of free frames kept in the free list of the recycle allocator, the
+/// rest of the free frames have not been handed out by the bump allocator yet
+pub fn recycled_frames() -> usize {
+    if let Some(ref allocator) = *ALLOCATOR.lock() {
+        allocator.recycled_frames()
+    } else {
+        panic!("frame allocator not initialized");
+    }
+}
+
+/// Get the number of frames holding page tables
+pub fn page_table_frames() -> usize {
+    PAGE_TABLE_FRAMES.load(Ordering::SeqCst)
+}
+
 /// Allocate a range of frames
 pub fn allocate_frames(count: usize) -> Option<Frame> {
     if let Some(ref mut allocator) = *ALLOCATOR.lock() {
@@ -22,6 +22,11 @@ impl<T: FrameAllocator> RecycleAllocator<T> {
         }
     }
 
+    /// Frames in the free list
+    pub fn recycled_frames(&self) -> usize {
+        self.free_count()
+    }
+
     fn free_count(&self) -> usize {
         let mut count = 0;
         for free in self.free.iter() {
@@ -0,0 +1,197 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+
+use syscall::error::*;
+use syscall::flag::{O_CREAT, O_RDONLY, O_TRUNC, O_WRONLY};
+
+/// Allows opening, and opening for reading
+pub const ACL_READ: u8 = 1;
+/// Allows opening for writing, and changing paths with `chmod`, `rmdir`, `unlink` and `frename`
+pub const ACL_WRITE: u8 = 2;
+/// Allows creating a scheme of the name with the root scheme
+pub const ACL_CREATE: u8 = 4;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Who {
+    Any,
+    Uid(u32),
+    Gid(u32),
+}
+
+#[derive(Clone, Copy, Debug)]
+struct AclEntry {
+    who: Who,
+    access: u8,
+}
+
+/// Access control list of a scheme name
+///
+/// Written and read through the root scheme as text, one entry per line, such as
+/// `uid 1000 rw`, `gid 10 r` or `* c`. Root is always allowed
+#[derive(Clone, Debug)]
+pub struct SchemeAcl {
+    entries: Vec<AclEntry>,
+}
+
+impl SchemeAcl {
+    pub fn parse(data: &[u8]) -> Result<SchemeAcl> {
+        let text = str::from_utf8(data).or(Err(Error::new(EINVAL)))?;
+
+        let mut entries = Vec::new();
+        for line in text.lines() {
+            let mut words = line.split_whitespace();
+            let who = match words.next() {
+                Some("*") => Who::Any,
+                Some("uid") => Who::Uid(words.next().and_then(|id| id.parse().ok()).ok_or(Error::new(EINVAL))?),
+                Some("gid") => Who::Gid(words.next().and_then(|id| id.parse().ok()).ok_or(Error::new(EINVAL))?),
+                Some(_) => return Err(Error::new(EINVAL)),
+                None => continue
+            };
+
+            let mut access = 0;
+            for c in words.next().unwrap_or("").chars() {
+                access |= match c {
+                    'r' => ACL_READ,
+                    'w' => ACL_WRITE,
+                    'c' => ACL_CREATE,
+                    '-' => 0,
+                    _ => return Err(Error::new(EINVAL))
+                };
+            }
+
+            if words.next().is_some() {
+                return Err(Error::new(EINVAL));
+            }
+
+            entries.push(AclEntry {
+                who: who,
+                access: access,
+            });
+        }
+
+        Ok(SchemeAcl {
+            entries: entries
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Check that `uid` or `gid` is granted all of `access`
+    pub fn check(&self, uid: u32, gid: u32, access: u8) -> Result<()> {
+        if uid == 0 {
+            return Ok(());
+        }
+
+        let mut granted = 0;
+        for entry in self.entries.iter() {
+            let matches = match entry.who {
+                Who::Any => true,
+                Who::Uid(entry_uid) => entry_uid == uid,
+                Who::Gid(entry_gid) => entry_gid == gid,
+            };
+            if matches {
+                granted |= entry.access;
+            }
+        }
+
+        if granted & access == access {
+            Ok(())
+        } else {
+            Err(Error::new(EACCES))
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut string = String::new();
+        for entry in self.entries.iter() {
+            let _ = match entry.who {
+                Who::Any => write!(string, "*"),
+                Who::Uid(uid) => write!(string, "uid {}", uid),
+                Who::Gid(gid) => write!(string, "gid {}", gid),
+            };
+            string.push(' ');
+            if entry.access == 0 {
+                string.push('-');
+            }
+            if entry.access & ACL_READ == ACL_READ {
+                string.push('r');
+            }
+            if entry.access & ACL_WRITE == ACL_WRITE {
+                string.push('w');
+            }
+            if entry.access & ACL_CREATE == ACL_CREATE {
+                string.push('c');
+            }
+            string.push('\n');
+        }
+        string.into_bytes()
+    }
+}
+
+/// The access needed to open a path with `flags`. Reading needs `ACL_READ`, and writing,
+/// creating or truncating needs `ACL_WRITE`
+pub fn open_access(flags: usize) -> u8 {
+    let mut access = 0;
+    if flags & O_RDONLY == O_RDONLY {
+        access |= ACL_READ;
+    }
+    if flags & O_WRONLY == O_WRONLY || flags & (O_CREAT | O_TRUNC) != 0 {
+        access |= ACL_WRITE;
+    }
+    access
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let acl = SchemeAcl::parse(b"uid 1000 rw\ngid 10 r\n\n* -\n").unwrap();
+        assert_eq!(acl.to_bytes(), b"uid 1000 rw\ngid 10 r\n* -\n".to_vec());
+        assert!(SchemeAcl::parse(b"").unwrap().is_empty());
+
+        assert!(SchemeAcl::parse(b"user 1000 rw").is_err());
+        assert!(SchemeAcl::parse(b"uid x r").is_err());
+        assert!(SchemeAcl::parse(b"uid 1000 rx").is_err());
+        assert!(SchemeAcl::parse(b"uid 1000 r w").is_err());
+    }
+
+    #[test]
+    fn check() {
+        let acl = SchemeAcl::parse(b"uid 1000 rw\ngid 10 r\n* c").unwrap();
+        assert_eq!(acl.check(0, 0, ACL_READ | ACL_WRITE | ACL_CREATE), Ok(()));
+        assert_eq!(acl.check(1000, 1000, ACL_READ | ACL_WRITE), Ok(()));
+        assert_eq!(acl.check(1001, 10, ACL_READ), Ok(()));
+        assert_eq!(acl.check(1001, 10, ACL_WRITE), Err(Error::new(EACCES)));
+        assert_eq!(acl.check(1001, 11, ACL_CREATE), Ok(()));
+        assert_eq!(acl.check(1001, 11, ACL_READ), Err(Error::new(EACCES)));
+    }
+
+    #[test]
+    fn open() {
+        use syscall::flag::{O_RDWR, O_STAT};
+
+        assert_eq!(open_access(0), 0);
+        assert_eq!(open_access(O_STAT), 0);
+        assert_eq!(open_access(O_RDONLY), ACL_READ);
+        assert_eq!(open_access(O_WRONLY), ACL_WRITE);
+        assert_eq!(open_access(O_RDWR), ACL_READ | ACL_WRITE);
+        assert_eq!(open_access(O_RDONLY | O_CREAT), ACL_READ | ACL_WRITE);
+        assert_eq!(open_access(O_WRONLY | O_TRUNC), ACL_WRITE);
+    }
+
+    #[test]
+    fn write_only() {
+        let acl = SchemeAcl::parse(b"uid 1000 w
+").unwrap();
+        assert_eq!(acl.check(1000, 1000, open_access(O_WRONLY)), Ok(()));
+        assert_eq!(acl.check(1000, 1000, open_access(O_WRONLY | O_CREAT)), Ok(()));
+        assert_eq!(acl.check(1000, 1000, open_access(O_RDONLY)), Err(Error::new(EACCES)));
+        assert_eq!(acl.check(1000, 1000, open_access(O_WRONLY | O_RDONLY)), Err(Error::new(EACCES)));
+    }
+}
@@ -0,0 +1,582 @@
+use alloc::sync::{Arc, Weak};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::str;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::{Mutex, RwLock};
+
+use event;
+use scheme::SchemeId;
+use sync::WaitCondition;
+use syscall::data::Stat;
+use syscall::error::*;
+use syscall::flag::{EVENT_READ, F_GETBACKLOG, F_GETFL, F_SETBACKLOG, F_SETFL, O_ACCMODE, O_CREAT, O_NONBLOCK, MODE_SOCK};
+use syscall::scheme::Scheme;
+
+/// Bytes a queue holds before writers block
+pub const CHAN_BUFFER_SIZE: usize = 65536;
+
+/// Connections a listener holds before refusing more, unless changed with `F_SETBACKLOG`
+pub const CHAN_BACKLOG_DEFAULT: usize = 128;
+
+/// Kind of channel, given by the first path component
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    /// Connected byte stream
+    Stream,
+    /// Connected, message boundaries are kept
+    SeqPacket,
+    /// Messages from any number of senders to one bound receiver
+    Datagram,
+}
+
+impl Kind {
+    fn parse(kind: &str) -> Result<Kind> {
+        match kind {
+            "stream" => Ok(Kind::Stream),
+            "seqpacket" => Ok(Kind::SeqPacket),
+            "dgram" => Ok(Kind::Datagram),
+            _ => Err(Error::new(EPROTONOSUPPORT))
+        }
+    }
+}
+
+/// Messages travelling in one direction
+struct Queue {
+    messages: Mutex<VecDeque<Vec<u8>>>,
+    /// Bytes in `messages`, only changed with `messages` locked
+    len: AtomicUsize,
+    condition: WaitCondition,
+    /// Notified when bytes are removed, or the reader goes away
+    space_condition: WaitCondition,
+    /// Event ID of the endpoint reading the queue
+    event_id: usize,
+    /// No endpoint reads the queue anymore
+    reader_gone: AtomicBool,
+    /// No endpoint writes the queue anymore, reads return end of file once it is empty
+    writer_gone: AtomicBool,
+}
+
+impl Queue {
+    fn new(event_id: usize) -> Queue {
+        Queue {
+            messages: Mutex::new(VecDeque::new()),
+            len: AtomicUsize::new(0),
+            condition: WaitCondition::new(),
+            space_condition: WaitCondition::new(),
+            event_id: event_id,
+            reader_gone: AtomicBool::new(false),
+            writer_gone: AtomicBool::new(false),
+        }
+    }
+}
+
+/// One end of a connection, or a bound datagram receiver
+struct Endpoint {
+    scheme_id: SchemeId,
+    kind: Kind,
+    path: Box<[u8]>,
+    flags: AtomicUsize,
+    rx: Arc<Queue>,
+    tx: Option<Arc<Queue>>,
+    /// Effective user and group of the other end, when it connected or listened
+    peer: (u32, u32),
+    /// Names of the scheme, to unbind a datagram receiver when it goes away
+    names: Option<Arc<Mutex<BTreeMap<Box<[u8]>, Bound>>>>,
+}
+
+impl Endpoint {
+    fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            {
+                let mut messages = self.rx.messages.lock();
+
+                let mut i = 0;
+                let mut removed = 0;
+                let mut received = false;
+                if self.kind == Kind::Stream {
+                    while i < buf.len() {
+                        if let Some(mut message) = messages.pop_front() {
+                            let count = message.len().min(buf.len() - i);
+                            buf[i..i + count].copy_from_slice(&message[..count]);
+                            i += count;
+                            removed += count;
+                            if count < message.len() {
+                                message.drain(..count);
+                                messages.push_front(message);
+                            }
+                            received = true;
+                        } else {
+                            break;
+                        }
+                    }
+                } else if let Some(message) = messages.pop_front() {
+                    // Whatever does not fit in the buffer is discarded
+                    i = message.len().min(buf.len());
+                    buf[..i].copy_from_slice(&message[..i]);
+                    removed = message.len();
+                    received = true;
+                }
+
+                if received {
+                    self.rx.len.fetch_sub(removed, Ordering::SeqCst);
+                    if messages.is_empty() && ! self.rx.writer_gone.load(Ordering::SeqCst) {
+                        event::clear(self.scheme_id, self.rx.event_id, EVENT_READ);
+                    }
+                    drop(messages);
+
+                    if removed > 0 {
+                        self.rx.space_condition.notify();
+                    }
+                    return Ok(i);
+                }
+            }
+
+            if self.rx.writer_gone.load(Ordering::SeqCst) {
+                return Ok(0);
+            } else if self.flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
+                return Err(Error::new(EAGAIN));
+            } else if ! self.rx.condition.wait() {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize> {
+        let tx = self.tx.as_ref().ok_or(Error::new(ENOTCONN))?;
+
+        // Empty writes are messages of their own, but carry nothing in a stream
+        if self.kind == Kind::Stream && buf.is_empty() {
+            return Ok(0);
+        } else if self.kind != Kind::Stream && buf.len() > CHAN_BUFFER_SIZE {
+            return Err(Error::new(EMSGSIZE));
+        }
+
+        loop {
+            if tx.reader_gone.load(Ordering::SeqCst) {
+                return Err(Error::new(EPIPE));
+            }
+
+            {
+                let mut messages = tx.messages.lock();
+
+                // A stream takes what fits, a message is queued whole or not at all
+                let space = CHAN_BUFFER_SIZE.saturating_sub(tx.len.load(Ordering::SeqCst));
+                let count = if self.kind == Kind::Stream {
+                    buf.len().min(space)
+                } else if buf.len() <= space {
+                    buf.len()
+                } else {
+                    0
+                };
+
+                if count > 0 || (buf.is_empty() && space > 0) {
+                    messages.push_back(buf[..count].to_vec());
+                    tx.len.fetch_add(count, Ordering::SeqCst);
+                    drop(messages);
+
+                    event::trigger(self.scheme_id, tx.event_id, EVENT_READ);
+                    tx.condition.notify();
+
+                    return Ok(count);
+                }
+            }
+
+            if self.flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
+                return Err(Error::new(EAGAIN));
+            } else if ! tx.space_condition.wait() {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        self.rx.reader_gone.store(true, Ordering::SeqCst);
+        self.rx.space_condition.notify();
+
+        if let Some(ref tx) = self.tx {
+            // A datagram receiver outlives its senders
+            if self.kind != Kind::Datagram {
+                tx.writer_gone.store(true, Ordering::SeqCst);
+                event::trigger(self.scheme_id, tx.event_id, EVENT_READ);
+                tx.condition.notify();
+            }
+        }
+
+        if let Some(ref names) = self.names {
+            unbind(names, path_name(&self.path), |bound| match *bound {
+                Bound::Datagram(ref queue, _) => queue.upgrade().map_or(true, |queue| Arc::ptr_eq(&queue, &self.rx)),
+                _ => false
+            });
+        }
+    }
+}
+
+/// Named endpoint that connected streams and sequenced packets are accepted from
+struct Listener {
+    scheme_id: SchemeId,
+    kind: Kind,
+    path: Box<[u8]>,
+    event_id: usize,
+    flags: AtomicUsize,
+    /// Effective user and group of the process that created the listener
+    creds: (u32, u32),
+    /// Server ends of connections that were not accepted yet
+    backlog: Mutex<VecDeque<Endpoint>>,
+    /// Connections in `backlog` past which connecting fails with `ECONNREFUSED`
+    backlog_max: AtomicUsize,
+    condition: WaitCondition,
+    names: Arc<Mutex<BTreeMap<Box<[u8]>, Bound>>>,
+}
+
+impl Listener {
+    fn accept(&self) -> Result<Endpoint> {
+        loop {
+            {
+                let mut backlog = self.backlog.lock();
+                if let Some(endpoint) = backlog.pop_front() {
+                    if backlog.is_empty() {
+                        event::clear(self.scheme_id, self.event_id, EVENT_READ);
+                    }
+                    return Ok(endpoint);
+                }
+            }
+
+            if self.flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
+                return Err(Error::new(EAGAIN));
+            } else if ! self.condition.wait() {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let listener: *const Listener = self;
+        unbind(&self.names, path_name(&self.path), |bound| match *bound {
+            Bound::Listener(ref weak) => weak.upgrade().map_or(true, |other| &*other as *const Listener == listener),
+            _ => false
+        });
+    }
+}
+
+/// What a name refers to
+enum Bound {
+    Listener(Weak<Listener>),
+    /// Queue of a datagram receiver, and the effective user and group that bound it
+    Datagram(Weak<Queue>, (u32, u32)),
+}
+
+/// Name part of a path, after the kind
+fn path_name(path: &[u8]) -> &[u8] {
+    match path.iter().position(|&b| b == b'/') {
+        Some(i) => &path[i + 1..],
+        None => path
+    }
+}
+
+/// Remove `name` if `is_own` accepts what it is bound to
+fn unbind<F>(names: &Mutex<BTreeMap<Box<[u8]>, Bound>>, name: &[u8], is_own: F) where F: Fn(&Bound) -> bool {
+    let mut names = names.lock();
+    let own = names.get(name).map_or(false, |bound| is_own(bound));
+    if own {
+        names.remove(name);
+    }
+}
+
+#[derive(Clone)]
+enum Handle {
+    Listener(Arc<Listener>),
+    Endpoint(Arc<Endpoint>),
+}
+
+/// `chan:` - local sockets with named listening endpoints
+///
+/// `chan:stream/name` and `chan:seqpacket/name` opened with `O_CREAT` listen on `name`, and
+/// connections are accepted with `dup(listener, "accept")`. Opening them without `O_CREAT`
+/// connects to the listener. `chan:dgram/name` opened with `O_CREAT` binds a receiver, and
+/// without it opens a sender to that receiver. `fstat` reports the credentials of the peer
+pub struct ChanScheme {
+    scheme_id: SchemeId,
+    next_id: AtomicUsize,
+    names: Arc<Mutex<BTreeMap<Box<[u8]>, Bound>>>,
+    handles: RwLock<BTreeMap<usize, Handle>>,
+}
+
+impl ChanScheme {
+    pub fn new(scheme_id: SchemeId) -> ChanScheme {
+        ChanScheme {
+            scheme_id: scheme_id,
+            next_id: AtomicUsize::new(0),
+            names: Arc::new(Mutex::new(BTreeMap::new())),
+            handles: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    fn get(&self, id: usize) -> Result<Handle> {
+        let handles = self.handles.read();
+        handles.get(&id).cloned().ok_or(Error::new(EBADF))
+    }
+
+    fn insert(&self, id: usize, handle: Handle) -> usize {
+        self.handles.write().insert(id, handle);
+        id
+    }
+
+    fn endpoint(&self, kind: Kind, path: &[u8], flags: usize, rx: Arc<Queue>, tx: Option<Arc<Queue>>, peer: (u32, u32)) -> Endpoint {
+        Endpoint {
+            scheme_id: self.scheme_id,
+            kind: kind,
+            path: path.to_vec().into_boxed_slice(),
+            flags: AtomicUsize::new(flags & ! O_ACCMODE),
+            rx: rx,
+            tx: tx,
+            peer: peer,
+            names: None,
+        }
+    }
+
+    fn bind(&self, kind: Kind, name: &[u8], path: &[u8], flags: usize, uid: u32, gid: u32) -> Result<usize> {
+        let mut names = self.names.lock();
+        if names.contains_key(name) {
+            return Err(Error::new(EADDRINUSE));
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let handle = if kind == Kind::Datagram {
+            let rx = Arc::new(Queue::new(id));
+            names.insert(name.to_vec().into_boxed_slice(), Bound::Datagram(Arc::downgrade(&rx), (uid, gid)));
+
+            // There is no single peer, so the receiver reports its own credentials
+            let mut endpoint = self.endpoint(kind, path, flags, rx, None, (uid, gid));
+            endpoint.names = Some(Arc::clone(&self.names));
+            Handle::Endpoint(Arc::new(endpoint))
+        } else {
+            let listener = Arc::new(Listener {
+                scheme_id: self.scheme_id,
+                kind: kind,
+                path: path.to_vec().into_boxed_slice(),
+                event_id: id,
+                flags: AtomicUsize::new(flags & ! O_ACCMODE),
+                creds: (uid, gid),
+                backlog: Mutex::new(VecDeque::new()),
+                backlog_max: AtomicUsize::new(CHAN_BACKLOG_DEFAULT),
+                condition: WaitCondition::new(),
+                names: Arc::clone(&self.names),
+            });
+            names.insert(name.to_vec().into_boxed_slice(), Bound::Listener(Arc::downgrade(&listener)));
+            Handle::Listener(listener)
+        };
+
+        Ok(self.insert(id, handle))
+    }
+
+    fn connect(&self, kind: Kind, name: &[u8], path: &[u8], flags: usize, uid: u32, gid: u32) -> Result<usize> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+
+        let bound = {
+            let names = self.names.lock();
+            let bound_opt = match names.get(name) {
+                Some(&Bound::Listener(ref weak)) => weak.upgrade().map(Handle::Listener),
+                Some(&Bound::Datagram(ref weak, creds)) => weak.upgrade().map(|queue| {
+                    // Senders have nothing to read
+                    let rx = Arc::new(Queue::new(id));
+                    rx.writer_gone.store(true, Ordering::SeqCst);
+                    Handle::Endpoint(Arc::new(self.endpoint(Kind::Datagram, path, flags, rx, Some(queue), creds)))
+                }),
+                None => None
+            };
+            bound_opt.ok_or(Error::new(ECONNREFUSED))?
+        };
+
+        match bound {
+            Handle::Listener(listener) => {
+                if listener.kind != kind {
+                    return Err(Error::new(EPROTOTYPE));
+                }
+
+                let server_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                let client_rx = Arc::new(Queue::new(id));
+                let server_rx = Arc::new(Queue::new(server_id));
+
+                let client = self.endpoint(kind, path, flags, Arc::clone(&client_rx), Some(Arc::clone(&server_rx)), listener.creds);
+                let server = self.endpoint(kind, path, listener.flags.load(Ordering::SeqCst), server_rx, Some(client_rx), (uid, gid));
+
+                {
+                    let mut backlog = listener.backlog.lock();
+                    if backlog.len() >= listener.backlog_max.load(Ordering::SeqCst) {
+                        return Err(Error::new(ECONNREFUSED));
+                    }
+                    backlog.push_back(server);
+                }
+                event::trigger(self.scheme_id, listener.event_id, EVENT_READ);
+                listener.condition.notify();
+
+                Ok(self.insert(id, Handle::Endpoint(Arc::new(client))))
+            },
+            Handle::Endpoint(sender) => {
+                if kind != Kind::Datagram {
+                    return Err(Error::new(EPROTOTYPE));
+                }
+
+                Ok(self.insert(id, Handle::Endpoint(sender)))
+            }
+        }
+    }
+}
+
+impl Scheme for ChanScheme {
+    fn open(&self, path: &[u8], flags: usize, uid: u32, gid: u32) -> Result<usize> {
+        let path_utf8 = str::from_utf8(path).or(Err(Error::new(ENOENT)))?;
+        let path_trimmed = path_utf8.trim_matches('/');
+
+        let mut parts = path_trimmed.splitn(2, '/');
+        let kind = Kind::parse(parts.next().unwrap_or(""))?;
+        let name = parts.next().unwrap_or("");
+        if name.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        if flags & O_CREAT == O_CREAT {
+            self.bind(kind, name.as_bytes(), path_trimmed.as_bytes(), flags, uid, gid)
+        } else {
+            self.connect(kind, name.as_bytes(), path_trimmed.as_bytes(), flags, uid, gid)
+        }
+    }
+
+    fn dup(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        let handle = self.get(id)?;
+
+        if buf == b"accept" {
+            if let Handle::Listener(listener) = handle {
+                let server = listener.accept()?;
+                let new_id = server.rx.event_id;
+                Ok(self.insert(new_id, Handle::Endpoint(Arc::new(server))))
+            } else {
+                Err(Error::new(EINVAL))
+            }
+        } else if buf.is_empty() {
+            let new_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            Ok(self.insert(new_id, handle))
+        } else {
+            Err(Error::new(EINVAL))
+        }
+    }
+
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        match self.get(id)? {
+            Handle::Endpoint(endpoint) => endpoint.read(buf),
+            Handle::Listener(_) => Err(Error::new(ENOTCONN))
+        }
+    }
+
+    fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        match self.get(id)? {
+            Handle::Endpoint(endpoint) => endpoint.write(buf),
+            Handle::Listener(_) => Err(Error::new(ENOTCONN))
+        }
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let handle = self.get(id)?;
+        if let Handle::Listener(ref listener) = handle {
+            match cmd {
+                F_GETBACKLOG => return Ok(listener.backlog_max.load(Ordering::SeqCst)),
+                // Connections already held are kept
+                F_SETBACKLOG => {
+                    listener.backlog_max.store(arg, Ordering::SeqCst);
+                    return Ok(0);
+                },
+                _ => ()
+            }
+        }
+
+        let flags = match handle {
+            Handle::Endpoint(ref endpoint) => &endpoint.flags,
+            Handle::Listener(ref listener) => &listener.flags
+        };
+
+        match cmd {
+            F_GETFL => Ok(flags.load(Ordering::SeqCst)),
+            F_SETFL => {
+                flags.store(arg & ! O_ACCMODE, Ordering::SeqCst);
+                Ok(0)
+            },
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+
+    fn fevent(&self, id: usize, _flags: usize) -> Result<usize> {
+        match self.get(id)? {
+            // Report what was queued before the registration
+            Handle::Endpoint(endpoint) => {
+                if ! endpoint.rx.messages.lock().is_empty() || endpoint.rx.writer_gone.load(Ordering::SeqCst) {
+                    event::trigger(self.scheme_id, endpoint.rx.event_id, EVENT_READ);
+                }
+                Ok(endpoint.rx.event_id)
+            },
+            Handle::Listener(listener) => {
+                if ! listener.backlog.lock().is_empty() {
+                    event::trigger(self.scheme_id, listener.event_id, EVENT_READ);
+                }
+                Ok(listener.event_id)
+            }
+        }
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.get(id)?;
+        let path = match handle {
+            Handle::Endpoint(ref endpoint) => &endpoint.path,
+            Handle::Listener(ref listener) => &listener.path
+        };
+
+        let mut i = 0;
+        let scheme_path = b"chan:";
+        while i < buf.len() && i < scheme_path.len() {
+            buf[i] = scheme_path[i];
+            i += 1;
+        }
+
+        let mut j = 0;
+        while i < buf.len() && j < path.len() {
+            buf[i] = path[j];
+            i += 1;
+            j += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
+        let (uid, gid) = match self.get(id)? {
+            Handle::Endpoint(endpoint) => endpoint.peer,
+            Handle::Listener(listener) => listener.creds
+        };
+
+        *stat = Stat {
+            st_mode: MODE_SOCK | 0o666,
+            st_uid: uid,
+            st_gid: gid,
+            ..Default::default()
+        };
+
+        Ok(0)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        self.get(id).and(Ok(0))
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        self.handles.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+
+    fn seek(&self, _id: usize, _pos: usize, _whence: usize) -> Result<usize> {
+        Err(Error::new(ESPIPE))
+    }
+}
@@ -68,7 +68,14 @@ impl Scheme for DebugScheme {
             *handles.get(&id).ok_or(Error::new(EBADF))?
         };
 
-        Ok(INPUT.call_once(init_input).receive_into(buf, flags & O_NONBLOCK != O_NONBLOCK))
+        let input = INPUT.call_once(init_input);
+        let count = input.receive_into(buf, flags & O_NONBLOCK != O_NONBLOCK);
+        if input.is_empty() {
+            for (id, _flags) in handles().iter() {
+                event::clear(DEBUG_SCHEME_ID.load(Ordering::SeqCst), *id, EVENT_READ);
+            }
+        }
+        Ok(count)
     }
 
     /// Write the `buffer` to the `file`
@@ -110,6 +117,11 @@ impl Scheme for DebugScheme {
             *handles.get(&id).ok_or(Error::new(EBADF))?
         };
 
+        // Report input that arrived before the registration
+        if ! INPUT.call_once(init_input).is_empty() {
+            event::trigger(DEBUG_SCHEME_ID.load(Ordering::SeqCst), id, EVENT_READ);
+        }
+
         Ok(id)
     }
 
@@ -1,7 +1,7 @@
 use alloc::sync::Arc;
 use core::{mem, slice};
 
-use event::{EventQueue, EventQueueId, next_queue_id, queues, queues_mut};
+use event::{EventQueue, EventQueueId, next_queue_id, queues, queues_mut, send_flags, unregister_queue};
 use syscall::data::Event;
 use syscall::error::*;
 use syscall::scheme::Scheme;
@@ -68,6 +68,9 @@ impl Scheme for EventScheme {
 
     fn close(&self, id: usize) -> Result<usize> {
         let id = EventQueueId::from(id);
+        for reg_key in unregister_queue(id) {
+            let _ = send_flags(reg_key);
+        }
         queues_mut().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
     }
 }
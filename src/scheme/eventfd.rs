@@ -0,0 +1,208 @@
+use alloc::sync::Arc;
+use alloc::collections::BTreeMap;
+use core::{mem, ptr, str, u64};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, RwLock};
+
+use event;
+use scheme::SchemeId;
+use sync::WaitCondition;
+use syscall::error::*;
+use syscall::flag::{EVENT_READ, EVENT_WRITE, F_GETFL, F_SETFL, O_ACCMODE, O_NONBLOCK};
+use syscall::scheme::Scheme;
+
+/// Largest value of a counter
+pub const EVENTFD_MAX: u64 = u64::MAX - 1;
+
+/// A 64-bit counter that can be waited on
+pub struct EventFd {
+    scheme_id: SchemeId,
+    id: usize,
+    flags: AtomicUsize,
+    /// Reads take one at a time, instead of the whole count
+    semaphore: bool,
+    count: Mutex<u64>,
+    condition: WaitCondition,
+}
+
+impl EventFd {
+    fn read(&self) -> Result<u64> {
+        loop {
+            {
+                let mut count = self.count.lock();
+                if *count > 0 {
+                    let value = if self.semaphore { 1 } else { *count };
+                    *count -= value;
+
+                    if *count == 0 {
+                        event::clear(self.scheme_id, self.id, EVENT_READ);
+                    }
+                    drop(count);
+
+                    event::trigger(self.scheme_id, self.id, EVENT_WRITE);
+                    self.condition.notify();
+                    return Ok(value);
+                }
+            }
+
+            if self.flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
+                return Err(Error::new(EAGAIN));
+            } else if ! self.condition.wait() {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+
+    fn write(&self, value: u64) -> Result<()> {
+        if value > EVENTFD_MAX {
+            return Err(Error::new(EINVAL));
+        }
+
+        loop {
+            {
+                let mut count = self.count.lock();
+                if EVENTFD_MAX - *count >= value {
+                    *count += value;
+
+                    if *count == EVENTFD_MAX {
+                        event::clear(self.scheme_id, self.id, EVENT_WRITE);
+                    }
+                    let readable = *count > 0;
+                    drop(count);
+
+                    if readable {
+                        event::trigger(self.scheme_id, self.id, EVENT_READ);
+                        self.condition.notify();
+                    }
+                    return Ok(());
+                }
+            }
+
+            if self.flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
+                return Err(Error::new(EAGAIN));
+            } else if ! self.condition.wait() {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+}
+
+/// `eventfd:` - counters for wakeups. Opening `eventfd:semaphore` makes each read take one
+pub struct EventFdScheme {
+    scheme_id: SchemeId,
+    next_id: AtomicUsize,
+    handles: RwLock<BTreeMap<usize, Arc<EventFd>>>,
+}
+
+impl EventFdScheme {
+    pub fn new(scheme_id: SchemeId) -> EventFdScheme {
+        EventFdScheme {
+            scheme_id: scheme_id,
+            next_id: AtomicUsize::new(0),
+            handles: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    fn get(&self, id: usize) -> Result<Arc<EventFd>> {
+        let handles = self.handles.read();
+        handles.get(&id).cloned().ok_or(Error::new(EBADF))
+    }
+}
+
+impl Scheme for EventFdScheme {
+    fn open(&self, path: &[u8], flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        let path_str = str::from_utf8(path).or(Err(Error::new(ENOENT)))?;
+
+        let semaphore = match path_str.trim_matches('/') {
+            "" => false,
+            "semaphore" => true,
+            _ => return Err(Error::new(ENOENT))
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(id, Arc::new(EventFd {
+            scheme_id: self.scheme_id,
+            id: id,
+            flags: AtomicUsize::new(flags & ! O_ACCMODE),
+            semaphore: semaphore,
+            count: Mutex::new(0),
+            condition: WaitCondition::new(),
+        }));
+
+        Ok(id)
+    }
+
+    /// Read the counter as a native endian `u64`
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let eventfd = self.get(id)?;
+
+        if buf.len() < mem::size_of::<u64>() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let value = eventfd.read()?;
+        unsafe { ptr::write_unaligned(buf.as_mut_ptr() as *mut u64, value); }
+        Ok(mem::size_of::<u64>())
+    }
+
+    /// Add a native endian `u64` to the counter
+    fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        let eventfd = self.get(id)?;
+
+        if buf.len() < mem::size_of::<u64>() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let value = unsafe { ptr::read_unaligned(buf.as_ptr() as *const u64) };
+        eventfd.write(value)?;
+        Ok(mem::size_of::<u64>())
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let eventfd = self.get(id)?;
+
+        match cmd {
+            F_GETFL => Ok(eventfd.flags.load(Ordering::SeqCst)),
+            F_SETFL => {
+                eventfd.flags.store(arg & ! O_ACCMODE, Ordering::SeqCst);
+                Ok(0)
+            },
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+
+    fn fevent(&self, id: usize, _flags: usize) -> Result<usize> {
+        let eventfd = self.get(id)?;
+
+        // Report the current state, events are only sent when it changes
+        let count = *eventfd.count.lock();
+        if count > 0 {
+            event::trigger(self.scheme_id, id, EVENT_READ);
+        }
+        if count < EVENTFD_MAX {
+            event::trigger(self.scheme_id, id, EVENT_WRITE);
+        }
+
+        Ok(id)
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let eventfd = self.get(id)?;
+
+        let mut i = 0;
+        let scheme_path: &[u8] = if eventfd.semaphore { b"eventfd:semaphore" } else { b"eventfd:" };
+        while i < buf.len() && i < scheme_path.len() {
+            buf[i] = scheme_path[i];
+            i += 1;
+        }
+        Ok(i)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        self.get(id).and(Ok(0))
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        self.handles.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
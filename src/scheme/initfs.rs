@@ -1,90 +1,423 @@
+use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use core::{cmp, str};
 use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::RwLock;
+use spin::{Once, RwLock};
 
+use common::lz4::Lz4Frame;
+use scheme::{AtomicSchemeId, ATOMIC_SCHEMEID_INIT, SchemeId};
 use syscall::data::Stat;
 use syscall::error::*;
-use syscall::flag::{MODE_DIR, MODE_FILE, SEEK_SET, SEEK_CUR, SEEK_END};
+use syscall::flag::{MODE_DIR, MODE_FILE, MODE_SYMLINK, MODE_TYPE, O_CREAT, O_DIRECTORY, O_RDONLY, O_SYMLINK, O_TRUNC, O_WRONLY, SEEK_SET, SEEK_CUR, SEEK_END};
+use syscall::fs::SYMLINK_MAX_HOPS;
 use syscall::scheme::Scheme;
 
-#[cfg(test)]
-mod gen {
-    use alloc::collections::BTreeMap;
-    pub fn gen() -> BTreeMap<&'static [u8], (&'static [u8], bool)> { BTreeMap::new() }
+/// Size of the header of each entry of a cpio archive in the "newc" format
+const CPIO_HEADER_SIZE: usize = 110;
+
+pub static INITFS_SCHEME_ID: AtomicSchemeId = ATOMIC_SCHEMEID_INIT;
+
+/// The initfs archive, which may be compressed as an LZ4 frame
+enum Archive {
+    Raw(&'static [u8]),
+    Lz4(Lz4Frame),
 }
 
-#[cfg(not(test))]
-include!(concat!(env!("OUT_DIR"), "/gen.rs"));
+impl Archive {
+    fn len(&self) -> usize {
+        match *self {
+            Archive::Raw(data) => data.len(),
+            Archive::Lz4(ref frame) => frame.len()
+        }
+    }
 
-struct Handle {
-    path: &'static [u8],
-    data: &'static [u8],
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        match *self {
+            Archive::Raw(data) => {
+                let start = cmp::min(offset, data.len());
+                let count = cmp::min(buf.len(), data.len() - start);
+                buf[..count].copy_from_slice(&data[start .. start + count]);
+                Ok(count)
+            },
+            Archive::Lz4(ref frame) => frame.read_at(offset, buf)
+        }
+    }
+
+    fn read_exact(&self, offset: usize, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        if self.read_at(offset, &mut buf)? != len {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(buf)
+    }
+}
+
+/// A file, directory or symbolic link of the archive
+struct Node {
     mode: u16,
+    uid: u32,
+    gid: u32,
+    /// Where the contents of a file are in the archive, read when needed
+    offset: usize,
+    size: usize,
+    /// Names of the entries of a directory separated by newlines, or the target of a link
+    inline: Vec<u8>,
+}
+
+impl Node {
+    fn is_dir(&self) -> bool {
+        self.mode & MODE_TYPE == MODE_DIR
+    }
+
+    fn is_file(&self) -> bool {
+        self.mode & MODE_TYPE == MODE_FILE
+    }
+
+    fn size(&self) -> usize {
+        if self.is_file() {
+            self.size
+        } else {
+            self.inline.len()
+        }
+    }
+
+    fn read(&self, archive: &Archive, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let count = cmp::min(buf.len(), self.size().saturating_sub(offset));
+        if count == 0 {
+            Ok(0)
+        } else if self.is_file() {
+            archive.read_at(self.offset + offset, &mut buf[..count])
+        } else {
+            buf[..count].copy_from_slice(&self.inline[offset .. offset + count]);
+            Ok(count)
+        }
+    }
+
+    fn permitted(&self, uid: u32, gid: u32, bits: u16) -> bool {
+        let perm = if uid == 0 {
+            0o7
+        } else if uid == self.uid {
+            (self.mode >> 6) & 0o7
+        } else if gid == self.gid {
+            (self.mode >> 3) & 0o7
+        } else {
+            self.mode & 0o7
+        };
+        perm & bits == bits
+    }
+}
+
+/// The files of the initfs archive handed over by the bootloader
+struct InitFs {
+    archive: Archive,
+    nodes: BTreeMap<Box<[u8]>, Node>,
+}
+
+fn parse_hex(field: &[u8]) -> Option<u32> {
+    str::from_utf8(field).ok().and_then(|field| u32::from_str_radix(field, 16).ok())
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Remove `.` and empty components, so that the root is the empty path
+fn normalize(path: &[u8]) -> Vec<u8> {
+    let mut normal = Vec::new();
+    for part in path.split(|&b| b == b'/') {
+        if ! part.is_empty() && part != b"." {
+            if ! normal.is_empty() {
+                normal.push(b'/');
+            }
+            normal.extend_from_slice(part);
+        }
+    }
+    normal
+}
+
+/// Split a normalized path into its parent and its name
+fn split_parent(path: &[u8]) -> (&[u8], &[u8]) {
+    match path.iter().rposition(|&b| b == b'/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => (b"", path)
+    }
+}
+
+impl InitFs {
+    fn new(archive: Archive) -> InitFs {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(Vec::new().into_boxed_slice(), InitFs::dir_node());
+        InitFs {
+            archive: archive,
+            nodes: nodes
+        }
+    }
+
+    fn empty() -> InitFs {
+        InitFs::new(Archive::Raw(&[]))
+    }
+
+    /// Directories that are only implied by the paths of their entries
+    fn dir_node() -> Node {
+        Node {
+            mode: MODE_DIR | 0o755,
+            uid: 0,
+            gid: 0,
+            offset: 0,
+            size: 0,
+            inline: Vec::new(),
+        }
+    }
+
+    /// Parse a cpio archive in the "newc" format, as written by `cpio -H newc`. Only the
+    /// headers are read, the contents of files are read when needed
+    fn parse(archive: Archive) -> Result<InitFs> {
+        let mut fs = InitFs::new(archive);
+        let archive_len = fs.archive.len();
+
+        let mut offset = 0;
+        loop {
+            let header = fs.archive.read_exact(offset, CPIO_HEADER_SIZE)?;
+            if &header[..6] != b"070701" && &header[..6] != b"070702" {
+                return Err(Error::new(EINVAL));
+            }
+
+            let field = |i: usize| parse_hex(&header[6 + i * 8 .. 14 + i * 8]).ok_or(Error::new(EINVAL));
+            let mode = field(1)?;
+            let uid = field(2)?;
+            let gid = field(3)?;
+            let size = field(6)? as usize;
+            let name_size = field(11)? as usize;
+
+            // The name is followed by a nul byte, the name and data are padded to four bytes
+            let name_start = offset + CPIO_HEADER_SIZE;
+            let mut name = fs.archive.read_exact(name_start, name_size)?;
+            let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            name.truncate(name_len);
+            let data_start = align4(name_start + name_size);
+            if data_start + size > archive_len {
+                return Err(Error::new(EINVAL));
+            }
+            offset = align4(data_start + size);
+
+            if name == b"TRAILER!!!" {
+                break;
+            }
+
+            // Devices and other special files are left out
+            let mode = mode as u16;
+            match mode & MODE_TYPE {
+                MODE_DIR | MODE_FILE | MODE_SYMLINK => (),
+                _ => continue
+            }
+
+            // Targets of links are small, and needed to look up paths
+            let inline = if mode & MODE_TYPE == MODE_SYMLINK {
+                fs.archive.read_exact(data_start, size)?
+            } else {
+                Vec::new()
+            };
+
+            fs.nodes.insert(normalize(&name).into_boxed_slice(), Node {
+                mode: mode,
+                uid: uid,
+                gid: gid,
+                offset: data_start,
+                size: if mode & MODE_TYPE == MODE_FILE { size } else { 0 },
+                inline: inline,
+            });
+        }
+
+        // Add directories that are missing from the archive
+        let paths: Vec<Box<[u8]>> = fs.nodes.keys().cloned().collect();
+        for path in paths.iter() {
+            let mut parent: &[u8] = path;
+            while ! parent.is_empty() {
+                parent = split_parent(parent).0;
+                if ! fs.nodes.contains_key(parent) {
+                    fs.nodes.insert(parent.to_vec().into_boxed_slice(), InitFs::dir_node());
+                }
+            }
+        }
+
+        // List the entries of each directory, in order
+        let paths: Vec<Box<[u8]>> = fs.nodes.keys().cloned().collect();
+        for path in paths.iter() {
+            if path.is_empty() {
+                continue;
+            }
+
+            let (parent, name) = split_parent(path);
+            let node = fs.nodes.get_mut(parent).ok_or(Error::new(EINVAL))?;
+            if ! node.is_dir() {
+                return Err(Error::new(ENOTDIR));
+            }
+            if ! node.inline.is_empty() {
+                node.inline.push(b'\n');
+            }
+            node.inline.extend_from_slice(name);
+        }
+
+        Ok(fs)
+    }
+
+    /// Find the node at `path`, following links within the archive. The last component is
+    /// only followed if `follow` is set. Links to other schemes are left to the caller
+    fn lookup(&self, path: &[u8], follow: bool) -> Result<(Box<[u8]>, &Node)> {
+        let mut pending: Vec<Vec<u8>> = path.split(|&b| b == b'/').rev().map(|part| part.to_vec()).collect();
+        let mut resolved = Vec::new();
+        let mut hops = 0;
+
+        while let Some(part) = pending.pop() {
+            if part.is_empty() || part == b"." {
+                continue;
+            } else if part == b".." {
+                let parent_len = split_parent(&resolved).0.len();
+                resolved.truncate(parent_len);
+                continue;
+            }
+
+            let mut next = resolved.clone();
+            if ! next.is_empty() {
+                next.push(b'/');
+            }
+            next.extend_from_slice(&part);
+
+            let node = self.nodes.get(&next[..]).ok_or(Error::new(ENOENT))?;
+            let last = pending.iter().all(|part| part.is_empty() || part == b".");
+            if node.mode & MODE_TYPE == MODE_SYMLINK && (follow || ! last) && ! node.inline.contains(&b':') {
+                hops += 1;
+                if hops > SYMLINK_MAX_HOPS {
+                    return Err(Error::new(ELOOP));
+                }
+
+                if node.inline.starts_with(b"/") {
+                    resolved.clear();
+                }
+                for target_part in node.inline.split(|&b| b == b'/').rev() {
+                    pending.push(target_part.to_vec());
+                }
+            } else if ! last && node.mode & MODE_TYPE != MODE_DIR && node.mode & MODE_TYPE != MODE_SYMLINK {
+                return Err(Error::new(ENOTDIR));
+            } else if ! last && node.mode & MODE_TYPE == MODE_SYMLINK {
+                // A link to another scheme cannot have entries here
+                return Err(Error::new(EXDEV));
+            } else {
+                resolved = next;
+            }
+        }
+
+        let node = self.nodes.get(&resolved[..]).ok_or(Error::new(ENOENT))?;
+        Ok((resolved.into_boxed_slice(), node))
+    }
+}
+
+static INITFS: Once<InitFs> = Once::new();
+
+/// Parse the initfs archive handed over by the bootloader, which must stay mapped. It may
+/// be compressed as an LZ4 frame with independent blocks, such as made by `lz4 -BX`
+pub fn init(data: &'static [u8]) {
+    INITFS.call_once(|| {
+        if data.is_empty() {
+            println!("initfs: no archive");
+            return InitFs::empty();
+        }
+
+        let archive = if Lz4Frame::is_frame(data) {
+            match Lz4Frame::new(data) {
+                Ok(frame) => Archive::Lz4(frame),
+                Err(err) => {
+                    println!("initfs: failed to read compressed archive: {}", err);
+                    return InitFs::empty();
+                }
+            }
+        } else {
+            Archive::Raw(data)
+        };
+
+        match InitFs::parse(archive) {
+            Ok(fs) => fs,
+            Err(err) => {
+                println!("initfs: failed to parse archive: {}", err);
+                InitFs::empty()
+            }
+        }
+    });
+}
+
+fn initfs() -> &'static InitFs {
+    INITFS.call_once(InitFs::empty)
+}
+
+struct Handle {
+    path: Box<[u8]>,
+    node: &'static Node,
     seek: usize
 }
 
 pub struct InitFsScheme {
     next_id: AtomicUsize,
-    files: BTreeMap<&'static [u8], (&'static [u8], bool)>,
+    fs: &'static InitFs,
     handles: RwLock<BTreeMap<usize, Handle>>
 }
 
 impl InitFsScheme {
-    pub fn new() -> InitFsScheme {
+    pub fn new(scheme_id: SchemeId) -> InitFsScheme {
+        INITFS_SCHEME_ID.store(scheme_id, Ordering::SeqCst);
         InitFsScheme {
             next_id: AtomicUsize::new(0),
-            files: gen::gen(),
+            fs: initfs(),
             handles: RwLock::new(BTreeMap::new())
         }
     }
 }
 
 impl Scheme for InitFsScheme {
-    fn open(&self, path: &[u8], _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+    fn open(&self, path: &[u8], flags: usize, uid: u32, gid: u32) -> Result<usize> {
         let path_utf8 = str::from_utf8(path).or(Err(Error::new(ENOENT)))?;
-        let path_trimmed = path_utf8.trim_matches('/');
-
-        //Have to iterate to get the path without allocation
-        for entry in self.files.iter() {
-            if entry.0 == &path_trimmed.as_bytes() {
-                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
-                self.handles.write().insert(id, Handle {
-                    path: entry.0,
-                    data: (entry.1).0,
-                    mode: if (entry.1).1 { MODE_DIR |  0o755 } else { MODE_FILE | 0o744 },
-                    seek: 0
-                });
-
-                return Ok(id);
-            }
+
+        let (path, node) = self.fs.lookup(path_utf8.as_bytes(), flags & O_SYMLINK != O_SYMLINK)?;
+
+        if flags & (O_WRONLY | O_CREAT | O_TRUNC) != 0 {
+            return Err(Error::new(EROFS));
+        }
+        if flags & O_DIRECTORY == O_DIRECTORY && ! node.is_dir() {
+            return Err(Error::new(ENOTDIR));
         }
+        if flags & O_RDONLY == O_RDONLY && ! node.permitted(uid, gid, 0o4) {
+            return Err(Error::new(EACCES));
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(id, Handle {
+            path: path,
+            node: node,
+            seek: 0
+        });
 
-        Err(Error::new(ENOENT))
+        Ok(id)
     }
 
     fn read(&self, id: usize, buffer: &mut [u8]) -> Result<usize> {
         let mut handles = self.handles.write();
         let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
 
-        let mut i = 0;
-        while i < buffer.len() && handle.seek < handle.data.len() {
-            buffer[i] = handle.data[handle.seek];
-            i += 1;
-            handle.seek += 1;
-        }
+        let count = handle.node.read(&self.fs.archive, handle.seek, buffer)?;
+        handle.seek += count;
 
-        Ok(i)
+        Ok(count)
     }
 
     fn seek(&self, id: usize, pos: usize, whence: usize) -> Result<usize> {
         let mut handles = self.handles.write();
         let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let len = handle.node.size();
 
         handle.seek = match whence {
-            SEEK_SET => cmp::min(handle.data.len(), pos),
-            SEEK_CUR => cmp::max(0, cmp::min(handle.data.len() as isize, handle.seek as isize + pos as isize)) as usize,
-            SEEK_END => cmp::max(0, cmp::min(handle.data.len() as isize, handle.data.len() as isize + pos as isize)) as usize,
+            SEEK_SET => cmp::min(len, pos),
+            SEEK_CUR => cmp::max(0, cmp::min(len as isize, handle.seek as isize + pos as isize)) as usize,
+            SEEK_END => cmp::max(0, cmp::min(len as isize, len as isize + pos as isize)) as usize,
             _ => return Err(Error::new(EINVAL))
         };
 
@@ -124,10 +457,10 @@ impl Scheme for InitFsScheme {
         let handles = self.handles.read();
         let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
 
-        stat.st_mode = handle.mode;
-        stat.st_uid = 0;
-        stat.st_gid = 0;
-        stat.st_size = handle.data.len() as u64;
+        stat.st_mode = handle.node.mode;
+        stat.st_uid = handle.node.uid;
+        stat.st_gid = handle.node.gid;
+        stat.st_size = handle.node.size() as u64;
 
         Ok(0)
     }
@@ -142,3 +475,92 @@ impl Scheme for InitFsScheme {
         self.handles.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use super::{Archive, InitFs};
+    use syscall::error::{Error, EINVAL, ENOENT};
+    use syscall::flag::{MODE_DIR, MODE_FILE, MODE_SYMLINK};
+
+    fn entry(archive: &mut Vec<u8>, name: &str, mode: u16, data: &[u8]) {
+        let fields = [0, mode as u32, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+        archive.extend_from_slice(b"070701");
+        for field in fields.iter() {
+            archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        while archive.len() % 4 != 0 {
+            archive.push(0);
+        }
+        archive.extend_from_slice(data);
+        while archive.len() % 4 != 0 {
+            archive.push(0);
+        }
+    }
+
+    fn parse(archive: Vec<u8>) -> Result<InitFs, Error> {
+        InitFs::parse(Archive::Raw(Box::leak(archive.into_boxed_slice())))
+    }
+
+    #[test]
+    fn parse_entries() {
+        let mut archive = Vec::new();
+        entry(&mut archive, "etc", MODE_DIR | 0o755, b"");
+        entry(&mut archive, "etc/hostname", MODE_FILE | 0o644, b"redox");
+        entry(&mut archive, "bin/init", MODE_FILE | 0o755, b"\x7FELF");
+        entry(&mut archive, "TRAILER!!!", 0, b"");
+        let fs = parse(archive).unwrap();
+
+        let (path, node) = fs.lookup(b"etc/hostname", true).unwrap();
+        assert_eq!(&path[..], b"etc/hostname");
+        let mut buf = [0; 16];
+        let count = node.read(&fs.archive, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..count], b"redox");
+
+        // The parent of bin/init is implied
+        let (_, bin) = fs.lookup(b"bin", true).unwrap();
+        assert!(bin.is_dir());
+        let (_, root) = fs.lookup(b"", true).unwrap();
+        assert_eq!(&root.inline[..], b"bin\netc");
+    }
+
+    #[test]
+    fn parse_links() {
+        let mut archive = Vec::new();
+        entry(&mut archive, "etc/hostname", MODE_FILE | 0o644, b"redox");
+        entry(&mut archive, "name", MODE_SYMLINK | 0o777, b"etc/hostname");
+        entry(&mut archive, "TRAILER!!!", 0, b"");
+        let fs = parse(archive).unwrap();
+
+        let (path, _) = fs.lookup(b"name", true).unwrap();
+        assert_eq!(&path[..], b"etc/hostname");
+        let (path, link) = fs.lookup(b"name", false).unwrap();
+        assert_eq!(&path[..], b"name");
+        assert_eq!(&link.inline[..], b"etc/hostname");
+        assert_eq!(fs.lookup(b"missing", true).err(), Some(Error::new(ENOENT)));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let mut archive = Vec::new();
+        entry(&mut archive, "file", MODE_FILE | 0o644, b"data");
+        entry(&mut archive, "TRAILER!!!", 0, b"");
+
+        let mut magic = archive.clone();
+        magic[5] = b'7';
+        assert_eq!(parse(magic).err(), Some(Error::new(EINVAL)));
+
+        // Data past the end of the archive
+        let mut truncated = archive.clone();
+        truncated.truncate(super::CPIO_HEADER_SIZE + 8);
+        assert_eq!(parse(truncated).err(), Some(Error::new(EINVAL)));
+
+        // No trailer
+        let len = archive.len() - super::CPIO_HEADER_SIZE - 14;
+        archive.truncate(len);
+        assert_eq!(parse(archive).err(), Some(Error::new(EINVAL)));
+    }
+}
@@ -73,6 +73,7 @@ impl Scheme for IrqScheme {
             let current = COUNTS.lock()[file];
             if ack == current {
                 ACKS.lock()[file] = ack;
+                event::clear(IRQ_SCHEME_ID.load(Ordering::SeqCst), file, EVENT_READ);
                 unsafe { acknowledge(file); }
                 Ok(mem::size_of::<usize>())
             } else {
@@ -88,6 +89,11 @@ impl Scheme for IrqScheme {
     }
 
     fn fevent(&self, file: usize, _flags: usize) -> Result<usize> {
+        // Report interrupts that were not acknowledged before the registration
+        let ack = ACKS.lock()[file];
+        if COUNTS.lock()[file] != ack {
+            event::trigger(IRQ_SCHEME_ID.load(Ordering::SeqCst), file, EVENT_READ);
+        }
         Ok(file)
     }
 
@@ -1,26 +1,217 @@
 /// Disk scheme replacement when making live disk
 
 use alloc::sync::Arc;
+use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
-use core::{cmp, slice};
+use alloc::vec::Vec;
+use core::{cmp, mem, slice, str};
 use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::RwLock;
 
+use common::lz4::Lz4Frame;
 use syscall::data::Stat;
 use syscall::error::*;
 use syscall::flag::{MODE_FILE, SEEK_SET, SEEK_CUR, SEEK_END};
 use syscall::scheme::Scheme;
 
+/// Size of the blocks copied to the overlay when written
+pub const LIVE_BLOCK_SIZE: usize = 4096;
+
+/// Most bytes kept in the overlay, writes that need another block fail with `ENOSPC`
+pub const LIVE_OVERLAY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Size of each record of `disk/live:diff`, a little endian `u64` block number followed
+/// by the contents of the block
+pub const LIVE_DIFF_RECORD_SIZE: usize = mem::size_of::<u64>() + LIVE_BLOCK_SIZE;
+
+/// The live disk image as loaded, which is never changed. An image compressed as an LZ4
+/// frame is decompressed a block at a time when read
+enum Image {
+    Raw(&'static [u8]),
+    Lz4(Lz4Frame),
+}
+
+impl Image {
+    fn len(&self) -> usize {
+        match *self {
+            Image::Raw(data) => data.len(),
+            Image::Lz4(ref frame) => frame.len()
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        match *self {
+            Image::Raw(data) => {
+                let start = cmp::min(offset, data.len());
+                let count = cmp::min(buf.len(), data.len() - start);
+                buf[..count].copy_from_slice(&data[start .. start + count]);
+                Ok(count)
+            },
+            Image::Lz4(ref frame) => frame.read_at(offset, buf)
+        }
+    }
+}
+
+/// The live disk, with written blocks kept in a copy-on-write overlay over the image
+struct Disk {
+    image: Image,
+    /// Blocks that were written, by block number
+    overlay: RwLock<BTreeMap<usize, Box<[u8]>>>,
+}
+
+impl Disk {
+    fn len(&self) -> usize {
+        self.image.len()
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let overlay = self.overlay.read();
+
+        let len = self.len();
+        let mut i = 0;
+        while i < buf.len() && offset + i < len {
+            let position = offset + i;
+            let block = position / LIVE_BLOCK_SIZE;
+            let start = position % LIVE_BLOCK_SIZE;
+            let count = cmp::min(cmp::min(buf.len() - i, LIVE_BLOCK_SIZE - start), len - position);
+
+            match overlay.get(&block) {
+                Some(data) => buf[i .. i + count].copy_from_slice(&data[start .. start + count]),
+                None => if self.image.read_at(position, &mut buf[i .. i + count])? != count {
+                    return Err(Error::new(EIO));
+                }
+            }
+
+            i += count;
+        }
+
+        Ok(i)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let mut overlay = self.overlay.write();
+
+        let len = self.len();
+        let mut i = 0;
+        while i < buf.len() && offset + i < len {
+            let position = offset + i;
+            let block = position / LIVE_BLOCK_SIZE;
+            let start = position % LIVE_BLOCK_SIZE;
+            let count = cmp::min(cmp::min(buf.len() - i, LIVE_BLOCK_SIZE - start), len - position);
+
+            if ! overlay.contains_key(&block) {
+                if (overlay.len() + 1) * LIVE_BLOCK_SIZE > LIVE_OVERLAY_SIZE {
+                    if i > 0 {
+                        break;
+                    }
+                    return Err(Error::new(ENOSPC));
+                }
+
+                // Copy the block from the image on its first write
+                let mut data = vec![0; LIVE_BLOCK_SIZE].into_boxed_slice();
+                self.image.read_at(block * LIVE_BLOCK_SIZE, &mut data)?;
+                overlay.insert(block, data);
+            }
+
+            if let Some(data) = overlay.get_mut(&block) {
+                data[start .. start + count].copy_from_slice(&buf[i .. i + count]);
+            }
+
+            i += count;
+        }
+
+        Ok(i)
+    }
+
+    /// Numbers of the blocks in the overlay, one per line
+    fn dirty(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for block in self.overlay.read().keys() {
+            data.extend_from_slice(format!("{}\n", block).as_bytes());
+        }
+        data
+    }
+
+    fn diff_len(&self) -> usize {
+        self.overlay.read().len() * LIVE_DIFF_RECORD_SIZE
+    }
+
+    /// Read the overlay as `LIVE_DIFF_RECORD_SIZE` records, ordered by block number
+    fn read_diff(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let overlay = self.overlay.read();
+
+        let mut i = 0;
+        let mut records = overlay.iter().skip(offset / LIVE_DIFF_RECORD_SIZE);
+        let mut start = offset % LIVE_DIFF_RECORD_SIZE;
+        while i < buf.len() {
+            let (&block, data) = match records.next() {
+                Some(record) => record,
+                None => break
+            };
+
+            let mut header = [0; 8];
+            for (j, byte) in header.iter_mut().enumerate() {
+                *byte = ((block as u64) >> (j * 8)) as u8;
+            }
+
+            while i < buf.len() && start < LIVE_DIFF_RECORD_SIZE {
+                let (src, src_start) = if start < header.len() {
+                    (&header[..], start)
+                } else {
+                    (&data[..], start - header.len())
+                };
+                let count = cmp::min(buf.len() - i, src.len() - src_start);
+                buf[i .. i + count].copy_from_slice(&src[src_start .. src_start + count]);
+                i += count;
+                start += count;
+            }
+            start = 0;
+        }
+
+        Ok(i)
+    }
+
+    /// Drop every written block, returning to the image as loaded
+    fn discard(&self) {
+        self.overlay.write().clear();
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Kind {
+    /// The contents of the disk
+    Disk,
+    /// Numbers of the written blocks
+    Dirty,
+    /// The written blocks and their contents, truncating it discards them
+    Diff,
+}
+
 struct Handle {
     path: &'static [u8],
-    data: Arc<RwLock<&'static mut [u8]>>,
+    kind: Kind,
+    data: Arc<Disk>,
+    /// Blocks listed by `Kind::Dirty`, as when opened
+    dirty: Box<[u8]>,
     mode: u16,
+    /// User that opened the handle
+    uid: u32,
     seek: usize
 }
 
+impl Handle {
+    fn len(&self) -> usize {
+        match self.kind {
+            Kind::Disk => self.data.len(),
+            Kind::Dirty => self.dirty.len(),
+            Kind::Diff => self.data.diff_len()
+        }
+    }
+}
+
 pub struct DiskScheme {
     next_id: AtomicUsize,
-    data: Arc<RwLock<&'static mut [u8]>>,
+    data: Arc<Disk>,
     handles: RwLock<BTreeMap<usize, Handle>>
 }
 
@@ -37,27 +228,60 @@ impl DiskScheme {
             let end = &mut __live_end as *mut u8;
 
             if end as usize >= start as usize {
-                data = slice::from_raw_parts_mut(start, end as usize - start as usize);
+                data = slice::from_raw_parts(start as *const u8, end as usize - start as usize);
             } else {
-                data = &mut [];
+                data = &[];
             };
         }
 
+        let image = if Lz4Frame::is_frame(data) {
+            match Lz4Frame::new(data) {
+                Ok(frame) => Image::Lz4(frame),
+                Err(err) => {
+                    println!("live: failed to read compressed image: {}", err);
+                    Image::Raw(&[])
+                }
+            }
+        } else {
+            Image::Raw(data)
+        };
+
         DiskScheme {
             next_id: AtomicUsize::new(0),
-            data: Arc::new(RwLock::new(data)),
+            data: Arc::new(Disk {
+                image: image,
+                overlay: RwLock::new(BTreeMap::new()),
+            }),
             handles: RwLock::new(BTreeMap::new())
         }
     }
 }
 
 impl Scheme for DiskScheme {
-    fn open(&self, _path: &[u8], _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+    fn open(&self, path: &[u8], _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let path_utf8 = str::from_utf8(path).or(Err(Error::new(ENOENT)))?;
+
+        let (path, kind): (&'static [u8], Kind) = match path_utf8.trim_matches('/') {
+            "" | "0" => (b"0", Kind::Disk),
+            "dirty" => (b"dirty", Kind::Dirty),
+            "diff" => (b"diff", Kind::Diff),
+            _ => return Err(Error::new(ENOENT))
+        };
+
+        let dirty: Box<[u8]> = if kind == Kind::Dirty {
+            self.data.dirty().into_boxed_slice()
+        } else {
+            Box::new([])
+        };
+
         let id = self.next_id.fetch_add(1, Ordering::SeqCst);
         self.handles.write().insert(id, Handle {
-            path: b"0",
+            path: path,
+            kind: kind,
             data: self.data.clone(),
+            dirty: dirty,
             mode: MODE_FILE | 0o744,
+            uid: uid,
             seek: 0
         });
 
@@ -67,42 +291,45 @@ impl Scheme for DiskScheme {
     fn read(&self, id: usize, buffer: &mut [u8]) -> Result<usize> {
         let mut handles = self.handles.write();
         let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
-        let data = handle.data.read();
 
-        let mut i = 0;
-        while i < buffer.len() && handle.seek < data.len() {
-            buffer[i] = data[handle.seek];
-            i += 1;
-            handle.seek += 1;
-        }
+        let count = match handle.kind {
+            Kind::Disk => handle.data.read_at(handle.seek, buffer)?,
+            Kind::Dirty => {
+                let start = cmp::min(handle.seek, handle.dirty.len());
+                let count = cmp::min(buffer.len(), handle.dirty.len() - start);
+                buffer[..count].copy_from_slice(&handle.dirty[start .. start + count]);
+                count
+            },
+            Kind::Diff => handle.data.read_diff(handle.seek, buffer)?
+        };
+        handle.seek += count;
 
-        Ok(i)
+        Ok(count)
     }
 
     fn write(&self, id: usize, buffer: &[u8]) -> Result<usize> {
         let mut handles = self.handles.write();
         let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
-        let mut data = handle.data.write();
 
-        let mut i = 0;
-        while i < buffer.len() && handle.seek < data.len() {
-            data[handle.seek] = buffer[i];
-            i += 1;
-            handle.seek += 1;
+        if handle.kind != Kind::Disk {
+            return Err(Error::new(EBADF));
         }
 
-        Ok(i)
+        let count = handle.data.write_at(handle.seek, buffer)?;
+        handle.seek += count;
+
+        Ok(count)
     }
 
     fn seek(&self, id: usize, pos: usize, whence: usize) -> Result<usize> {
         let mut handles = self.handles.write();
         let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
-        let data = handle.data.read();
+        let len = handle.len();
 
         handle.seek = match whence {
-            SEEK_SET => cmp::min(data.len(), pos),
-            SEEK_CUR => cmp::max(0, cmp::min(data.len() as isize, handle.seek as isize + pos as isize)) as usize,
-            SEEK_END => cmp::max(0, cmp::min(data.len() as isize, data.len() as isize + pos as isize)) as usize,
+            SEEK_SET => cmp::min(len, pos),
+            SEEK_CUR => cmp::max(0, cmp::min(len as isize, handle.seek as isize + pos as isize)) as usize,
+            SEEK_END => cmp::max(0, cmp::min(len as isize, len as isize + pos as isize)) as usize,
             _ => return Err(Error::new(EINVAL))
         };
 
@@ -122,7 +349,7 @@ impl Scheme for DiskScheme {
 
         //TODO: Copy scheme part in kernel
         let mut i = 0;
-        let scheme_path = b"disk:";
+        let scheme_path = b"disk/live:";
         while i < buf.len() && i < scheme_path.len() {
             buf[i] = scheme_path[i];
             i += 1;
@@ -141,12 +368,11 @@ impl Scheme for DiskScheme {
     fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
         let handles = self.handles.read();
         let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
-        let data = handle.data.read();
 
         stat.st_mode = handle.mode;
         stat.st_uid = 0;
         stat.st_gid = 0;
-        stat.st_size = data.len() as u64;
+        stat.st_size = handle.len() as u64;
 
         Ok(0)
     }
@@ -158,6 +384,24 @@ impl Scheme for DiskScheme {
         Ok(0)
     }
 
+    /// Truncating `disk/live:diff` to zero discards the overlay, only root may do so
+    fn ftruncate(&self, id: usize, len: usize) -> Result<usize> {
+        let handles = self.handles.read();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        match handle.kind {
+            Kind::Diff if len == 0 => {
+                if handle.uid != 0 {
+                    return Err(Error::new(EACCES));
+                }
+                handle.data.discard();
+                Ok(0)
+            },
+            Kind::Diff => Err(Error::new(EINVAL)),
+            _ => Err(Error::new(EBADF))
+        }
+    }
+
     fn close(&self, id: usize) -> Result<usize> {
         self.handles.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
     }
@@ -8,29 +8,44 @@
 
 use alloc::sync::Arc;
 use alloc::boxed::Box;
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
 use core::sync::atomic::AtomicUsize;
 use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use syscall::error::*;
 use syscall::scheme::Scheme;
 
+use self::acl::SchemeAcl;
+use self::chan::ChanScheme;
 use self::debug::DebugScheme;
 use self::event::EventScheme;
+use self::eventfd::EventFdScheme;
 use self::initfs::InitFsScheme;
 use self::irq::IrqScheme;
 use self::memory::MemoryScheme;
 use self::pipe::PipeScheme;
+use self::ring::RingScheme;
 use self::root::RootScheme;
+use self::signalfd::SignalFdScheme;
 use self::sys::SysScheme;
 use self::time::TimeScheme;
 
+/// `chan:` - local sockets with named listening endpoints
+pub mod chan;
+
+/// Access control lists of scheme names
+pub mod acl;
+
 /// `debug:` - provides access to serial console
 pub mod debug;
 
 /// `event:` - allows reading of `Event`s which are registered using `fevent`
 pub mod event;
 
+/// `eventfd:` - counters for wakeups that can be waited on with events
+pub mod eventfd;
+
 /// `initfs:` - a readonly filesystem used for initializing the system
 pub mod initfs;
 
@@ -47,9 +62,15 @@ pub mod memory;
 /// `pipe:` - used internally by the kernel to implement `pipe`
 pub mod pipe;
 
+/// `ring:` - submission and completion queues for asynchronous file operations
+pub mod ring;
+
 /// `:` - allows the creation of userspace schemes, tightly dependent on `user`
 pub mod root;
 
+/// `signalfd:` - receive signals as records instead of running handlers
+pub mod signalfd;
+
 /// `sys:` - system information, such as the context list and scheme list
 pub mod sys;
 
@@ -89,6 +110,14 @@ impl<'a> Iterator for SchemeIter<'a> {
 pub struct SchemeList {
     map: BTreeMap<SchemeId, Arc<Box<Scheme + Send + Sync>>>,
     names: BTreeMap<SchemeNamespace, BTreeMap<Box<[u8]>, SchemeId>>,
+    /// Paths that names bound to part of a scheme are rooted at
+    binds: BTreeMap<SchemeNamespace, BTreeMap<Box<[u8]>, Box<[u8]>>>,
+    /// The namespace each namespace was made from
+    parents: BTreeMap<SchemeNamespace, SchemeNamespace>,
+    /// Namespaces made without privileges, and those made from them
+    restricted: BTreeSet<SchemeNamespace>,
+    /// Access control lists of names, kept when the scheme of a name goes away
+    acls: BTreeMap<SchemeNamespace, BTreeMap<Box<[u8]>, SchemeAcl>>,
     next_ns: usize,
     next_id: usize
 }
@@ -99,6 +128,10 @@ impl SchemeList {
         let mut list = SchemeList {
             map: BTreeMap::new(),
             names: BTreeMap::new(),
+            binds: BTreeMap::new(),
+            parents: BTreeMap::new(),
+            restricted: BTreeSet::new(),
+            acls: BTreeMap::new(),
             // Scheme namespaces always start at 1. 0 is a reserved namespace, the null namespace
             next_ns: 1,
             next_id: 1
@@ -114,8 +147,12 @@ impl SchemeList {
         self.names.insert(ns, BTreeMap::new());
 
         self.insert(ns, Box::new(*b""), |scheme_id| Arc::new(Box::new(RootScheme::new(ns, scheme_id)))).unwrap();
+        self.insert(ns, Box::new(*b"chan"), |scheme_id| Arc::new(Box::new(ChanScheme::new(scheme_id)))).unwrap();
         self.insert(ns, Box::new(*b"event"), |_| Arc::new(Box::new(EventScheme))).unwrap();
+        self.insert(ns, Box::new(*b"eventfd"), |scheme_id| Arc::new(Box::new(EventFdScheme::new(scheme_id)))).unwrap();
         self.insert(ns, Box::new(*b"memory"), |_| Arc::new(Box::new(MemoryScheme::new()))).unwrap();
+        self.insert(ns, Box::new(*b"ring"), |scheme_id| Arc::new(Box::new(RingScheme::new(scheme_id)))).unwrap();
+        self.insert(ns, Box::new(*b"signalfd"), |scheme_id| Arc::new(Box::new(SignalFdScheme::new(scheme_id)))).unwrap();
         self.insert(ns, Box::new(*b"sys"), |_| Arc::new(Box::new(SysScheme::new()))).unwrap();
         self.insert(ns, Box::new(*b"time"), |scheme_id| Arc::new(Box::new(TimeScheme::new(scheme_id)))).unwrap();
 
@@ -130,7 +167,7 @@ impl SchemeList {
 
         // Debug, Initfs and IRQ are only available in the root namespace. Pipe is special
         self.insert(ns, Box::new(*b"debug"), |scheme_id| Arc::new(Box::new(DebugScheme::new(scheme_id)))).unwrap();
-        self.insert(ns, Box::new(*b"initfs"), |_| Arc::new(Box::new(InitFsScheme::new()))).unwrap();
+        self.insert(ns, Box::new(*b"initfs"), |scheme_id| Arc::new(Box::new(InitFsScheme::new(scheme_id)))).unwrap();
         self.insert(ns, Box::new(*b"irq"), |scheme_id| Arc::new(Box::new(IrqScheme::new(scheme_id)))).unwrap();
         self.insert(ns, Box::new(*b"pipe"), |scheme_id| Arc::new(Box::new(PipeScheme::new(scheme_id)))).unwrap();
     }
@@ -144,33 +181,159 @@ impl SchemeList {
         // Debug, Disk, Initfs and IRQ are only available in the root namespace. Pipe is special
         self.insert(ns, Box::new(*b"debug"), |scheme_id| Arc::new(Box::new(DebugScheme::new(scheme_id)))).unwrap();
         self.insert(ns, Box::new(*b"disk/live"), |_| Arc::new(Box::new(self::live::DiskScheme::new()))).unwrap();
-        self.insert(ns, Box::new(*b"initfs"), |_| Arc::new(Box::new(InitFsScheme::new()))).unwrap();
+        self.insert(ns, Box::new(*b"initfs"), |scheme_id| Arc::new(Box::new(InitFsScheme::new(scheme_id)))).unwrap();
         self.insert(ns, Box::new(*b"irq"), |scheme_id| Arc::new(Box::new(IrqScheme::new(scheme_id)))).unwrap();
         self.insert(ns, Box::new(*b"pipe"), |scheme_id| Arc::new(Box::new(PipeScheme::new(scheme_id)))).unwrap();
     }
 
+    /// Create a namespace containing `names` from the namespace `from`
+    ///
+    /// Each name is either copied as is, like `file`, bound under another name, like
+    /// `root=file`, or bound to a path of a scheme, like `app=file:/srv/app`, which
+    /// makes `app:/bin` refer to `file:/srv/app/bin`. Schemes every namespace starts
+    /// with are left out if `from` does not have them, so access can only be narrowed
     pub fn make_ns(&mut self, from: SchemeNamespace, names: &[&[u8]]) -> Result<SchemeNamespace> {
+        // Look up every name before creating anything
+        let mut entries = Vec::new();
+        for entry in names.iter() {
+            let (name, source) = {
+                let mut parts = entry.splitn(2, |&b| b == b'=');
+                let name = parts.next().unwrap_or(b"");
+                (name, parts.next().unwrap_or(name))
+            };
+
+            if name.contains(&b':') {
+                return Err(Error::new(EINVAL));
+            }
+
+            let mut parts = source.splitn(2, |&b| b == b':');
+            let source_name = parts.next().unwrap_or(b"");
+            let (id, path) = match parts.next() {
+                Some(reference) => {
+                    let (id, _scheme, path) = self.resolve(from, source_name, reference).ok_or(Error::new(ENODEV))?;
+                    (id, Some(path))
+                },
+                None => match self.binds.get(&from).and_then(|binds| binds.get(source_name)) {
+                    // Bound names keep their path when copied
+                    Some(prefix) => (self.get_name(from, source_name).ok_or(Error::new(ENODEV))?.0, Some(prefix.to_vec())),
+                    None => (self.get_name(from, source_name).ok_or(Error::new(ENODEV))?.0, None)
+                }
+            };
+
+            // Names keep the access control list of the name they are made from
+            let acl = self.acl(from, source_name).cloned();
+
+            entries.push((name.to_vec().into_boxed_slice(), id, path, acl));
+        }
+
         // Create an empty namespace
         let to = self.new_ns();
+        self.parents.insert(to, from);
+        if self.restricted.contains(&from) {
+            self.restricted.insert(to);
+        }
 
-        // Copy requested scheme IDs
-        for name in names.iter() {
-            let id = if let Some((id, _scheme)) = self.get_name(from, name) {
-                id
-            } else {
-                return Err(Error::new(ENODEV));
+        // Drop the schemes `new_ns` added that `from` does not have
+        let missing: Vec<(Box<[u8]>, SchemeId)> = {
+            let from_names = self.names.get(&from);
+            match self.names.get(&to) {
+                Some(names) => names.iter()
+                    .filter(|&(name, _id)| ! name.is_empty() && from_names.map_or(true, |from_names| ! from_names.contains_key(name)))
+                    .map(|(name, &id)| (name.clone(), id))
+                    .collect(),
+                None => Vec::new()
+            }
+        };
+        for (name, id) in missing {
+            if let Some(ref mut names) = self.names.get_mut(&to) {
+                names.remove(&name);
+            }
+            self.map.remove(&id);
+        }
+
+        let kept_acls: Vec<(Box<[u8]>, SchemeAcl)> = match (self.names.get(&to), self.acls.get(&from)) {
+            (Some(names), Some(acls)) => names.keys()
+                .filter_map(|name| acls.get(name).map(|acl| (name.clone(), acl.clone())))
+                .collect(),
+            _ => Vec::new()
+        };
+        for (name, acl) in kept_acls {
+            self.set_acl(to, name, acl);
+        }
+
+        for (name, id, path, acl) in entries {
+            let old_opt = match self.names.get_mut(&to) {
+                // Replaces the namespace's own instance, so that `chan:` names can be shared
+                Some(names) => names.insert(name.clone(), id),
+                None => panic!("scheme namespace not found")
             };
 
-            if let Some(ref mut names) = self.names.get_mut(&to) {
-                assert!(names.insert(name.to_vec().into_boxed_slice(), id).is_none());
-            } else {
-                panic!("scheme namespace not found");
+            // The replaced instance was just made, so no descriptor is open on it
+            if let Some(old_id) = old_opt {
+                self.remove_unnamed(old_id);
+            }
+
+            if let Some(acl) = acl {
+                self.set_acl(to, name.clone(), acl);
+            }
+
+            if let Some(path) = path {
+                self.binds.entry(to).or_insert_with(BTreeMap::new).insert(name, path.into_boxed_slice());
             }
         }
 
         Ok(to)
     }
 
+    /// Check if `ns` is `ancestor`, or was made from it
+    pub fn is_within(&self, ns: SchemeNamespace, ancestor: SchemeNamespace) -> bool {
+        let mut ns = ns;
+        loop {
+            if ns == ancestor {
+                return true;
+            }
+            ns = match self.parents.get(&ns) {
+                Some(&parent) => parent,
+                None => return false
+            };
+        }
+    }
+
+    /// Get the access control list of a name
+    pub fn acl(&self, ns: SchemeNamespace, name: &[u8]) -> Option<&SchemeAcl> {
+        self.acls.get(&ns).and_then(|acls| acls.get(name))
+    }
+
+    /// Set the access control list of a name, an empty one removes it
+    pub fn set_acl(&mut self, ns: SchemeNamespace, name: Box<[u8]>, acl: SchemeAcl) {
+        if acl.is_empty() {
+            if let Some(acls) = self.acls.get_mut(&ns) {
+                acls.remove(&name);
+            }
+        } else {
+            self.acls.entry(ns).or_insert_with(BTreeMap::new).insert(name, acl);
+        }
+    }
+
+    /// Check that `uid` or `gid` may use a name with `access`. Names without an access
+    /// control list may be used by anyone
+    pub fn check_access(&self, ns: SchemeNamespace, name: &[u8], uid: u32, gid: u32, access: u8) -> Result<()> {
+        match self.acl(ns, name) {
+            Some(acl) => acl.check(uid, gid, access),
+            None => Ok(())
+        }
+    }
+
+    /// Mark a namespace as made without privileges. Set-ID executables run from it do not
+    /// gain privileges, as its names may lead to files other than the ones they expect
+    pub fn restrict(&mut self, ns: SchemeNamespace) {
+        self.restricted.insert(ns);
+    }
+
+    pub fn is_restricted(&self, ns: SchemeNamespace) -> bool {
+        self.restricted.contains(&ns)
+    }
+
     pub fn iter(&self) -> ::alloc::collections::btree_map::Iter<SchemeId, Arc<Box<Scheme + Send + Sync>>> {
         self.map.iter()
     }
@@ -181,6 +344,10 @@ impl SchemeList {
         }
     }
 
+    pub fn iter_ns(&self) -> ::alloc::collections::btree_map::Keys<SchemeNamespace, BTreeMap<Box<[u8]>, SchemeId>> {
+        self.names.keys()
+    }
+
     /// Get the nth scheme.
     pub fn get(&self, id: SchemeId) -> Option<&Arc<Box<Scheme + Send + Sync>>> {
         self.map.get(&id)
@@ -195,6 +362,125 @@ impl SchemeList {
         None
     }
 
+    /// Find the scheme for `name`, and the reference to open in it, following binds to paths
+    pub fn resolve(&self, ns: SchemeNamespace, name: &[u8], reference: &[u8]) -> Option<(SchemeId, &Arc<Box<Scheme + Send + Sync>>, Vec<u8>)> {
+        let (id, scheme) = self.get_name(ns, name)?;
+
+        let prefix = match self.binds.get(&ns).and_then(|binds| binds.get(name)) {
+            Some(prefix) => prefix,
+            None => return Some((id, scheme, reference.to_vec()))
+        };
+
+        // Parent directories may not leave the bound path
+        let mut parts = Vec::new();
+        for part in reference.split(|&b| b == b'/') {
+            if part == b".." {
+                parts.pop();
+            } else if ! part.is_empty() && part != b"." {
+                parts.push(part);
+            }
+        }
+
+        let mut path = prefix.to_vec();
+        while path.ends_with(b"/") {
+            path.pop();
+        }
+        for part in parts.iter() {
+            path.push(b'/');
+            path.extend_from_slice(part);
+        }
+        if path.is_empty() {
+            path.push(b'/');
+        }
+
+        Some((id, scheme, path))
+    }
+
+    /// Rewrite `path`, as reported by the scheme `id`, to how it is reached from `ns`
+    ///
+    /// Returns `None` if it is not reachable through a bound name
+    pub fn unresolve(&self, ns: SchemeNamespace, id: SchemeId, path: &[u8]) -> Option<Vec<u8>> {
+        let binds = self.binds.get(&ns)?;
+        let names = self.names.get(&ns)?;
+
+        let mut parts = path.splitn(2, |&b| b == b':');
+        let path_name = parts.next().unwrap_or(b"");
+        let reference = parts.next()?;
+
+        // The name with the longest bound path containing the reference is used
+        let mut best: Option<(&[u8], &[u8], usize)> = None;
+        for (name, &name_id) in names.iter() {
+            if name_id != id {
+                continue;
+            }
+
+            let (rest, len) = match binds.get(name) {
+                Some(prefix) => {
+                    let mut prefix: &[u8] = prefix;
+                    while prefix.ends_with(b"/") {
+                        prefix = &prefix[.. prefix.len() - 1];
+                    }
+                    if ! reference.starts_with(prefix) {
+                        continue;
+                    }
+                    let rest = &reference[prefix.len() ..];
+                    if ! rest.is_empty() && ! rest.starts_with(b"/") {
+                        continue;
+                    }
+                    (rest, prefix.len() + 1)
+                },
+                // Unchanged, if the scheme is also reachable under its own name
+                None if &name[..] == path_name => return None,
+                None => (reference, 0)
+            };
+
+            if best.map_or(true, |(_, _, best_len)| len > best_len) {
+                best = Some((&name[..], rest, len));
+            }
+        }
+
+        let (name, rest, _len) = best?;
+        let mut remapped = name.to_vec();
+        remapped.push(b':');
+        if rest.is_empty() {
+            remapped.push(b'/');
+        } else {
+            remapped.extend_from_slice(rest);
+        }
+        Some(remapped)
+    }
+
+    /// Remove the names of a scheme from every namespace. It stays registered under its ID,
+    /// so open descriptors keep reaching it until it is removed
+    pub fn remove_names(&mut self, id: SchemeId) {
+        for (ns, names) in self.names.iter_mut() {
+            let matching: Vec<Box<[u8]>> = names.iter()
+                .filter(|&(_name, &name_id)| name_id == id)
+                .map(|(name, _name_id)| name.clone())
+                .collect();
+            for name in matching {
+                names.remove(&name);
+                if let Some(binds) = self.binds.get_mut(ns) {
+                    binds.remove(&name);
+                }
+            }
+        }
+    }
+
+    /// Remove a scheme whose names were removed. Descriptors still open on it fail with
+    /// `EBADF`, until its ID is reused after IDs wrap around at `SCHEME_MAX_SCHEMES`
+    pub fn remove(&mut self, id: SchemeId) {
+        self.map.remove(&id);
+    }
+
+    /// Remove a scheme if no namespace has a name for it anymore
+    fn remove_unnamed(&mut self, id: SchemeId) {
+        let named = self.names.values().any(|names| names.values().any(|&name_id| name_id == id));
+        if ! named {
+            self.remove(id);
+        }
+    }
+
     /// Create a new scheme.
     pub fn insert<F>(&mut self, ns: SchemeNamespace, name: Box<[u8]>, scheme_fn: F) -> Result<SchemeId>
         where F: Fn(SchemeId) -> Arc<Box<Scheme + Send + Sync>>
@@ -233,6 +519,38 @@ impl SchemeList {
         }
         Ok(id)
     }
+
+    /// Create a new scheme that takes `name` over from the scheme registered as it, in
+    /// every namespace the old scheme is reachable from. Descriptors that are already
+    /// open keep using the old scheme
+    pub fn replace<F>(&mut self, ns: SchemeNamespace, name: Box<[u8]>, scheme_fn: F) -> Result<SchemeId>
+        where F: Fn(SchemeId) -> Arc<Box<Scheme + Send + Sync>>
+    {
+        let old_id = match self.names.get_mut(&ns) {
+            Some(names) => names.remove(&name).ok_or(Error::new(ENOENT))?,
+            None => return Err(Error::new(ENODEV))
+        };
+
+        let id = match self.insert(ns, name.clone(), scheme_fn) {
+            Ok(id) => id,
+            Err(err) => {
+                if let Some(names) = self.names.get_mut(&ns) {
+                    names.insert(name, old_id);
+                }
+                return Err(err);
+            }
+        };
+
+        for names in self.names.values_mut() {
+            for name_id in names.values_mut() {
+                if *name_id == old_id {
+                    *name_id = id;
+                }
+            }
+        }
+
+        Ok(id)
+    }
 }
 
 /// Schemes list
@@ -252,3 +570,103 @@ pub fn schemes() -> RwLockReadGuard<'static, SchemeList> {
 pub fn schemes_mut() -> RwLockWriteGuard<'static, SchemeList> {
     SCHEMES.call_once(init_schemes).write()
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use alloc::boxed::Box;
+    use alloc::collections::{BTreeMap, BTreeSet};
+    use alloc::vec::Vec;
+    use super::{SchemeId, SchemeList, SchemeNamespace};
+    use super::event::EventScheme;
+
+    /// A namespace with one scheme reachable under `names`, each bound to a path if given
+    fn make_list(names: &[(&str, Option<&str>)]) -> (SchemeList, SchemeNamespace, SchemeId) {
+        let ns = SchemeNamespace::from(1);
+        let id = SchemeId::from(1);
+
+        let mut list = SchemeList {
+            map: BTreeMap::new(),
+            names: BTreeMap::new(),
+            binds: BTreeMap::new(),
+            parents: BTreeMap::new(),
+            restricted: BTreeSet::new(),
+            acls: BTreeMap::new(),
+            next_ns: 2,
+            next_id: 2
+        };
+        list.map.insert(id, Arc::new(Box::new(EventScheme)));
+
+        let mut ns_names = BTreeMap::new();
+        let mut ns_binds = BTreeMap::new();
+        for &(name, path) in names.iter() {
+            ns_names.insert(name.as_bytes().to_vec().into_boxed_slice(), id);
+            if let Some(path) = path {
+                ns_binds.insert(name.as_bytes().to_vec().into_boxed_slice(), path.as_bytes().to_vec().into_boxed_slice());
+            }
+        }
+        list.names.insert(ns, ns_names);
+        list.binds.insert(ns, ns_binds);
+
+        (list, ns, id)
+    }
+
+    fn resolve(list: &SchemeList, ns: SchemeNamespace, name: &[u8], reference: &[u8]) -> Option<Vec<u8>> {
+        list.resolve(ns, name, reference).map(|(_id, _scheme, path)| path)
+    }
+
+    #[test]
+    fn resolve_bound() {
+        let (list, ns, _id) = make_list(&[("file", None), ("app", Some("/srv/app/"))]);
+
+        assert_eq!(resolve(&list, ns, b"file", b"/etc/../bin"), Some(b"/etc/../bin".to_vec()));
+        assert_eq!(resolve(&list, ns, b"app", b"/bin/./ls"), Some(b"/srv/app/bin/ls".to_vec()));
+        assert_eq!(resolve(&list, ns, b"app", b""), Some(b"/srv/app".to_vec()));
+        assert_eq!(resolve(&list, ns, b"missing", b"/"), None);
+    }
+
+    #[test]
+    fn resolve_stays_inside() {
+        let (list, ns, _id) = make_list(&[("app", Some("/srv/app"))]);
+
+        assert_eq!(resolve(&list, ns, b"app", b"/../../etc/passwd"), Some(b"/srv/app/etc/passwd".to_vec()));
+        assert_eq!(resolve(&list, ns, b"app", b"bin/.."), Some(b"/srv/app".to_vec()));
+
+        let (list, ns, _id) = make_list(&[("root", Some("/"))]);
+        assert_eq!(resolve(&list, ns, b"root", b".."), Some(b"/".to_vec()));
+    }
+
+    #[test]
+    fn unresolve_bound() {
+        let (list, ns, id) = make_list(&[("app", Some("/srv/app")), ("bin", Some("/srv/app/bin"))]);
+
+        assert_eq!(list.unresolve(ns, id, b"file:/srv/app/etc"), Some(b"app:/etc".to_vec()));
+        assert_eq!(list.unresolve(ns, id, b"file:/srv/app"), Some(b"app:/".to_vec()));
+        // The longest bound path is used
+        assert_eq!(list.unresolve(ns, id, b"file:/srv/app/bin/ls"), Some(b"bin:/ls".to_vec()));
+        // Only whole components match
+        assert_eq!(list.unresolve(ns, id, b"file:/srv/application"), None);
+        assert_eq!(list.unresolve(ns, id, b"/srv/app"), None);
+    }
+
+    #[test]
+    fn remove_unnamed() {
+        let (mut list, _ns, id) = make_list(&[("file", None)]);
+        let unnamed = SchemeId::from(2);
+        list.map.insert(unnamed, Arc::new(Box::new(EventScheme)));
+
+        list.remove_unnamed(id);
+        list.remove_unnamed(unnamed);
+        assert!(list.get(id).is_some());
+        assert!(list.get(unnamed).is_none());
+    }
+
+    #[test]
+    fn unresolve_own_name() {
+        let (list, ns, id) = make_list(&[("file", None), ("app", Some("/srv/app"))]);
+        assert_eq!(list.unresolve(ns, id, b"file:/srv/app/etc"), None);
+
+        let (list, ns, id) = make_list(&[("root", None)]);
+        assert_eq!(list.unresolve(ns, id, b"file:/etc"), Some(b"root:/etc".to_vec()));
+    }
+}
@@ -1,16 +1,24 @@
 use alloc::sync::{Arc, Weak};
 use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::cmp;
 use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use spin::{Mutex, Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use event;
 use scheme::{AtomicSchemeId, ATOMIC_SCHEMEID_INIT, SchemeId};
 use sync::WaitCondition;
-use syscall::error::{Error, Result, EAGAIN, EBADF, EINTR, EINVAL, EPIPE, ESPIPE};
-use syscall::flag::{EVENT_READ, F_GETFL, F_SETFL, O_ACCMODE, O_NONBLOCK, MODE_FIFO};
+use syscall::error::{Error, Result, EAGAIN, EBADF, EBUSY, EINTR, EINVAL, EPERM, EPIPE, ESPIPE};
+use syscall::flag::{EVENT_READ, EVENT_WRITE, F_GETFL, F_SETFL, F_GETPIPE_SZ, F_SETPIPE_SZ, O_ACCMODE, O_NONBLOCK, MODE_FIFO, PIPE_BUF};
 use syscall::scheme::Scheme;
 use syscall::data::Stat;
 
+/// Capacity of new pipes
+pub const PIPE_DEFAULT_SIZE: usize = 65536;
+
+/// Largest capacity that can be set with `F_SETPIPE_SZ`
+pub const PIPE_MAX_SIZE: usize = 1048576;
+
 /// Pipes list
 pub static PIPE_SCHEME_ID: AtomicSchemeId = ATOMIC_SCHEMEID_INIT;
 static PIPE_NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
@@ -36,13 +44,35 @@ pub fn pipe(flags: usize) -> (usize, usize) {
     let scheme_id = PIPE_SCHEME_ID.load(Ordering::SeqCst);
     let read_id = PIPE_NEXT_ID.fetch_add(1, Ordering::SeqCst);
     let write_id = PIPE_NEXT_ID.fetch_add(1, Ordering::SeqCst);
-    let read = PipeRead::new(scheme_id, read_id, flags);
+    let read = PipeRead::new(scheme_id, read_id, write_id, flags);
     let write = PipeWrite::new(&read, flags);
     pipes.0.insert(read_id, Arc::new(read));
     pipes.1.insert(write_id, Arc::new(write));
     (read_id, write_id)
 }
 
+/// Get the read side of a pipe
+pub fn pipe_read(id: usize) -> Option<Arc<PipeRead>> {
+    pipes().0.get(&id).cloned()
+}
+
+/// Get the write side of a pipe
+pub fn pipe_write(id: usize) -> Option<Arc<PipeWrite>> {
+    pipes().1.get(&id).cloned()
+}
+
+/// Bytes waiting in all pipes, and bytes of heap allocated for them
+pub fn pipe_usage() -> (usize, usize) {
+    let mut buffered = 0;
+    let mut allocated = 0;
+    for (_id, read) in pipes().0.iter() {
+        let vec = read.vec.lock();
+        buffered += vec.len();
+        allocated += vec.capacity();
+    }
+    (buffered, allocated)
+}
+
 pub struct PipeScheme;
 
 impl PipeScheme {
@@ -94,6 +124,10 @@ impl Scheme for PipeScheme {
             return pipe.fevent(flags);
         }
 
+        if let Some(pipe) = pipes.1.get(&id) {
+            return pipe.fevent(flags);
+        }
+
         Err(Error::new(EBADF))
     }
 
@@ -138,18 +172,24 @@ impl Scheme for PipeScheme {
 pub struct PipeRead {
     scheme_id: SchemeId,
     event_id: usize,
+    write_event_id: usize,
     flags: AtomicUsize,
+    capacity: Arc<AtomicUsize>,
     condition: Arc<WaitCondition>,
+    write_condition: Arc<WaitCondition>,
     vec: Arc<Mutex<VecDeque<u8>>>
 }
 
 impl PipeRead {
-    pub fn new(scheme_id: SchemeId, event_id: usize, flags: usize) -> Self {
+    pub fn new(scheme_id: SchemeId, event_id: usize, write_event_id: usize, flags: usize) -> Self {
         PipeRead {
             scheme_id: scheme_id,
             event_id: event_id,
+            write_event_id: write_event_id,
             flags: AtomicUsize::new(flags),
+            capacity: Arc::new(AtomicUsize::new(PIPE_DEFAULT_SIZE)),
             condition: Arc::new(WaitCondition::new()),
+            write_condition: Arc::new(WaitCondition::new()),
             vec: Arc::new(Mutex::new(VecDeque::new())),
         }
     }
@@ -161,37 +201,99 @@ impl PipeRead {
                 self.flags.store(arg & ! O_ACCMODE, Ordering::SeqCst);
                 Ok(0)
             },
+            F_GETPIPE_SZ => Ok(self.capacity.load(Ordering::SeqCst)),
+            F_SETPIPE_SZ => set_capacity(&self.vec, &self.capacity, arg),
             _ => Err(Error::new(EINVAL))
         }
     }
 
     fn fevent(&self, _flags: usize) -> Result<usize> {
+        // Report data written, or writers closed, before the registration
+        if ! self.vec.lock().is_empty() || Arc::weak_count(&self.vec) == 0 {
+            event::trigger(self.scheme_id, self.event_id, EVENT_READ);
+        }
         Ok(self.event_id)
     }
 
     fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        let nonblock = self.flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK;
+        self.read_with(nonblock, |vec| {
+            let mut i = 0;
+            while i < buf.len() {
+                if let Some(b) = vec.pop_front() {
+                    buf[i] = b;
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+            i
+        })
+    }
+
+    /// Remove up to `len` bytes, for splice
+    pub fn take(&self, len: usize, nonblock: bool) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.read_with(nonblock, |vec| {
+            let count = cmp::min(len, vec.len());
+            data.extend(vec.drain(..count));
+            count
+        })?;
+        Ok(data)
+    }
+
+    /// Copy up to `len` bytes without removing them, for tee
+    pub fn peek(&self, len: usize, nonblock: bool) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.read_with(nonblock, |vec| {
+            data.extend(vec.iter().take(len));
+            0
+        })?;
+        Ok(data)
+    }
+
+    /// Put back bytes that were taken but could not be passed on
+    pub fn untake(&self, data: &[u8]) {
+        {
+            let mut vec = self.vec.lock();
+            for &b in data.iter().rev() {
+                vec.push_front(b);
+            }
+        }
+
+        event::trigger(self.scheme_id, self.event_id, EVENT_READ);
+        self.condition.notify();
+    }
+
+    /// Wait until there is data or end of file, then let `f` consume the buffered bytes and
+    /// return how many it consumed
+    fn read_with<F>(&self, nonblock: bool, f: F) -> Result<usize> where F: FnOnce(&mut VecDeque<u8>) -> usize {
+        let mut f_opt = Some(f);
         loop {
             {
                 let mut vec = self.vec.lock();
 
-                let mut i = 0;
-                while i < buf.len() {
-                    if let Some(b) = vec.pop_front() {
-                        buf[i] = b;
-                        i += 1;
-                    } else {
-                        break;
+                if ! vec.is_empty() {
+                    let f = f_opt.take().expect("PipeRead::read_with: called twice");
+                    let count = f(&mut vec);
+
+                    // Stays readable at end of file, once all writers are gone
+                    if vec.is_empty() && Arc::weak_count(&self.vec) != 0 {
+                        event::clear(self.scheme_id, self.event_id, EVENT_READ);
                     }
-                }
+                    drop(vec);
 
-                if i > 0 {
-                    return Ok(i);
+                    if count > 0 {
+                        event::trigger(self.scheme_id, self.write_event_id, EVENT_WRITE);
+                        self.write_condition.notify();
+                    }
+                    return Ok(count);
                 }
             }
 
             if Arc::weak_count(&self.vec) == 0 {
                 return Ok(0);
-            } else if self.flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
+            } else if nonblock {
                 return Err(Error::new(EAGAIN));
             } else {
                 if ! self.condition.wait() {
@@ -202,12 +304,24 @@ impl PipeRead {
     }
 }
 
-/// Read side of a pipe
+impl Drop for PipeRead {
+    fn drop(&mut self) {
+        // Release the buffer first, so that woken writers fail with EPIPE
+        self.vec = Arc::new(Mutex::new(VecDeque::new()));
+        event::trigger(self.scheme_id, self.write_event_id, EVENT_WRITE);
+        self.write_condition.notify();
+    }
+}
+
+/// Write side of a pipe
 pub struct PipeWrite {
     scheme_id: SchemeId,
     event_id: usize,
+    write_event_id: usize,
     flags: AtomicUsize,
+    capacity: Arc<AtomicUsize>,
     condition: Arc<WaitCondition>,
+    write_condition: Arc<WaitCondition>,
     vec: Option<Weak<Mutex<VecDeque<u8>>>>
 }
 
@@ -216,8 +330,11 @@ impl PipeWrite {
         PipeWrite {
             scheme_id: read.scheme_id,
             event_id: read.event_id,
+            write_event_id: read.write_event_id,
             flags: AtomicUsize::new(flags),
+            capacity: read.capacity.clone(),
             condition: read.condition.clone(),
+            write_condition: read.write_condition.clone(),
             vec: Some(Arc::downgrade(&read.vec)),
         }
     }
@@ -229,32 +346,123 @@ impl PipeWrite {
                 self.flags.store(arg & ! O_ACCMODE, Ordering::SeqCst);
                 Ok(0)
             },
+            F_GETPIPE_SZ => Ok(self.capacity.load(Ordering::SeqCst)),
+            F_SETPIPE_SZ => {
+                let vec_lock = self.vec_lock()?;
+                set_capacity(&vec_lock, &self.capacity, arg)
+            },
             _ => Err(Error::new(EINVAL))
         }
     }
 
-    fn write(&self, buf: &[u8]) -> Result<usize> {
+    fn fevent(&self, _flags: usize) -> Result<usize> {
+        // Report the current state, as writers are only notified when space is freed
+        let vec_lock = self.vec_lock()?;
+        if vec_lock.lock().len() < self.capacity.load(Ordering::SeqCst) {
+            event::trigger(self.scheme_id, self.write_event_id, EVENT_WRITE);
+        }
+        Ok(self.write_event_id)
+    }
+
+    fn vec_lock(&self) -> Result<Arc<Mutex<VecDeque<u8>>>> {
         if let Some(ref vec_weak) = self.vec {
-            if let Some(vec_lock) = vec_weak.upgrade() {
-                {
-                    let mut vec = vec_lock.lock();
+            vec_weak.upgrade().ok_or(Error::new(EPIPE))
+        } else {
+            panic!("PipeWrite dropped before write");
+        }
+    }
 
-                    for &b in buf.iter() {
-                        vec.push_back(b);
-                    }
+    fn write(&self, buf: &[u8]) -> Result<usize> {
+        let nonblock = self.flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK;
+        self.write_inner(buf, nonblock)
+    }
+
+    /// Write `buf`, waiting for space unless `nonblock` is set. Writes of up to `PIPE_BUF`
+    /// bytes are never interleaved with other writes
+    pub fn write_inner(&self, buf: &[u8], nonblock: bool) -> Result<usize> {
+        let mut written = 0;
+        loop {
+            let count = {
+                // The reader must not be kept alive while waiting
+                let vec_lock = match self.vec_lock() {
+                    Ok(vec_lock) => vec_lock,
+                    Err(err) => return if written > 0 { Ok(written) } else { Err(err) }
+                };
+                let mut vec = vec_lock.lock();
+
+                let space = self.capacity.load(Ordering::SeqCst).saturating_sub(vec.len());
+                let remaining = buf.len() - written;
+                let count = if buf.len() <= PIPE_BUF {
+                    if space >= remaining { remaining } else { 0 }
+                } else {
+                    cmp::min(space, remaining)
+                };
+
+                vec.extend(buf[written..written + count].iter());
+                written += count;
+
+                if vec.len() >= self.capacity.load(Ordering::SeqCst) {
+                    event::clear(self.scheme_id, self.write_event_id, EVENT_WRITE);
                 }
 
+                count
+            };
+
+            if count > 0 {
                 event::trigger(self.scheme_id, self.event_id, EVENT_READ);
                 self.condition.notify();
+            }
 
-                Ok(buf.len())
-            } else {
-                Err(Error::new(EPIPE))
+            if written == buf.len() {
+                return Ok(written);
+            } else if nonblock {
+                return if written > 0 { Ok(written) } else { Err(Error::new(EAGAIN)) };
+            } else if ! self.write_condition.wait() {
+                return if written > 0 { Ok(written) } else { Err(Error::new(EINTR)) };
             }
-        } else {
-            panic!("PipeWrite dropped before write");
         }
     }
+
+    /// Wait until there is space, unless `nonblock` is set, and return how much, for splice
+    pub fn wait_space(&self, nonblock: bool) -> Result<usize> {
+        loop {
+            {
+                let vec_lock = self.vec_lock()?;
+                let vec = vec_lock.lock();
+                let space = self.capacity.load(Ordering::SeqCst).saturating_sub(vec.len());
+                if space > 0 {
+                    return Ok(space);
+                }
+            }
+
+            if nonblock {
+                return Err(Error::new(EAGAIN));
+            } else if ! self.write_condition.wait() {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+
+    /// Write all of `buf` even past the capacity, for data that was read after `wait_space`
+    /// and must not be lost
+    pub fn push(&self, buf: &[u8]) -> Result<usize> {
+        {
+            let vec_lock = self.vec_lock()?;
+            let mut vec = vec_lock.lock();
+            vec.extend(buf.iter());
+
+            if vec.len() >= self.capacity.load(Ordering::SeqCst) {
+                event::clear(self.scheme_id, self.write_event_id, EVENT_WRITE);
+            }
+        }
+
+        if ! buf.is_empty() {
+            event::trigger(self.scheme_id, self.event_id, EVENT_READ);
+            self.condition.notify();
+        }
+
+        Ok(buf.len())
+    }
 }
 
 impl Drop for PipeWrite {
@@ -264,3 +472,21 @@ impl Drop for PipeWrite {
         self.condition.notify();
     }
 }
+
+/// Change the capacity of a pipe, rounded up to whole pages. Fails with `EBUSY` if more
+/// is buffered than fits
+fn set_capacity(vec: &Mutex<VecDeque<u8>>, capacity: &AtomicUsize, arg: usize) -> Result<usize> {
+    if arg > PIPE_MAX_SIZE {
+        return Err(Error::new(EPERM));
+    }
+
+    let new_capacity = cmp::max((arg + 4095)/4096 * 4096, PIPE_BUF);
+
+    let vec = vec.lock();
+    if vec.len() > new_capacity {
+        return Err(Error::new(EBUSY));
+    }
+    capacity.store(new_capacity, Ordering::SeqCst);
+
+    Ok(new_capacity)
+}
@@ -0,0 +1,351 @@
+use alloc::sync::{Arc, Weak};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::{cmp, intrinsics};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, RwLock};
+
+use context::{self, Context};
+use context::memory::{self, SharedFrames};
+use event;
+use scheme::{self, FileHandle, SchemeId};
+use scheme::user::{self, Completion, UserInner};
+use sync::WaitCondition;
+use syscall;
+use syscall::data::{RingCqe, RingHeader, RingSqe};
+use syscall::error::*;
+use syscall::flag::{EVENT_READ, O_NONBLOCK, RING_CQ_ENTRIES, RING_CQ_OFFSET, RING_OP_CLOSE, RING_OP_FSYNC,
+                    RING_OP_NOP, RING_OP_OPEN, RING_OP_READ, RING_OP_WRITE, RING_SIZE, RING_SQ_ENTRIES, RING_SQ_OFFSET};
+use syscall::number::{SYS_FSYNC, SYS_READ, SYS_WRITE};
+use syscall::scheme::Scheme;
+use syscall::validate::{validate_slice, validate_slice_mut};
+
+/// Submission and completion queues shared with one process
+pub struct Ring {
+    scheme_id: SchemeId,
+    id: usize,
+    flags: usize,
+    frames: RwLock<Option<SharedFrames>>,
+    /// Notified when a completion is posted
+    condition: WaitCondition,
+    /// Serializes writers of the completion queue
+    cq_lock: Mutex<()>,
+}
+
+impl Ring {
+    fn header(frames: &SharedFrames) -> *mut RingHeader {
+        frames.kernel_address() as *mut RingHeader
+    }
+
+    /// Number of completions that userspace has not consumed
+    fn completions(&self) -> usize {
+        let frames_opt = self.frames.read();
+        if let Some(ref frames) = *frames_opt {
+            let header = Ring::header(frames);
+            unsafe {
+                let head = intrinsics::atomic_load(&(*header).cq_head);
+                let tail = intrinsics::atomic_load(&(*header).cq_tail);
+                tail.wrapping_sub(head)
+            }
+        } else {
+            0
+        }
+    }
+
+    /// Post a completion and wake up waiters
+    fn post(&self, user_data: usize, result: usize) {
+        {
+            let frames_opt = self.frames.read();
+            if let Some(ref frames) = *frames_opt {
+                let _guard = self.cq_lock.lock();
+
+                let header = Ring::header(frames);
+                unsafe {
+                    let head = intrinsics::atomic_load(&(*header).cq_head);
+                    let tail = (*header).cq_tail;
+                    if tail.wrapping_sub(head) >= RING_CQ_ENTRIES {
+                        (*header).cq_overflow += 1;
+                    } else {
+                        let cqe = (frames.kernel_address() + RING_CQ_OFFSET) as *mut RingCqe;
+                        *cqe.offset((tail % RING_CQ_ENTRIES) as isize) = RingCqe {
+                            user_data: user_data,
+                            result: result
+                        };
+                        intrinsics::atomic_store(&mut (*header).cq_tail, tail.wrapping_add(1));
+                    }
+                }
+            }
+        }
+
+        self.condition.notify();
+        event::trigger(self.scheme_id, self.id, EVENT_READ);
+    }
+
+    /// Consume the submissions queued when called, returning the number consumed. The tail is
+    /// read once, so that userspace cannot keep the kernel here
+    fn submit(ring: &Arc<Ring>) -> Result<usize> {
+        let (head, count) = {
+            let frames_opt = ring.frames.read();
+            let frames = frames_opt.as_ref().ok_or(Error::new(EBADF))?;
+
+            let header = Ring::header(frames);
+            unsafe {
+                let head = (*header).sq_head;
+                let tail = intrinsics::atomic_load(&(*header).sq_tail);
+                let count = tail.wrapping_sub(head);
+                if count > RING_SQ_ENTRIES {
+                    return Err(Error::new(EINVAL));
+                }
+                (head, count)
+            }
+        };
+
+        for i in 0..count {
+            let sqe = {
+                let frames_opt = ring.frames.read();
+                let frames = frames_opt.as_ref().ok_or(Error::new(EBADF))?;
+
+                let header = Ring::header(frames);
+                let position = head.wrapping_add(i);
+                unsafe {
+                    let sq = (frames.kernel_address() + RING_SQ_OFFSET) as *const RingSqe;
+                    let sqe = *sq.offset((position % RING_SQ_ENTRIES) as isize);
+                    intrinsics::atomic_store(&mut (*header).sq_head, position.wrapping_add(1));
+                    sqe
+                }
+            };
+
+            if let Err(err) = Ring::submit_one(ring, &sqe) {
+                ring.post(sqe.user_data, Error::mux(Err(err)));
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn submit_one(ring: &Arc<Ring>, sqe: &RingSqe) -> Result<()> {
+        match sqe.opcode {
+            RING_OP_NOP => {
+                ring.post(sqe.user_data, 0);
+            },
+            RING_OP_OPEN => {
+                let path = validate_slice(sqe.addr as *const u8, sqe.len)?;
+                let fd = syscall::open(path, sqe.flags)?;
+                ring.post(sqe.user_data, fd.into());
+            },
+            RING_OP_CLOSE => {
+                let result = syscall::close(FileHandle::from(sqe.fd))?;
+                ring.post(sqe.user_data, result);
+            },
+            RING_OP_READ | RING_OP_WRITE | RING_OP_FSYNC => {
+                let (scheme_id, number) = {
+                    let contexts = context::contexts();
+                    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+                    let context = context_lock.read();
+                    let file = context.get_file(FileHandle::from(sqe.fd)).ok_or(Error::new(EBADF))?;
+                    let description = file.description.read();
+                    (description.scheme, description.number)
+                };
+
+                if let Some(inner) = user::user_scheme(scheme_id) {
+                    // User schemes answer asynchronously, the buffer is copied until then
+                    Ring::submit_user(ring, &inner, number, sqe)?;
+                } else {
+                    // Kernel schemes complete immediately
+                    let scheme = {
+                        let schemes = scheme::schemes();
+                        let scheme = schemes.get(scheme_id).ok_or(Error::new(EBADF))?;
+                        Arc::clone(&scheme)
+                    };
+
+                    let result = match sqe.opcode {
+                        RING_OP_READ => scheme.read(number, validate_slice_mut(sqe.addr as *mut u8, sqe.len)?)?,
+                        RING_OP_WRITE => scheme.write(number, validate_slice(sqe.addr as *const u8, sqe.len)?)?,
+                        _ => scheme.fsync(number)?
+                    };
+                    ring.post(sqe.user_data, result);
+                }
+            },
+            _ => return Err(Error::new(EINVAL))
+        }
+
+        Ok(())
+    }
+
+    /// Send a request to a user scheme. The submitter does not wait for the answer and may
+    /// unmap its buffer meanwhile, so the daemon is given a copy, and data read is copied back
+    /// to the submitter on completion
+    fn submit_user(ring: &Arc<Ring>, inner: &UserInner, number: usize, sqe: &RingSqe) -> Result<()> {
+        let (a, address) = match sqe.opcode {
+            RING_OP_READ => (SYS_READ, inner.capture_mut(validate_slice_mut(sqe.addr as *mut u8, sqe.len)?)?),
+            RING_OP_WRITE => (SYS_WRITE, inner.capture(validate_slice(sqe.addr as *const u8, sqe.len)?)?),
+            _ => (SYS_FSYNC, 0)
+        };
+
+        if let Err(err) = inner.bounce(address) {
+            let _ = inner.release(address);
+            return Err(err);
+        }
+
+        let context_weak = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+            Arc::downgrade(&context_lock)
+        };
+
+        let completion = Box::new(RingCompletion {
+            ring: Arc::clone(ring),
+            user_data: sqe.user_data,
+            address: address,
+            context: context_weak,
+            read: if a == SYS_READ { Some((sqe.addr, sqe.len)) } else { None }
+        });
+
+        let (c, d) = if a == SYS_FSYNC { (0, 0) } else { (address, sqe.len) };
+        if let Err(err) = inner.call_async(a, number, c, d, completion) {
+            let _ = inner.release(address);
+            return Err(err);
+        }
+
+        Ok(())
+    }
+}
+
+/// Answer to a request that a ring sent to a user scheme
+struct RingCompletion {
+    ring: Arc<Ring>,
+    user_data: usize,
+    /// Copy of the buffer granted to the scheme daemon
+    address: usize,
+    /// The submitter
+    context: Weak<RwLock<Context>>,
+    /// Buffer of the submitter to copy the data read to
+    read: Option<(usize, usize)>,
+}
+
+impl Completion for RingCompletion {
+    /// Called in the scheme daemon, where the grant is mapped, unless the daemon is gone
+    fn complete(self: Box<Self>, inner: &UserInner, mut result: usize) {
+        if let (Some((buf, len)), Ok(count)) = (self.read, Error::demux(result)) {
+            let copied = match (self.context.upgrade(), validate_slice(self.address as *const u8, cmp::min(count, len))) {
+                (Some(context_lock), Ok(data)) => memory::copy_to_context(&context_lock.read(), buf, data),
+                _ => 0
+            };
+            result = copied;
+        }
+
+        let _ = inner.release(self.address);
+        self.ring.post(self.user_data, result);
+    }
+}
+
+pub struct RingScheme {
+    scheme_id: SchemeId,
+    next_id: AtomicUsize,
+    handles: RwLock<BTreeMap<usize, Arc<Ring>>>,
+}
+
+impl RingScheme {
+    pub fn new(scheme_id: SchemeId) -> RingScheme {
+        RingScheme {
+            scheme_id: scheme_id,
+            next_id: AtomicUsize::new(0),
+            handles: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    fn get(&self, id: usize) -> Result<Arc<Ring>> {
+        let handles = self.handles.read();
+        let ring = handles.get(&id).ok_or(Error::new(EBADF))?;
+        Ok(Arc::clone(ring))
+    }
+}
+
+impl Scheme for RingScheme {
+    fn open(&self, path: &[u8], flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        if ! path.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(id, Arc::new(Ring {
+            scheme_id: self.scheme_id,
+            id: id,
+            flags: flags,
+            frames: RwLock::new(None),
+            condition: WaitCondition::new(),
+            cq_lock: Mutex::new(()),
+        }));
+
+        Ok(id)
+    }
+
+    /// Wait for completions, returning the number available
+    fn read(&self, id: usize, _buf: &mut [u8]) -> Result<usize> {
+        let ring = self.get(id)?;
+        loop {
+            let completions = ring.completions();
+            if completions > 0 {
+                return Ok(completions);
+            }
+
+            // Userspace consumes completions from shared memory, so readiness is only cleared here
+            event::clear(ring.scheme_id, ring.id, EVENT_READ);
+            if ring.flags & O_NONBLOCK == O_NONBLOCK {
+                return Err(Error::new(EAGAIN));
+            } else if ! ring.condition.wait() {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+
+    /// Consume queued submissions, returning the number consumed
+    fn write(&self, id: usize, _buf: &[u8]) -> Result<usize> {
+        let ring = self.get(id)?;
+        Ring::submit(&ring)
+    }
+
+    fn fevent(&self, id: usize, _flags: usize) -> Result<usize> {
+        let ring = self.get(id)?;
+        // Report completions posted before the registration
+        if ring.completions() > 0 {
+            event::trigger(self.scheme_id, id, EVENT_READ);
+        }
+        Ok(id)
+    }
+
+    fn fmap(&self, id: usize, _offset: usize, size: usize) -> Result<usize> {
+        let ring = self.get(id)?;
+
+        if size != RING_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut frames_opt = ring.frames.write();
+        if frames_opt.is_some() {
+            return Err(Error::new(EEXIST));
+        }
+
+        let frames = SharedFrames::new(RING_SIZE)?;
+        let address = frames.user_address();
+        *frames_opt = Some(frames);
+
+        Ok(address)
+    }
+
+    fn fpath(&self, _id: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        let scheme_path = b"ring:";
+        while i < buf.len() && i < scheme_path.len() {
+            buf[i] = scheme_path[i];
+            i += 1;
+        }
+        Ok(i)
+    }
+
+    /// Requests in flight are detached, each `RingCompletion` keeps the ring and releases its
+    /// capture when the scheme daemon answers
+    fn close(&self, id: usize) -> Result<usize> {
+        self.handles.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
@@ -9,10 +9,11 @@ use spin::{Mutex, RwLock};
 use context;
 use syscall::data::Stat;
 use syscall::error::*;
-use syscall::flag::{O_CREAT, MODE_FILE, MODE_DIR};
+use syscall::flag::{O_CREAT, O_TRUNC, O_WRONLY, MODE_FILE, MODE_DIR};
 use syscall::scheme::Scheme;
 use scheme::{self, SchemeNamespace, SchemeId};
-use scheme::user::{UserInner, UserScheme};
+use scheme::acl::{ACL_CREATE, SchemeAcl};
+use scheme::user::{register_user_scheme, user_scheme, UserInner, UserScheme};
 
 struct FolderInner {
     data: Box<[u8]>,
@@ -34,10 +35,16 @@ impl FolderInner {
     }
 }
 
+struct FileInner {
+    name: Box<[u8]>,
+    /// The access control list of the name when opened, as text
+    acl: FolderInner
+}
+
 #[derive(Clone)]
 enum Handle {
     Scheme(Arc<UserInner>),
-    File(Arc<Box<[u8]>>),
+    File(Arc<FileInner>),
     Folder(Arc<FolderInner>)
 }
 
@@ -60,17 +67,44 @@ impl RootScheme {
 }
 
 impl Scheme for RootScheme {
-    fn open(&self, path: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+    fn open(&self, path: &[u8], flags: usize, uid: u32, gid: u32) -> Result<usize> {
         let path_utf8 = str::from_utf8(path).or(Err(Error::new(ENOENT)))?;
         let path_trimmed = path_utf8.trim_matches('/');
 
         //TODO: Make this follow standards for flags and errors
         if flags & O_CREAT == O_CREAT {
-            if uid == 0 {
-                let context = {
+            // Besides root, those the access control list of the name lets create it
+            let allowed = uid == 0 || {
+                let schemes = scheme::schemes();
+                schemes.acl(self.scheme_ns, path_trimmed.as_bytes()).map_or(false, |acl| acl.check(uid, gid, ACL_CREATE).is_ok())
+            };
+
+            if allowed {
+                let (context, pid) = {
                     let contexts = context::contexts();
-                    let context = contexts.current().ok_or(Error::new(ESRCH))?;
-                    Arc::downgrade(&context)
+                    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+                    let pid = context_lock.read().id;
+                    (Arc::downgrade(&context_lock), pid)
+                };
+
+                // With O_TRUNC, a new daemon takes the name over from a running one, if it is
+                // root or the same daemon, as with unlink
+                let old_id_opt = if flags & O_TRUNC == O_TRUNC {
+                    let old_id_opt = {
+                        let schemes = scheme::schemes();
+                        schemes.get_name(self.scheme_ns, path_trimmed.as_bytes()).map(|(old_id, _scheme)| old_id)
+                    };
+                    match old_id_opt.and_then(|old_id| user_scheme(old_id).map(|old_inner| (old_id, old_inner))) {
+                        Some((old_id, old_inner)) => {
+                            if uid != 0 && old_inner.owner() != Some(pid) {
+                                return Err(Error::new(EACCES));
+                            }
+                            Some(old_id)
+                        },
+                        None => None
+                    }
+                } else {
+                    None
                 };
 
                 let id = self.next_id.fetch_add(1, Ordering::SeqCst);
@@ -79,10 +113,29 @@ impl Scheme for RootScheme {
                     let path_box = path_trimmed.as_bytes().to_vec().into_boxed_slice();
                     let mut schemes = scheme::schemes_mut();
                     let inner = Arc::new(UserInner::new(self.scheme_id, id, path_box.clone(), flags, context));
-                    schemes.insert(self.scheme_ns, path_box, |scheme_id| {
-                        inner.scheme_id.store(scheme_id, Ordering::SeqCst);
-                        Arc::new(Box::new(UserScheme::new(Arc::downgrade(&inner))))
-                    })?;
+
+                    // The name may have changed hands since it was checked
+                    let handover = match old_id_opt {
+                        Some(old_id) => match schemes.get_name(self.scheme_ns, &path_box) {
+                            Some((current_id, _)) if current_id == old_id => true,
+                            _ => return Err(Error::new(EAGAIN))
+                        },
+                        None => false
+                    };
+
+                    let scheme_id = {
+                        let scheme_fn = |scheme_id: SchemeId| -> Arc<Box<Scheme + Send + Sync>> {
+                            inner.scheme_id.store(scheme_id, Ordering::SeqCst);
+                            Arc::new(Box::new(UserScheme::new(Arc::downgrade(&inner))))
+                        };
+
+                        if handover {
+                            schemes.replace(self.scheme_ns, path_box, &scheme_fn)?
+                        } else {
+                            schemes.insert(self.scheme_ns, path_box, &scheme_fn)?
+                        }
+                    };
+                    register_user_scheme(scheme_id, Arc::downgrade(&inner));
                     inner
                 };
 
@@ -118,9 +171,23 @@ impl Scheme for RootScheme {
             self.handles.write().insert(id, Handle::Folder(inner));
             Ok(id)
         } else {
-            let inner = Arc::new(
-                path_trimmed.as_bytes().to_vec().into_boxed_slice()
-            );
+            // Writing sets the access control list of the name
+            if flags & O_WRONLY == O_WRONLY && uid != 0 {
+                return Err(Error::new(EACCES));
+            }
+
+            let acl = {
+                let schemes = scheme::schemes();
+                schemes.acl(self.scheme_ns, path_trimmed.as_bytes()).map_or(Vec::new(), |acl| acl.to_bytes())
+            };
+
+            let inner = Arc::new(FileInner {
+                name: path_trimmed.as_bytes().to_vec().into_boxed_slice(),
+                acl: FolderInner {
+                    data: acl.into_boxed_slice(),
+                    pos: Mutex::new(0)
+                }
+            });
 
             let id = self.next_id.fetch_add(1, Ordering::SeqCst);
             self.handles.write().insert(id, Handle::File(inner));
@@ -128,6 +195,35 @@ impl Scheme for RootScheme {
         }
     }
 
+    /// Unregister a scheme. Descriptors that are already open keep using it until its
+    /// daemon closes the scheme handle
+    fn unlink(&self, path: &[u8], uid: u32, _gid: u32) -> Result<usize> {
+        let path_utf8 = str::from_utf8(path).or(Err(Error::new(ENOENT)))?;
+        let path_trimmed = path_utf8.trim_matches('/');
+
+        let scheme_id = {
+            let schemes = scheme::schemes();
+            let (scheme_id, _scheme) = schemes.get_name(self.scheme_ns, path_trimmed.as_bytes()).ok_or(Error::new(ENOENT))?;
+            scheme_id
+        };
+
+        // Only schemes provided by daemons can be removed, by root or by the daemon itself
+        let inner = user_scheme(scheme_id).ok_or(Error::new(EPERM))?;
+        let pid = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+            let context = context_lock.read();
+            context.id
+        };
+        if uid != 0 && inner.owner() != Some(pid) {
+            return Err(Error::new(EACCES));
+        }
+
+        scheme::schemes_mut().remove_names(scheme_id);
+
+        Ok(0)
+    }
+
     fn read(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
         let handle = {
             let handles = self.handles.read();
@@ -139,8 +235,8 @@ impl Scheme for RootScheme {
             Handle::Scheme(inner) => {
                 inner.read(buf)
             },
-            Handle::File(_) => {
-                Err(Error::new(EBADF))
+            Handle::File(inner) => {
+                inner.acl.read(buf)
             },
             Handle::Folder(inner) => {
                 inner.read(buf)
@@ -159,8 +255,11 @@ impl Scheme for RootScheme {
             Handle::Scheme(inner) => {
                 inner.write(buf)
             },
-            Handle::File(_) => {
-                Err(Error::new(EBADF))
+            Handle::File(inner) => {
+                // Each write replaces the whole list, an empty one removes it
+                let acl = SchemeAcl::parse(buf)?;
+                scheme::schemes_mut().set_acl(self.scheme_ns, inner.name.clone(), acl);
+                Ok(buf.len())
             },
             Handle::Folder(_) => {
                 Err(Error::new(EBADF))
@@ -188,6 +287,46 @@ impl Scheme for RootScheme {
         }
     }
 
+    fn fcntl(&self, file: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let handle = {
+            let handles = self.handles.read();
+            let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+            handle.clone()
+        };
+
+        match handle {
+            Handle::Scheme(inner) => {
+                inner.fcntl(cmd, arg)
+            },
+            Handle::File(_) => {
+                Ok(0)
+            },
+            Handle::Folder(_) => {
+                Ok(0)
+            }
+        }
+    }
+
+    fn fmap(&self, file: usize, _offset: usize, size: usize) -> Result<usize> {
+        let handle = {
+            let handles = self.handles.read();
+            let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+            handle.clone()
+        };
+
+        match handle {
+            Handle::Scheme(inner) => {
+                inner.register_buffer(size)
+            },
+            Handle::File(_) => {
+                Err(Error::new(EBADF))
+            },
+            Handle::Folder(_) => {
+                Err(Error::new(EBADF))
+            }
+        }
+    }
+
     fn fpath(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
         let handle = {
             let handles = self.handles.read();
@@ -213,8 +352,8 @@ impl Scheme for RootScheme {
             },
             Handle::File(inner) => {
                 let mut j = 0;
-                while i < buf.len() && j < inner.len() {
-                    buf[i] = inner[j];
+                while i < buf.len() && j < inner.name.len() {
+                    buf[i] = inner.name[j];
                     i += 1;
                     j += 1;
                 }
@@ -277,7 +416,16 @@ impl Scheme for RootScheme {
     }
 
     fn close(&self, file: usize) -> Result<usize> {
-        self.handles.write().remove(&file);
+        let handle = self.handles.write().remove(&file).ok_or(Error::new(EBADF))?;
+
+        // The daemon is gone, let a replacement register the name and fail its clients
+        if let Handle::Scheme(inner) = handle {
+            let scheme_id = inner.scheme_id.load(Ordering::SeqCst);
+            scheme::schemes_mut().remove_names(scheme_id);
+            inner.disconnect();
+            scheme::schemes_mut().remove(scheme_id);
+        }
+
         Ok(0)
     }
 }
@@ -0,0 +1,225 @@
+use alloc::sync::Arc;
+use alloc::collections::{BTreeMap, VecDeque};
+use core::{mem, slice, str};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, RwLock};
+
+use context::{self, Context};
+use event;
+use scheme::SchemeId;
+use sync::WaitCondition;
+use syscall::data::SigInfo;
+use syscall::error::*;
+use syscall::flag::{EVENT_READ, F_GETFL, F_SETFL, O_ACCMODE, O_NONBLOCK, SIGKILL, SIGSTOP};
+use syscall::scheme::Scheme;
+
+/// Signals queued for reading instead of running handlers
+pub struct SignalFd {
+    scheme_id: SchemeId,
+    id: usize,
+    flags: AtomicUsize,
+    /// Bit `n` is set to receive signal `n`
+    mask: u64,
+    queue: Mutex<VecDeque<SigInfo>>,
+    condition: WaitCondition,
+}
+
+impl SignalFd {
+    pub fn accepts(&self, sig: usize) -> bool {
+        sig < 64 && self.mask & (1 << sig) != 0
+    }
+
+    /// Queue a signal, must not be called while holding the lock of a context. Like the
+    /// pending signals of a context, a signal that is already queued is not queued again, so
+    /// the queue holds at most one record per signal
+    pub fn send(&self, info: SigInfo) {
+        {
+            let mut queue = self.queue.lock();
+            if queue.iter().any(|queued| queued.si_signo == info.si_signo) {
+                return;
+            }
+            queue.push_back(info);
+        }
+        event::trigger(self.scheme_id, self.id, EVENT_READ);
+        self.condition.notify();
+    }
+
+    fn read(&self, buf: &mut [SigInfo]) -> Result<usize> {
+        loop {
+            {
+                let mut queue = self.queue.lock();
+
+                let mut i = 0;
+                while i < buf.len() {
+                    if let Some(info) = queue.pop_front() {
+                        buf[i] = info;
+                        i += 1;
+                    } else {
+                        break;
+                    }
+                }
+
+                if i > 0 {
+                    if queue.is_empty() {
+                        event::clear(self.scheme_id, self.id, EVENT_READ);
+                    }
+                    return Ok(i);
+                }
+            }
+
+            if self.flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
+                return Err(Error::new(EAGAIN));
+            } else if ! self.condition.wait() {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+}
+
+/// Find the signal descriptor of `context` that receives `sig`, forgetting closed ones
+pub fn route(context: &mut Context, sig: usize) -> Option<Arc<SignalFd>> {
+    let mut found = None;
+    context.signalfds.retain(|weak| {
+        match weak.upgrade() {
+            Some(signalfd) => {
+                if found.is_none() && signalfd.accepts(sig) {
+                    found = Some(signalfd);
+                }
+                true
+            },
+            None => false
+        }
+    });
+    found
+}
+
+/// `signalfd:` - receive signals as `SigInfo` records instead of running handlers
+///
+/// The path lists the signal numbers to receive, separated by commas, such as
+/// `signalfd:15,17`. They are received by the context that opened the descriptor
+pub struct SignalFdScheme {
+    scheme_id: SchemeId,
+    next_id: AtomicUsize,
+    handles: RwLock<BTreeMap<usize, Arc<SignalFd>>>,
+}
+
+impl SignalFdScheme {
+    pub fn new(scheme_id: SchemeId) -> SignalFdScheme {
+        SignalFdScheme {
+            scheme_id: scheme_id,
+            next_id: AtomicUsize::new(0),
+            handles: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    fn get(&self, id: usize) -> Result<Arc<SignalFd>> {
+        let handles = self.handles.read();
+        handles.get(&id).cloned().ok_or(Error::new(EBADF))
+    }
+}
+
+impl Scheme for SignalFdScheme {
+    fn open(&self, path: &[u8], flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        let path_str = str::from_utf8(path).or(Err(Error::new(ENOENT)))?;
+
+        let mut mask = 0u64;
+        for part in path_str.trim_matches('/').split(',') {
+            let sig = part.parse::<usize>().or(Err(Error::new(EINVAL)))?;
+            // These can never be caught
+            if sig == 0 || sig >= 64 || sig == SIGKILL || sig == SIGSTOP {
+                return Err(Error::new(EINVAL));
+            }
+            mask |= 1 << sig;
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let signalfd = Arc::new(SignalFd {
+            scheme_id: self.scheme_id,
+            id: id,
+            flags: AtomicUsize::new(flags & ! O_ACCMODE),
+            mask: mask,
+            queue: Mutex::new(VecDeque::new()),
+            condition: WaitCondition::new(),
+        });
+
+        {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+            let mut context = context_lock.write();
+
+            // Signals that are already pending are received too
+            let mut pending = VecDeque::new();
+            while let Some(sig) = context.pending.pop_front() {
+                if signalfd.accepts(sig as usize) {
+                    signalfd.queue.lock().push_back(SigInfo {
+                        si_signo: sig as usize,
+                        ..SigInfo::default()
+                    });
+                } else {
+                    pending.push_back(sig);
+                }
+            }
+            context.pending = pending;
+
+            context.signalfds.push(Arc::downgrade(&signalfd));
+        }
+
+        self.handles.write().insert(id, signalfd);
+
+        Ok(id)
+    }
+
+    /// Read pending signals as `SigInfo` records
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let signalfd = self.get(id)?;
+
+        let info_buf = unsafe { slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut SigInfo, buf.len()/mem::size_of::<SigInfo>()) };
+        if info_buf.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        Ok(signalfd.read(info_buf)? * mem::size_of::<SigInfo>())
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let signalfd = self.get(id)?;
+
+        match cmd {
+            F_GETFL => Ok(signalfd.flags.load(Ordering::SeqCst)),
+            F_SETFL => {
+                signalfd.flags.store(arg & ! O_ACCMODE, Ordering::SeqCst);
+                Ok(0)
+            },
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+
+    fn fevent(&self, id: usize, _flags: usize) -> Result<usize> {
+        let signalfd = self.get(id)?;
+
+        // Report signals that were queued before registering
+        if ! signalfd.queue.lock().is_empty() {
+            event::trigger(self.scheme_id, id, EVENT_READ);
+        }
+
+        Ok(id)
+    }
+
+    fn fpath(&self, _id: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        let scheme_path = b"signalfd:";
+        while i < buf.len() && i < scheme_path.len() {
+            buf[i] = scheme_path[i];
+            i += 1;
+        }
+        Ok(i)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        self.get(id).and(Ok(0))
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        self.handles.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
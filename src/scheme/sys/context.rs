@@ -1,9 +1,100 @@
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::fmt::Write;
 use core::str;
 
-use context;
-use syscall::error::Result;
+use context::{self, Context, ContextId};
+use syscall::error::{Error, ESRCH, Result};
+use super::json;
+
+/// Bytes of memory used by a context
+fn memory(context: &Context) -> usize {
+    let mut memory = 0;
+    if let Some(ref kfx) = context.kfx {
+        memory += kfx.len();
+    }
+    if let Some(ref kstack) = context.kstack {
+        memory += kstack.len();
+    }
+    for shared_mem in context.image.iter() {
+        shared_mem.with(|mem| {
+            memory += mem.size();
+        });
+    }
+    if let Some(ref heap) = context.heap {
+        heap.with(|heap| {
+            memory += heap.size();
+        });
+    }
+    if let Some(ref stack) = context.stack {
+        memory += stack.size();
+    }
+    if let Some(ref sigstack) = context.sigstack {
+        memory += sigstack.size();
+    }
+    memory
+}
+
+/// Write a context as a JSON object
+fn write_json(string: &mut String, context: &Context) {
+    let status = match context.status {
+        context::Status::Runnable => "runnable",
+        context::Status::Blocked => if context.wake.is_some() { "sleeping" } else { "blocked" },
+        context::Status::Stopped(_sig) => "stopped",
+        context::Status::Exited(_status) => "exited"
+    };
+
+    let _ = write!(string, "{{\"pid\":{},\"pgid\":{},\"ppid\":{},\"ruid\":{},\"rgid\":{},\"rns\":{},\"euid\":{},\"egid\":{},\"ens\":{},\"status\":\"{}\",\"running\":{},\"kernel\":{},",
+                   context.id.into(),
+                   context.pgid.into(),
+                   context.ppid.into(),
+                   context.ruid,
+                   context.rgid,
+                   context.rns.into(),
+                   context.euid,
+                   context.egid,
+                   context.ens.into(),
+                   status,
+                   context.running,
+                   context.stack.is_none());
+    match context.cpu_id {
+        Some(cpu_id) => { let _ = write!(string, "\"cpu\":{},", cpu_id); },
+        None => string.push_str("\"cpu\":null,")
+    }
+    let _ = write!(string, "\"memory\":{},\"name\":", memory(context));
+    json::bytes(string, &context.name.lock());
+    string.push('}');
+}
+
+/// All contexts as a JSON array
+pub fn resource_json() -> Result<Vec<u8>> {
+    let mut string = String::from("[");
+    {
+        let contexts = context::contexts();
+        for (i, (_id, context_lock)) in contexts.iter().enumerate() {
+            if i > 0 {
+                string.push(',');
+            }
+            write_json(&mut string, &context_lock.read());
+        }
+    }
+    string.push_str("]\n");
+
+    Ok(string.into_bytes())
+}
+
+/// One context as a JSON object
+pub fn resource_pid(pid: ContextId) -> Result<Vec<u8>> {
+    let mut string = String::new();
+    {
+        let contexts = context::contexts();
+        let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+        write_json(&mut string, &context_lock.read());
+    }
+    string.push('\n');
+
+    Ok(string.into_bytes())
+}
 
 pub fn resource() -> Result<Vec<u8>> {
     let mut string = format!("{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<8}{}\n",
@@ -57,29 +148,7 @@ pub fn resource() -> Result<Vec<u8>> {
                 format!("?")
             };
 
-            let mut memory = 0;
-            if let Some(ref kfx) = context.kstack {
-                memory += kfx.len();
-            }
-            if let Some(ref kstack) = context.kstack {
-                memory += kstack.len();
-            }
-            for shared_mem in context.image.iter() {
-                shared_mem.with(|mem| {
-                    memory += mem.size();
-                });
-            }
-            if let Some(ref heap) = context.heap {
-                heap.with(|heap| {
-                    memory += heap.size();
-                });
-            }
-            if let Some(ref stack) = context.stack {
-                memory += stack.size();
-            }
-            if let Some(ref sigstack) = context.sigstack {
-                memory += sigstack.size();
-            }
+            let memory = memory(&context);
 
             let memory_string = if memory >= 1024 * 1024 * 1024 {
                 format!("{} GB", memory / 1024 / 1024 / 1024)
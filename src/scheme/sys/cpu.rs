@@ -1,7 +1,10 @@
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::fmt::Write;
 
 use device::cpu::cpu_info;
 use syscall::error::{Error, EIO, Result};
+use super::json;
 
 pub fn resource() -> Result<Vec<u8>> {
     let mut string = format!("CPUs: {}\n", ::cpu_count());
@@ -11,3 +14,47 @@ pub fn resource() -> Result<Vec<u8>> {
         Err(_) => Err(Error::new(EIO))
     }
 }
+
+/// The CPU information as a JSON object, with frequencies in hertz
+pub fn resource_json() -> Result<Vec<u8>> {
+    let mut info = String::new();
+    if cpu_info(&mut info).is_err() {
+        return Err(Error::new(EIO));
+    }
+
+    let mut string = format!("{{\"cpus\":{}", ::cpu_count());
+    for line in info.lines() {
+        let mut parts = line.splitn(2, ':');
+        let key = parts.next().unwrap_or("");
+        let value = parts.next().unwrap_or("").trim();
+        match key {
+            "Vendor" | "Model" => {
+                let _ = write!(string, ",\"{}\":", if key == "Vendor" { "vendor" } else { "model" });
+                json::string(&mut string, value);
+            },
+            "CPU Base MHz" | "CPU Max MHz" | "Bus MHz" => {
+                let field = match key {
+                    "CPU Base MHz" => "base_frequency_hz",
+                    "CPU Max MHz" => "max_frequency_hz",
+                    _ => "bus_frequency_hz"
+                };
+                let mhz = value.parse::<u64>().unwrap_or(0);
+                let _ = write!(string, ",\"{}\":{}", field, mhz * 1_000_000);
+            },
+            "Features" => {
+                string.push_str(",\"features\":[");
+                for (i, feature) in value.split_whitespace().enumerate() {
+                    if i > 0 {
+                        string.push(',');
+                    }
+                    json::string(&mut string, feature);
+                }
+                string.push(']');
+            },
+            _ => ()
+        }
+    }
+    string.push_str("}\n");
+
+    Ok(string.into_bytes())
+}
@@ -3,22 +3,83 @@ use alloc::vec::Vec;
 use core::fmt::Write;
 use core::str;
 
-use context;
+use context::{self, ContextId};
+use context::file::FileDescriptor;
 use scheme;
-use syscall::error::Result;
+use syscall::error::{Error, ESRCH, Result};
+use super::json;
+
+type Row = (ContextId, Vec<u8>, Vec<Option<FileDescriptor>>);
+
+fn rows(pid: Option<ContextId>) -> Result<Vec<Row>> {
+    let mut rows = Vec::new();
+    let contexts = context::contexts();
+    if let Some(pid) = pid {
+        let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        rows.push((pid, context.name.lock().clone(), context.files.lock().clone()));
+    } else {
+        for (id, context_lock) in contexts.iter() {
+            let context = context_lock.read();
+            rows.push((*id, context.name.lock().clone(), context.files.lock().clone()));
+        }
+    }
+    Ok(rows)
+}
+
+/// Write a row as a JSON object, with the path of each file or the error getting it
+fn write_json(string: &mut String, row: &Row) {
+    let _ = write!(string, "{{\"pid\":{},\"name\":", row.0.into());
+    json::bytes(string, &row.1);
+    string.push_str(",\"files\":[");
+
+    let mut first = true;
+    for (fd, f) in row.2.iter().enumerate() {
+        let file = match *f {
+            None => continue,
+            Some(ref file) => file.clone()
+        };
+
+        if ! first {
+            string.push(',');
+        }
+        first = false;
+
+        let description = file.description.read();
+        let _ = write!(string, "{{\"fd\":{},\"scheme\":{},\"number\":{},\"flags\":{},",
+                       fd, description.scheme.into(), description.number, description.flags);
+
+        let scheme_opt = {
+            let schemes = scheme::schemes();
+            schemes.get(description.scheme).map(|scheme| scheme.clone())
+        };
+
+        match scheme_opt {
+            Some(scheme) => {
+                let mut fpath = [0; 4096];
+                match scheme.fpath(description.number, &mut fpath) {
+                    Ok(path_len) => {
+                        string.push_str("\"path\":");
+                        json::bytes(string, &fpath[..path_len]);
+                    },
+                    Err(err) => {
+                        string.push_str("\"error\":");
+                        json::string(string, &format!("{}", err));
+                    }
+                }
+            },
+            None => string.push_str("\"error\":\"no scheme\"")
+        }
+        string.push('}');
+    }
+    string.push_str("]}");
+}
 
 pub fn resource() -> Result<Vec<u8>> {
     let mut string = String::new();
 
     {
-        let mut rows = Vec::new();
-        {
-            let contexts = context::contexts();
-            for (id, context_lock) in contexts.iter() {
-                let context = context_lock.read();
-                rows.push((*id, context.name.lock().clone(), context.files.lock().clone()));
-            }
-        }
+        let rows = rows(None)?;
 
         for row in rows.iter() {
             let id: usize = row.0.into();
@@ -60,3 +121,28 @@ pub fn resource() -> Result<Vec<u8>> {
 
     Ok(string.into_bytes())
 }
+
+/// The open files of all contexts as a JSON array
+pub fn resource_json() -> Result<Vec<u8>> {
+    let mut string = String::from("[");
+    for (i, row) in rows(None)?.iter().enumerate() {
+        if i > 0 {
+            string.push(',');
+        }
+        write_json(&mut string, row);
+    }
+    string.push_str("]\n");
+
+    Ok(string.into_bytes())
+}
+
+/// The open files of one context as a JSON object
+pub fn resource_pid(pid: ContextId) -> Result<Vec<u8>> {
+    let mut string = String::new();
+    for row in rows(Some(pid))?.iter() {
+        write_json(&mut string, row);
+    }
+    string.push('\n');
+
+    Ok(string.into_bytes())
+}
@@ -0,0 +1,30 @@
+//! Helpers for the structured `.json` variants of the files of `sys:`
+//!
+//! Field names are stable, and numbers are in base units, such as bytes and hertz
+
+use alloc::string::String;
+use core::fmt::Write;
+
+/// Write `value` as a JSON string
+pub fn string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            },
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+}
+
+/// Write bytes as a JSON string, replacing invalid UTF-8
+pub fn bytes(out: &mut String, value: &[u8]) {
+    string(out, &String::from_utf8_lossy(value));
+}
@@ -0,0 +1,185 @@
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use allocator::Allocator;
+use context;
+use memory::{self, PAGE_SIZE};
+use scheme::pipe::pipe_usage;
+use scheme::user::user_buffer_usage;
+use syscall::error::Result;
+
+/// Memory usage, in bytes unless named as frames
+struct MemInfo {
+    total_frames: usize,
+    used_frames: usize,
+    free_frames: usize,
+    /// Free frames in the free list of the recycle allocator
+    recycled_frames: usize,
+    page_table_frames: usize,
+    heap_size: usize,
+    heap_used: usize,
+    /// Slab size and allocations in use
+    slabs: Vec<(usize, usize)>,
+    /// Allocations in use that are larger than every slab
+    large_allocations: Option<usize>,
+    kernel_stacks: usize,
+    images: usize,
+    heaps: usize,
+    stacks: usize,
+    grants: usize,
+    pipes_buffered: usize,
+    pipes_allocated: usize,
+    scheme_buffers: usize,
+}
+
+fn meminfo() -> MemInfo {
+    let used_frames = memory::used_frames();
+    let free_frames = memory::free_frames();
+
+    let mut kernel_stacks = 0;
+    let mut images = 0;
+    let mut heaps = 0;
+    let mut stacks = 0;
+    let mut grants = 0;
+    {
+        // Memory shared between contexts is counted once
+        let mut seen_memory = BTreeSet::new();
+        let mut seen_grants = BTreeSet::new();
+
+        let contexts = context::contexts();
+        for (_id, context_lock) in contexts.iter() {
+            let context = context_lock.read();
+            if let Some(ref kfx) = context.kfx {
+                kernel_stacks += kfx.len();
+            }
+            if let Some(ref kstack) = context.kstack {
+                kernel_stacks += kstack.len();
+            }
+            for shared_mem in context.image.iter() {
+                shared_mem.with(|mem| {
+                    if seen_memory.insert(mem as *const _ as usize) {
+                        images += mem.size();
+                    }
+                });
+            }
+            if let Some(ref heap) = context.heap {
+                heap.with(|heap| {
+                    if seen_memory.insert(heap as *const _ as usize) {
+                        heaps += heap.size();
+                    }
+                });
+            }
+            if let Some(ref stack) = context.stack {
+                stacks += stack.size();
+            }
+            if let Some(ref sigstack) = context.sigstack {
+                stacks += sigstack.size();
+            }
+            if seen_grants.insert(&*context.grants as *const _ as usize) {
+                for grant in context.grants.lock().iter() {
+                    grants += grant.size();
+                }
+            }
+        }
+    }
+
+    let (pipes_buffered, pipes_allocated) = pipe_usage();
+
+    MemInfo {
+        total_frames: used_frames + free_frames,
+        used_frames: used_frames,
+        free_frames: free_frames,
+        recycled_frames: memory::recycled_frames(),
+        page_table_frames: memory::page_table_frames(),
+        heap_size: Allocator::size(),
+        heap_used: Allocator::used(),
+        slabs: Allocator::size_classes(),
+        large_allocations: Allocator::large_allocations(),
+        kernel_stacks: kernel_stacks,
+        images: images,
+        heaps: heaps,
+        stacks: stacks,
+        grants: grants,
+        pipes_buffered: pipes_buffered,
+        pipes_allocated: pipes_allocated,
+        scheme_buffers: user_buffer_usage(),
+    }
+}
+
+pub fn resource() -> Result<Vec<u8>> {
+    let info = meminfo();
+
+    let mut string = String::new();
+    {
+        let mut line = |name: &str, bytes: usize| {
+            let _ = writeln!(string, "{:<20}{:>12} KB", name, bytes / 1024);
+        };
+        line("Total:", info.total_frames * PAGE_SIZE);
+        line("Used:", info.used_frames * PAGE_SIZE);
+        line("Free:", info.free_frames * PAGE_SIZE);
+        line("FreeRecycled:", info.recycled_frames * PAGE_SIZE);
+        line("FreeUnallocated:", info.free_frames.saturating_sub(info.recycled_frames) * PAGE_SIZE);
+        line("PageTables:", info.page_table_frames * PAGE_SIZE);
+        line("KernelHeap:", info.heap_size);
+        line("KernelHeapUsed:", info.heap_used);
+        line("KernelHeapFree:", info.heap_size.saturating_sub(info.heap_used));
+        line("KernelStacks:", info.kernel_stacks);
+        line("Images:", info.images);
+        line("Heaps:", info.heaps);
+        line("Stacks:", info.stacks);
+        line("Grants:", info.grants);
+        line("Pipes:", info.pipes_allocated);
+        line("PipesBuffered:", info.pipes_buffered);
+        line("SchemeBuffers:", info.scheme_buffers);
+    }
+    for &(size, used) in info.slabs.iter() {
+        let _ = writeln!(string, "{:<20}{:>12} used", format!("Slab{}:", size), used);
+    }
+    if let Some(used) = info.large_allocations {
+        let _ = writeln!(string, "{:<20}{:>12} used", "SlabLarge:", used);
+    }
+
+    Ok(string.into_bytes())
+}
+
+/// Memory usage as a JSON object, in bytes
+pub fn resource_json() -> Result<Vec<u8>> {
+    let info = meminfo();
+
+    let mut string = String::new();
+    let _ = write!(string, "{{\"total\":{},\"used\":{},\"free\":{},\"free_recycled\":{},\"free_unallocated\":{},\"page_tables\":{},",
+                   info.total_frames * PAGE_SIZE,
+                   info.used_frames * PAGE_SIZE,
+                   info.free_frames * PAGE_SIZE,
+                   info.recycled_frames * PAGE_SIZE,
+                   info.free_frames.saturating_sub(info.recycled_frames) * PAGE_SIZE,
+                   info.page_table_frames * PAGE_SIZE);
+    let _ = write!(string, "\"kernel_heap\":{},\"kernel_heap_used\":{},\"kernel_heap_free\":{},\"kernel_stacks\":{},",
+                   info.heap_size,
+                   info.heap_used,
+                   info.heap_size.saturating_sub(info.heap_used),
+                   info.kernel_stacks);
+    let _ = write!(string, "\"images\":{},\"heaps\":{},\"stacks\":{},\"grants\":{},\"pipes\":{},\"pipes_buffered\":{},\"scheme_buffers\":{},\"slabs\":[",
+                   info.images,
+                   info.heaps,
+                   info.stacks,
+                   info.grants,
+                   info.pipes_allocated,
+                   info.pipes_buffered,
+                   info.scheme_buffers);
+    for (i, &(size, used)) in info.slabs.iter().enumerate() {
+        if i > 0 {
+            string.push(',');
+        }
+        let _ = write!(string, "{{\"size\":{},\"used\":{}}}", size, used);
+    }
+    string.push(']');
+    if let Some(used) = info.large_allocations {
+        let _ = write!(string, ",\"slab_large\":{}", used);
+    }
+    string.push_str("}\n");
+
+    Ok(string.into_bytes())
+}
@@ -6,7 +6,8 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::RwLock;
 
 use syscall::data::Stat;
-use syscall::error::{Error, EBADF, EINVAL, ENOENT, Result};
+use context::ContextId;
+use syscall::error::{Error, EBADF, EINVAL, ENOENT, ESRCH, Result};
 use syscall::flag::{MODE_DIR, MODE_FILE, SEEK_CUR, SEEK_END, SEEK_SET};
 use syscall::scheme::Scheme;
 
@@ -14,13 +15,15 @@ mod context;
 mod cpu;
 mod exe;
 mod iostat;
+mod json;
+mod meminfo;
 mod scheme;
 mod scheme_num;
 mod syscall;
 mod uname;
 
 struct Handle {
-    path: &'static [u8],
+    path: Box<[u8]>,
     data: Vec<u8>,
     mode: u16,
     seek: usize
@@ -28,10 +31,17 @@ struct Handle {
 
 type SysFn = Fn() -> Result<Vec<u8>> + Send + Sync;
 
+type PidFn = Fn(ContextId) -> Result<Vec<u8>> + Send + Sync;
+
 /// System information scheme
+///
+/// Files ending in `.json` are structured variants of the text files, and the
+/// names in `pids` also have a JSON file for each context, such as `context/1`,
+/// listed by the directory of the same name, such as `context/`
 pub struct SysScheme {
     next_id: AtomicUsize,
     files: BTreeMap<&'static [u8], Box<SysFn>>,
+    pids: BTreeMap<&'static [u8], Box<PidFn>>,
     handles: RwLock<BTreeMap<usize, Handle>>
 }
 
@@ -43,14 +53,30 @@ impl SysScheme {
         files.insert(b"cpu", Box::new(move || cpu::resource()));
         files.insert(b"exe", Box::new(move || exe::resource()));
         files.insert(b"iostat", Box::new(move || iostat::resource()));
+        files.insert(b"meminfo", Box::new(move || meminfo::resource()));
         files.insert(b"scheme", Box::new(move || scheme::resource()));
         files.insert(b"scheme_num", Box::new(move || scheme_num::resource()));
         files.insert(b"syscall", Box::new(move || syscall::resource()));
         files.insert(b"uname", Box::new(move || uname::resource()));
 
+        files.insert(b"context.json", Box::new(move || context::resource_json()));
+        files.insert(b"cpu.json", Box::new(move || cpu::resource_json()));
+        files.insert(b"iostat.json", Box::new(move || iostat::resource_json()));
+        files.insert(b"meminfo.json", Box::new(move || meminfo::resource_json()));
+        files.insert(b"scheme.json", Box::new(move || scheme::resource_json()));
+        files.insert(b"syscall.json", Box::new(move || syscall::resource_json()));
+        files.insert(b"uname.json", Box::new(move || uname::resource_json()));
+
+        let mut pids: BTreeMap<&'static [u8], Box<PidFn>> = BTreeMap::new();
+
+        pids.insert(b"context", Box::new(move |pid| context::resource_pid(pid)));
+        pids.insert(b"iostat", Box::new(move |pid| iostat::resource_pid(pid)));
+        pids.insert(b"syscall", Box::new(move |pid| syscall::resource_pid(pid)));
+
         SysScheme {
             next_id: AtomicUsize::new(0),
             files: files,
+            pids: pids,
             handles: RwLock::new(BTreeMap::new())
         }
     }
@@ -60,6 +86,7 @@ impl Scheme for SysScheme {
     fn open(&self, path: &[u8], _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
         let path_utf8 = str::from_utf8(path).or(Err(Error::new(ENOENT)))?;
         let path_trimmed = path_utf8.trim_matches('/');
+        let path_dir = path_utf8.ends_with('/');
 
         if path_trimmed.is_empty() {
             let mut data = Vec::new();
@@ -69,10 +96,36 @@ impl Scheme for SysScheme {
                 }
                 data.extend_from_slice(entry.0);
             }
+            for entry in self.pids.iter() {
+                data.push(b'\n');
+                data.extend_from_slice(entry.0);
+                data.push(b'/');
+            }
 
             let id = self.next_id.fetch_add(1, Ordering::SeqCst);
             self.handles.write().insert(id, Handle {
-                path: b"",
+                path: Box::new([]),
+                data: data,
+                mode: MODE_DIR | 0o444,
+                seek: 0
+            });
+            return Ok(id)
+        } else if path_dir && self.pids.contains_key(path_trimmed.as_bytes()) {
+            // The contexts that have files in a directory such as `context/`
+            let mut data = Vec::new();
+            {
+                let contexts = ::context::contexts();
+                for (pid, _context_lock) in contexts.iter() {
+                    if ! data.is_empty() {
+                        data.push(b'\n');
+                    }
+                    data.extend_from_slice(format!("{}", pid.into()).as_bytes());
+                }
+            }
+
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            self.handles.write().insert(id, Handle {
+                path: format!("{}/", path_trimmed).into_bytes().into_boxed_slice(),
                 data: data,
                 mode: MODE_DIR | 0o444,
                 seek: 0
@@ -84,7 +137,7 @@ impl Scheme for SysScheme {
                 if entry.0 == &path_trimmed.as_bytes() {
                     let id = self.next_id.fetch_add(1, Ordering::SeqCst);
                     self.handles.write().insert(id, Handle {
-                        path: entry.0,
+                        path: entry.0.to_vec().into_boxed_slice(),
                         data: entry.1()?,
                         mode: MODE_FILE | 0o444,
                         seek: 0
@@ -92,6 +145,28 @@ impl Scheme for SysScheme {
                     return Ok(id)
                 }
             }
+
+            // Files of one context, such as `context/1`
+            let mut parts = path_trimmed.splitn(2, '/');
+            let dir = parts.next().unwrap_or("");
+            if let (Some(pid_fn), Some(pid)) = (self.pids.get(dir.as_bytes()), parts.next()) {
+                let pid = pid.parse::<usize>().or(Err(Error::new(ENOENT)))?;
+                // A context that is gone is a missing file
+                let data = pid_fn(ContextId::from(pid)).map_err(|err| if err.errno == ESRCH {
+                    Error::new(ENOENT)
+                } else {
+                    err
+                })?;
+
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                self.handles.write().insert(id, Handle {
+                    path: path_trimmed.as_bytes().to_vec().into_boxed_slice(),
+                    data: data,
+                    mode: MODE_FILE | 0o444,
+                    seek: 0
+                });
+                return Ok(id)
+            }
         }
 
         Err(Error::new(ENOENT))
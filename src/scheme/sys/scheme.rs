@@ -1,10 +1,27 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
 
-use context;
-use scheme;
+use context::{self, ContextId};
+use scheme::{self, SchemeId, SchemeNamespace};
+use scheme::user::user_scheme;
 use syscall::error::{Error, ESRCH, Result};
+use super::json;
 
-pub fn resource() -> Result<Vec<u8>> {
+/// A scheme name, with its daemon and the number of open and pending files
+struct Row {
+    id: SchemeId,
+    ns: SchemeNamespace,
+    pid: Option<ContextId>,
+    files: usize,
+    pending: Option<usize>,
+    name: Box<[u8]>,
+}
+
+fn rows() -> Result<Vec<Row>> {
     let scheme_ns = {
         let contexts = context::contexts();
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
@@ -12,13 +29,95 @@ pub fn resource() -> Result<Vec<u8>> {
         context.ens
     };
 
-    let mut data = Vec::new();
+    // Descriptions open on each scheme, shared ones are counted once
+    let mut open: BTreeMap<SchemeId, BTreeSet<usize>> = BTreeMap::new();
+    {
+        let contexts = context::contexts();
+        for (_id, context_lock) in contexts.iter() {
+            let context = context_lock.read();
+            for file_opt in context.files.lock().iter() {
+                if let Some(ref file) = *file_opt {
+                    let scheme_id = file.description.read().scheme;
+                    open.entry(scheme_id).or_insert_with(BTreeSet::new).insert(&*file.description as *const _ as usize);
+                }
+            }
+        }
+    }
+
+    // Namespaces made from the current one are listed too
+    let mut rows = Vec::new();
+    {
+        let schemes = scheme::schemes();
+        for &ns in schemes.iter_ns() {
+            if schemes.is_within(ns, scheme_ns) {
+                for (name, &scheme_id) in schemes.iter_name(ns) {
+                    rows.push((ns, scheme_id, name.clone()));
+                }
+            }
+        }
+    }
+
+    Ok(rows.into_iter().map(|(ns, id, name)| {
+        let (pid, pending) = match user_scheme(id) {
+            Some(inner) => (inner.owner(), Some(inner.pending_count())),
+            None => (None, None)
+        };
+        Row {
+            id: id,
+            ns: ns,
+            pid: pid,
+            files: open.get(&id).map_or(0, |descriptions| descriptions.len()),
+            pending: pending,
+            name: name,
+        }
+    }).collect())
+}
+
+pub fn resource() -> Result<Vec<u8>> {
+    let rows = rows()?;
+
+    let mut string = format!("{:<6}{:<6}{:<6}{:<8}{:<8}{}\n",
+                             "ID",
+                             "NS",
+                             "PID",
+                             "FILES",
+                             "PENDING",
+                             "NAME");
+    for row in rows.iter() {
+        let _ = writeln!(string, "{:<6}{:<6}{:<6}{:<8}{:<8}{}",
+                         row.id.into(),
+                         row.ns.into(),
+                         row.pid.map_or(String::from("-"), |pid| format!("{}", pid.into())),
+                         row.files,
+                         row.pending.map_or(String::from("-"), |pending| format!("{}", pending)),
+                         str::from_utf8(&row.name).unwrap_or("?"));
+    }
+
+    Ok(string.into_bytes())
+}
 
-    let schemes = scheme::schemes();
-    for (name, _scheme_id) in schemes.iter_name(scheme_ns) {
-        data.extend_from_slice(name);
-        data.push(b'\n');
+/// The scheme names as a JSON array, with `null` for schemes without a daemon
+pub fn resource_json() -> Result<Vec<u8>> {
+    let mut string = String::from("[");
+    for (i, row) in rows()?.iter().enumerate() {
+        if i > 0 {
+            string.push(',');
+        }
+        let _ = write!(string, "{{\"id\":{},\"ns\":{},", row.id.into(), row.ns.into());
+        match row.pid {
+            Some(pid) => { let _ = write!(string, "\"pid\":{},", pid.into()); },
+            None => string.push_str("\"pid\":null,")
+        }
+        let _ = write!(string, "\"files\":{},", row.files);
+        match row.pending {
+            Some(pending) => { let _ = write!(string, "\"pending\":{},", pending); },
+            None => string.push_str("\"pending\":null,")
+        }
+        string.push_str("\"name\":");
+        json::bytes(&mut string, &row.name);
+        string.push('}');
     }
+    string.push_str("]\n");
 
-    Ok(data)
+    Ok(string.into_bytes())
 }
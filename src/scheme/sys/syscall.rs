@@ -3,22 +3,50 @@ use alloc::vec::Vec;
 use core::fmt::Write;
 use core::str;
 
-use context;
+use context::{self, ContextId};
 use syscall;
-use syscall::error::Result;
+use syscall::error::{Error, ESRCH, Result};
+use super::json;
+
+type Row = (ContextId, Vec<u8>, Option<(usize, usize, usize, usize, usize, usize)>);
+
+fn rows(pid: Option<ContextId>) -> Result<Vec<Row>> {
+    let mut rows = Vec::new();
+    let contexts = context::contexts();
+    if let Some(pid) = pid {
+        let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        rows.push((pid, context.name.lock().clone(), context.syscall.clone()));
+    } else {
+        for (id, context_lock) in contexts.iter() {
+            let context = context_lock.read();
+            rows.push((*id, context.name.lock().clone(), context.syscall.clone()));
+        }
+    }
+    Ok(rows)
+}
+
+/// Write a row as a JSON object, with `null` when not in a system call
+fn write_json(string: &mut String, row: &Row) {
+    let _ = write!(string, "{{\"pid\":{},\"name\":", row.0.into());
+    json::bytes(string, &row.1);
+    string.push_str(",\"syscall\":");
+    match row.2 {
+        Some((a, b, c, d, e, f)) => {
+            let _ = write!(string, "{{\"number\":{},\"args\":[{},{},{},{},{}],\"text\":", a, b, c, d, e, f);
+            json::string(string, &syscall::debug::format_call(a, b, c, d, e, f));
+            string.push('}');
+        },
+        None => string.push_str("null")
+    }
+    string.push('}');
+}
 
 pub fn resource() -> Result<Vec<u8>> {
     let mut string = String::new();
 
     {
-        let mut rows = Vec::new();
-        {
-            let contexts = context::contexts();
-            for (id, context_lock) in contexts.iter() {
-                let context = context_lock.read();
-                rows.push((*id, context.name.lock().clone(), context.syscall.clone()));
-            }
-        }
+        let rows = rows(None)?;
 
         for row in rows.iter() {
             let id: usize = row.0.into();
@@ -34,3 +62,28 @@ pub fn resource() -> Result<Vec<u8>> {
 
     Ok(string.into_bytes())
 }
+
+/// The system calls of all contexts as a JSON array
+pub fn resource_json() -> Result<Vec<u8>> {
+    let mut string = String::from("[");
+    for (i, row) in rows(None)?.iter().enumerate() {
+        if i > 0 {
+            string.push(',');
+        }
+        write_json(&mut string, row);
+    }
+    string.push_str("]\n");
+
+    Ok(string.into_bytes())
+}
+
+/// The system call of one context as a JSON object
+pub fn resource_pid(pid: ContextId) -> Result<Vec<u8>> {
+    let mut string = String::new();
+    for row in rows(Some(pid))?.iter() {
+        write_json(&mut string, row);
+    }
+    string.push('\n');
+
+    Ok(string.into_bytes())
+}
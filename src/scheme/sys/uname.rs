@@ -1,5 +1,7 @@
+use alloc::string::String;
 use alloc::vec::Vec;
 use syscall::error::Result;
+use super::json;
 
 pub fn resource() -> Result<Vec<u8>> {
     Ok(format!("Redox\n\n{}\n\n{}\n",
@@ -7,3 +9,12 @@ pub fn resource() -> Result<Vec<u8>> {
                env!("TARGET").split('-').next().unwrap()).into_bytes())
 }
 
+pub fn resource_json() -> Result<Vec<u8>> {
+    let mut string = String::from("{\"sysname\":\"Redox\",\"release\":");
+    json::string(&mut string, env!("CARGO_PKG_VERSION"));
+    string.push_str(",\"machine\":");
+    json::string(&mut string, env!("TARGET").split('-').next().unwrap());
+    string.push_str("}\n");
+
+    Ok(string.into_bytes())
+}
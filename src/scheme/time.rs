@@ -4,10 +4,11 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::RwLock;
 
 use context::timeout;
+use event;
 use scheme::SchemeId;
 use syscall::data::TimeSpec;
 use syscall::error::*;
-use syscall::flag::{CLOCK_REALTIME, CLOCK_MONOTONIC};
+use syscall::flag::{CLOCK_REALTIME, CLOCK_MONOTONIC, EVENT_READ};
 use syscall::scheme::Scheme;
 use time;
 
@@ -65,6 +66,9 @@ impl Scheme for TimeScheme {
             i += 1;
         }
 
+        // The timeout that fired has been seen
+        event::clear(self.scheme_id, id, EVENT_READ);
+
         Ok(i * mem::size_of::<TimeSpec>())
     }
 
@@ -76,6 +80,9 @@ impl Scheme for TimeScheme {
 
         let time_buf = unsafe { slice::from_raw_parts(buf.as_ptr() as *const TimeSpec, buf.len()/mem::size_of::<TimeSpec>()) };
 
+        // Waits for the new timeout
+        event::clear(self.scheme_id, id, EVENT_READ);
+
         let mut i = 0;
         while i < time_buf.len() {
             let time = time_buf[i];
@@ -1,23 +1,195 @@
 use alloc::sync::{Arc, Weak};
 use alloc::boxed::Box;
-use alloc::collections::BTreeMap;
-use core::sync::atomic::{AtomicU64, Ordering};
-use core::{mem, slice, usize};
-use spin::{Mutex, RwLock};
-
-use context::{self, Context};
-use context::memory::Grant;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::{intrinsics, mem, slice, usize};
+use spin::{Mutex, Once, RwLock};
+
+use context::{self, Context, ContextId};
+use context::file::FileDescriptor;
+use context::memory::{Grant, SharedFrames};
 use event;
 use paging::{InactivePageTable, Page, VirtualAddress};
 use paging::entry::EntryFlags;
 use paging::temporary_page::TemporaryPage;
 use scheme::{AtomicSchemeId, ATOMIC_SCHEMEID_INIT, SchemeId};
 use sync::{WaitQueue, WaitMap};
-use syscall::data::{Packet, Stat, StatVfs, TimeSpec};
+use syscall::data::{Packet, Stat, StatVfs, TimeSpec, UserBufferHeader};
 use syscall::error::*;
-use syscall::flag::{EVENT_READ, O_NONBLOCK};
+use syscall::flag::{EVENT_READ, F_GETTIMEOUT, F_SETTIMEOUT, O_NONBLOCK, USER_BUFFER_AQ_ENTRIES, USER_BUFFER_AQ_OFFSET,
+                    USER_BUFFER_RQ_ENTRIES, USER_BUFFER_RQ_OFFSET, USER_BUFFER_SLOT_OFFSET};
 use syscall::number::*;
 use syscall::scheme::Scheme;
+use time;
+
+/// Size of each slot of a `UserBuffer`
+pub const USER_BUFFER_SLOT_SIZE: usize = 4096;
+
+/// Requests whose caller gave up that are remembered until the scheme daemon answers them.
+/// Past this, the oldest are forgotten and the buffers lent for them are given back
+pub const USER_CANCELLED_MAX: usize = 256;
+
+/// Memory shared between the kernel and a scheme daemon, registered with `fmap` on the
+/// scheme handle, laid out as described by `UserBufferHeader`
+///
+/// Requests are put in its request queue, and the daemon puts answers in its answer queue and
+/// calls `fsync` on the scheme handle to have them handled, so packets are not copied by read
+/// and write. Requests that do not fit are read from the scheme handle as before. Transfers of
+/// up to `USER_BUFFER_SLOT_SIZE` bytes are copied through its slots instead of mapping a
+/// temporary grant into the daemon for every call. Larger transfers, and transfers made while
+/// every slot is lent, are still captured with a grant
+pub struct UserBuffer {
+    frames: SharedFrames,
+    /// Slots that are not in use
+    free: Mutex<Vec<usize>>,
+    /// Serializes writers of the request queue
+    rq_lock: Mutex<()>,
+}
+
+impl UserBuffer {
+    fn new(size: usize) -> Result<UserBuffer> {
+        if size < USER_BUFFER_SLOT_OFFSET + USER_BUFFER_SLOT_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+
+        let frames = SharedFrames::new(size)?;
+        let slots = (frames.size() - USER_BUFFER_SLOT_OFFSET)/USER_BUFFER_SLOT_SIZE;
+        Ok(UserBuffer {
+            frames: frames,
+            free: Mutex::new((0..slots).rev().collect()),
+            rq_lock: Mutex::new(()),
+        })
+    }
+
+    fn header(&self) -> *mut UserBufferHeader {
+        self.frames.kernel_address() as *mut UserBufferHeader
+    }
+
+    /// Number of requests in the request queue that the daemon has not taken
+    fn requests(&self) -> usize {
+        let header = self.header();
+        unsafe {
+            let head = intrinsics::atomic_load(&(*header).rq_head);
+            let tail = intrinsics::atomic_load(&(*header).rq_tail);
+            tail.wrapping_sub(head)
+        }
+    }
+
+    /// Put a request in the request queue, returns false if it is full
+    fn push_request(&self, packet: &Packet) -> bool {
+        let _guard = self.rq_lock.lock();
+
+        let header = self.header();
+        unsafe {
+            let head = intrinsics::atomic_load(&(*header).rq_head);
+            let tail = (*header).rq_tail;
+            // A head moved past the tail by the daemon counts as full
+            if tail.wrapping_sub(head) >= USER_BUFFER_RQ_ENTRIES {
+                return false;
+            }
+            let rq = (self.frames.kernel_address() + USER_BUFFER_RQ_OFFSET) as *mut Packet;
+            *rq.offset((tail % USER_BUFFER_RQ_ENTRIES) as isize) = *packet;
+            intrinsics::atomic_store(&mut (*header).rq_tail, tail.wrapping_add(1));
+        }
+        true
+    }
+
+    /// Take the answers in the answer queue when called. The tail is read once, so that the
+    /// daemon cannot keep the kernel here
+    fn pop_answers(&self) -> Result<Vec<Packet>> {
+        let header = self.header();
+        unsafe {
+            let head = (*header).aq_head;
+            let tail = intrinsics::atomic_load(&(*header).aq_tail);
+            let count = tail.wrapping_sub(head);
+            if count > USER_BUFFER_AQ_ENTRIES {
+                return Err(Error::new(EINVAL));
+            }
+
+            let aq = (self.frames.kernel_address() + USER_BUFFER_AQ_OFFSET) as *const Packet;
+            let mut answers = Vec::with_capacity(count);
+            for i in 0..count {
+                answers.push(*aq.offset((head.wrapping_add(i) % USER_BUFFER_AQ_ENTRIES) as isize));
+            }
+            intrinsics::atomic_store(&mut (*header).aq_head, tail);
+            Ok(answers)
+        }
+    }
+
+    fn user_address(&self, slot: usize) -> usize {
+        self.frames.user_address() + USER_BUFFER_SLOT_OFFSET + slot * USER_BUFFER_SLOT_SIZE
+    }
+
+    fn slot(&self) -> Option<usize> {
+        self.free.lock().pop()
+    }
+
+    fn release_slot(&self, slot: usize) {
+        self.free.lock().push(slot);
+    }
+
+    fn slot_mut(&self, slot: usize, len: usize) -> &mut [u8] {
+        let address = self.frames.kernel_address() + USER_BUFFER_SLOT_OFFSET + slot * USER_BUFFER_SLOT_SIZE;
+        unsafe { slice::from_raw_parts_mut(address as *mut u8, len) }
+    }
+}
+
+/// A buffer lent to the scheme daemon for one request
+enum Lent {
+    /// A slot of the shared buffer
+    Slot(Arc<UserBuffer>, usize),
+    /// A grant mapped into the scheme daemon
+    Grant(usize),
+}
+
+/// A request whose caller gave up, or the packet asking the scheme daemon to abort it
+struct Cancelled {
+    /// The cancel packet of a request, or the request of a cancel packet
+    partner: u64,
+    /// Lent until either the request or the cancel packet is answered
+    lent: Option<Lent>,
+}
+
+/// Handler for the answer to a request sent with `UserInner::call_async`
+pub trait Completion: Send {
+    fn complete(self: Box<Self>, inner: &UserInner, result: usize);
+}
+
+/// User schemes by scheme ID, for callers that need more than the `Scheme` trait
+static USER_SCHEMES: Once<RwLock<BTreeMap<SchemeId, Weak<UserInner>>>> = Once::new();
+
+/// Initialize user schemes, called if needed
+fn init_user_schemes() -> RwLock<BTreeMap<SchemeId, Weak<UserInner>>> {
+    RwLock::new(BTreeMap::new())
+}
+
+/// Remember `inner` as the user scheme registered as `scheme_id`
+pub fn register_user_scheme(scheme_id: SchemeId, inner: Weak<UserInner>) {
+    USER_SCHEMES.call_once(init_user_schemes).write().insert(scheme_id, inner);
+}
+
+/// Get the user scheme registered as `scheme_id`, if it is one
+pub fn user_scheme(scheme_id: SchemeId) -> Option<Arc<UserInner>> {
+    USER_SCHEMES.call_once(init_user_schemes).read().get(&scheme_id).and_then(|inner| inner.upgrade())
+}
+
+/// Bytes of the buffers registered by all scheme daemons
+pub fn user_buffer_usage() -> usize {
+    let mut size = 0;
+    let mut seen = BTreeSet::new();
+    for (_id, inner_weak) in USER_SCHEMES.call_once(init_user_schemes).read().iter() {
+        if let Some(inner) = inner_weak.upgrade() {
+            if ! seen.insert(&*inner as *const UserInner as usize) {
+                continue;
+            }
+            if let Some(ref buffer) = *inner.buffer.read() {
+                size += buffer.frames.size();
+            }
+        }
+    }
+    size
+}
 
 pub struct UserInner {
     root_id: SchemeId,
@@ -29,7 +201,18 @@ pub struct UserInner {
     context: Weak<RwLock<Context>>,
     todo: WaitQueue<Packet>,
     fmap: Mutex<BTreeMap<u64, (Weak<RwLock<Context>>, usize)>>,
-    done: WaitMap<u64, usize>
+    done: WaitMap<u64, usize>,
+    /// Requests sent with `call_async`, completed when answered instead of waking a caller
+    pending: Mutex<BTreeMap<u64, Box<Completion>>>,
+    buffer: RwLock<Option<Arc<UserBuffer>>>,
+    /// Milliseconds a caller waits for an answer, zero to wait forever
+    timeout: AtomicU64,
+    /// Requests whose caller gave up and their cancel packets, the answers are dropped
+    cancelled: Mutex<BTreeMap<u64, Cancelled>>,
+    /// Requests with a caller waiting in `call_inner`
+    waiting: Mutex<BTreeSet<u64>>,
+    /// Set when the scheme daemon closed the scheme handle
+    dead: AtomicBool,
 }
 
 impl UserInner {
@@ -44,11 +227,33 @@ impl UserInner {
             context: context,
             todo: WaitQueue::new(),
             fmap: Mutex::new(BTreeMap::new()),
-            done: WaitMap::new()
+            done: WaitMap::new(),
+            pending: Mutex::new(BTreeMap::new()),
+            buffer: RwLock::new(None),
+            timeout: AtomicU64::new(0),
+            cancelled: Mutex::new(BTreeMap::new()),
+            waiting: Mutex::new(BTreeSet::new()),
+            dead: AtomicBool::new(false),
         }
     }
 
     pub fn call(&self, a: usize, b: usize, c: usize, d: usize) -> Result<usize> {
+        self.call_lent(&mut None, a, b, c, d)
+    }
+
+    /// Like `call`, but waits for the answer even if signaled or past the timeout, for
+    /// requests such as close that must not be abandoned
+    pub fn call_uninterruptible(&self, a: usize, b: usize, c: usize, d: usize) -> Result<usize> {
+        self.call_packet(&mut None, false, a, b, c, d)
+    }
+
+    /// Like `call`, for a request that uses a buffer lent to the scheme daemon. If the caller
+    /// gives up, `lent` is taken and kept until the scheme daemon is done with it
+    fn call_lent(&self, lent: &mut Option<Lent>, a: usize, b: usize, c: usize, d: usize) -> Result<usize> {
+        self.call_packet(lent, true, a, b, c, d)
+    }
+
+    fn call_packet(&self, lent: &mut Option<Lent>, interruptible: bool, a: usize, b: usize, c: usize, d: usize) -> Result<usize> {
         let (pid, uid, gid) = {
             let contexts = context::contexts();
             let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
@@ -65,16 +270,384 @@ impl UserInner {
             b: b,
             c: c,
             d: d
-        })
+        }, lent, interruptible)
     }
 
-    fn call_inner(&self, packet: Packet) -> Result<usize> {
+    /// Send a request without waiting for the answer, which is passed to `completion`
+    pub fn call_async(&self, a: usize, b: usize, c: usize, d: usize, completion: Box<Completion>) -> Result<()> {
+        let (pid, uid, gid) = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+            let context = context_lock.read();
+            (context.id, context.euid, context.egid)
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        {
+            let mut pending = self.pending.lock();
+            if self.is_dead() {
+                return Err(Error::new(EPIPE));
+            }
+            pending.insert(id, completion);
+        }
+
+        self.queue(Packet {
+            id: id,
+            pid: pid.into(),
+            uid: uid,
+            gid: gid,
+            a: a,
+            b: b,
+            c: c,
+            d: d
+        });
+
+        Ok(())
+    }
+
+    fn call_inner(&self, packet: Packet, lent: &mut Option<Lent>, interruptible: bool) -> Result<usize> {
         let id = packet.id;
+        let pid = packet.pid;
+
+        let context_lock = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+            Arc::clone(&context_lock)
+        };
+
+        let timeout = self.timeout.load(Ordering::SeqCst);
+        let end_opt = if timeout > 0 && interruptible {
+            let start = time::monotonic();
+            let sum = start.1 + (timeout % 1000) * 1_000_000;
+            Some((start.0 + timeout / 1000 + sum / 1_000_000_000, sum % 1_000_000_000))
+        } else {
+            None
+        };
+
+        {
+            let mut waiting = self.waiting.lock();
+            if self.is_dead() {
+                return Err(Error::new(EPIPE));
+            }
+            waiting.insert(id);
+        }
+
+        self.queue(packet);
 
-        self.todo.send(packet);
+        if let Some(end) = end_opt {
+            context_lock.write().wake = Some(end);
+        }
+
+        let answer_opt = if interruptible {
+            self.done.receive_interruptible(&id)
+        } else {
+            Some(self.done.receive(&id))
+        };
+
+        if end_opt.is_some() {
+            context_lock.write().wake = None;
+        }
+
+        match answer_opt {
+            Some(answer) => {
+                self.waiting.lock().remove(&id);
+                Error::demux(answer)
+            },
+            None => if ! self.bounce_lent(lent) {
+                // The daemon would be left with a grant of memory the caller may free, so the
+                // answer has to be waited for
+                let answer = self.done.receive(&id);
+                self.waiting.lock().remove(&id);
+                Error::demux(answer)
+            } else if let Some(answer) = self.cancel(id, pid, lent) {
+                Error::demux(answer)
+            } else if end_opt.map_or(false, |end| time::monotonic() >= end) {
+                Err(Error::new(ETIMEDOUT))
+            } else {
+                Err(Error::new(EINTR))
+            }
+        }
+    }
+
+    /// Give up waiting for request `id`, and ask the scheme daemon to abort it. Returns the
+    /// answer instead if it arrived in the meantime
+    fn cancel(&self, id: u64, pid: usize, lent: &mut Option<Lent>) -> Option<usize> {
+        let mut evicted = Vec::new();
+        let cancel_id = {
+            // Answers are sent while holding this lock, so they are either received here or dropped
+            let mut cancelled = self.cancelled.lock();
+            if let Some(answer) = self.done.receive_nonblock(&id) {
+                self.waiting.lock().remove(&id);
+                return Some(answer);
+            }
+            self.waiting.lock().remove(&id);
+
+            // A daemon that never answers must not grow the list forever. The buffers lent
+            // for forgotten requests are taken back from it
+            while cancelled.len() >= USER_CANCELLED_MAX * 2 {
+                let oldest_opt = cancelled.keys().next().cloned();
+                if let Some(oldest) = oldest_opt {
+                    if let Some(entry) = cancelled.remove(&oldest) {
+                        evicted.extend(entry.lent);
+                        if let Some(partner) = cancelled.remove(&entry.partner) {
+                            evicted.extend(partner.lent);
+                        }
+                    }
+                }
+            }
+
+            let cancel_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            cancelled.insert(id, Cancelled {
+                partner: cancel_id,
+                lent: lent.take(),
+            });
+            cancelled.insert(cancel_id, Cancelled {
+                partner: id,
+                lent: None,
+            });
+            cancel_id
+        };
+
+        for lent in evicted {
+            self.give_back(lent);
+        }
+
+        self.fmap.lock().remove(&id);
+
+        self.queue(Packet {
+            id: cancel_id,
+            pid: pid,
+            uid: 0,
+            gid: 0,
+            a: KSMSG_CANCEL,
+            b: id as usize,
+            c: 0,
+            d: 0
+        });
+
+        None
+    }
+
+    /// Send a request to the scheme daemon, through the request queue of the shared buffer if
+    /// there is room. Requests that are already waiting to be read keep the order
+    fn queue(&self, packet: Packet) {
+        let queued = match *self.buffer.read() {
+            Some(ref buffer) => self.todo.is_empty() && buffer.push_request(&packet),
+            None => false
+        };
+        if queued {
+            self.todo.condition.notify();
+        } else {
+            self.todo.send(packet);
+        }
         event::trigger(self.root_id, self.handle_id, EVENT_READ);
+    }
 
-        Error::demux(self.done.receive(&id))
+    /// Whether there are requests for the scheme daemon in any queue
+    fn has_requests(&self) -> bool {
+        ! self.todo.is_empty() || self.buffer.read().as_ref().map_or(false, |buffer| buffer.requests() > 0)
+    }
+
+    /// Add `file` to the files of the scheme daemon, and pass the new descriptor to it along
+    /// with the handle `number` it was sent on
+    pub fn send_fd(&self, number: usize, file: FileDescriptor, flags: usize) -> Result<usize> {
+        let context_lock = self.context.upgrade().ok_or(Error::new(EPIPE))?;
+        // The weak reference keeps the address of the description from being reused
+        let description = Arc::downgrade(&file.description);
+        let description_ptr = &*file.description as *const _;
+        let fd = context_lock.read().add_file(file).ok_or(Error::new(EMFILE))?;
+
+        let result = self.call(SYS_SENDFD, number, fd.into(), flags);
+        if result.is_err() {
+            // Refused by the daemon, so the descriptor must not stay open in its files, unless
+            // the daemon already closed it and the slot was reused
+            let file_opt = context_lock.read().remove_file_if(fd, description_ptr);
+            if let Some(file) = file_opt {
+                let _ = file.close();
+            }
+        }
+        drop(description);
+        result
+    }
+
+    /// The context of the scheme daemon, if it still exists
+    pub fn owner(&self) -> Option<ContextId> {
+        self.context.upgrade().map(|context_lock| context_lock.read().id)
+    }
+
+    /// Requests sent to the scheme daemon that have not been answered
+    pub fn pending_count(&self) -> usize {
+        self.waiting.lock().len() + self.pending.lock().len()
+    }
+
+    /// Whether the scheme daemon is gone
+    pub fn is_dead(&self) -> bool {
+        self.dead.load(Ordering::SeqCst)
+    }
+
+    /// Fail all requests in flight, called when the scheme daemon closes the scheme handle.
+    /// Later requests fail with `EPIPE`
+    pub fn disconnect(&self) {
+        let waiting = {
+            let mut waiting = self.waiting.lock();
+            self.dead.store(true, Ordering::SeqCst);
+            mem::replace(&mut *waiting, BTreeSet::new())
+        };
+        for id in waiting {
+            self.done.send(id, Error::mux(Err(Error::new(EIO))));
+        }
+
+        let pending = mem::replace(&mut *self.pending.lock(), BTreeMap::new());
+        for (_id, completion) in pending {
+            completion.complete(self, Error::mux(Err(Error::new(EIO))));
+        }
+
+        self.fmap.lock().clear();
+
+        let cancelled = mem::replace(&mut *self.cancelled.lock(), BTreeMap::new());
+        for (_id, entry) in cancelled {
+            if let Some(lent) = entry.lent {
+                self.give_back(lent);
+            }
+        }
+    }
+
+    /// Handle fcntl on the scheme handle
+    pub fn fcntl(&self, cmd: usize, arg: usize) -> Result<usize> {
+        match cmd {
+            F_GETTIMEOUT => Ok(self.timeout.load(Ordering::SeqCst) as usize),
+            F_SETTIMEOUT => {
+                self.timeout.store(arg as u64, Ordering::SeqCst);
+                Ok(0)
+            },
+            _ => Ok(0)
+        }
+    }
+
+    /// Register the shared buffer of packet queues and small-buffer slots, must be called by
+    /// the scheme daemon. Returns its address
+    pub fn register_buffer(&self, size: usize) -> Result<usize> {
+        let mut buffer = self.buffer.write();
+        if buffer.is_some() {
+            return Err(Error::new(EEXIST));
+        }
+
+        let new_buffer = UserBuffer::new(size)?;
+        let user = new_buffer.frames.user_address();
+        *buffer = Some(Arc::new(new_buffer));
+        Ok(user)
+    }
+
+    /// Send the request made by `f` from the address of `buf` in the scheme daemon, copying
+    /// it through the shared buffer if possible, and capturing it otherwise
+    pub fn call_buf<F>(&self, buf: &[u8], f: F) -> Result<usize> where F: FnOnce(usize) -> (usize, usize, usize, usize) {
+        let mut lent = None;
+        if ! buf.is_empty() && buf.len() <= USER_BUFFER_SLOT_SIZE {
+            let buffer_opt = self.buffer.read().clone();
+            if let Some(buffer) = buffer_opt {
+                if let Some(slot) = buffer.slot() {
+                    buffer.slot_mut(slot, buf.len()).copy_from_slice(buf);
+                    lent = Some(Lent::Slot(buffer, slot));
+                }
+            }
+        }
+        if lent.is_none() {
+            lent = Some(Lent::Grant(self.capture(buf)?));
+        }
+
+        let (a, b, c, d) = f(self.lent_address(&lent));
+        let result = self.call_lent(&mut lent, a, b, c, d);
+        if let Some(lent) = lent {
+            self.give_back(lent);
+        }
+        result
+    }
+
+    /// Like `call_buf`, for a buffer the scheme daemon writes to
+    pub fn call_buf_mut<F>(&self, buf: &mut [u8], f: F) -> Result<usize> where F: FnOnce(usize) -> (usize, usize, usize, usize) {
+        let mut lent = None;
+        if ! buf.is_empty() && buf.len() <= USER_BUFFER_SLOT_SIZE {
+            let buffer_opt = self.buffer.read().clone();
+            if let Some(buffer) = buffer_opt {
+                if let Some(slot) = buffer.slot() {
+                    // Copied in as well, so the daemon never sees stale data from other callers
+                    buffer.slot_mut(slot, buf.len()).copy_from_slice(buf);
+                    lent = Some(Lent::Slot(buffer, slot));
+                }
+            }
+        }
+        if lent.is_none() {
+            lent = Some(Lent::Grant(self.capture_mut(buf)?));
+        }
+
+        let (a, b, c, d) = f(self.lent_address(&lent));
+        let result = self.call_lent(&mut lent, a, b, c, d);
+        if let Some(lent) = lent {
+            if let Lent::Slot(ref buffer, slot) = lent {
+                if result.is_ok() {
+                    let len = buf.len();
+                    buf.copy_from_slice(buffer.slot_mut(slot, len));
+                }
+            }
+            self.give_back(lent);
+        }
+        result
+    }
+
+    fn lent_address(&self, lent: &Option<Lent>) -> usize {
+        match *lent {
+            Some(Lent::Slot(ref buffer, slot)) => buffer.user_address(slot),
+            Some(Lent::Grant(address)) => address,
+            None => 0
+        }
+    }
+
+    /// Free a buffer the scheme daemon is done with
+    fn give_back(&self, lent: Lent) {
+        match lent {
+            Lent::Slot(buffer, slot) => buffer.release_slot(slot),
+            Lent::Grant(address) => {
+                let _ = self.release(address);
+            }
+        }
+    }
+
+    /// Make a lent grant independent of the memory of the caller, so that the caller can stop
+    /// waiting for the request. Returns false if there is no memory to copy it to. A grant that
+    /// is already gone, along with the daemon or unmapped by it, needs nothing
+    fn bounce_lent(&self, lent: &Option<Lent>) -> bool {
+        match *lent {
+            Some(Lent::Grant(address)) => match self.bounce(address) {
+                Err(err) => err.errno != ENOMEM,
+                Ok(()) => true
+            },
+            _ => true
+        }
+    }
+
+    /// Copy the grant at `address` to frames of its own, see `Grant::bounce_inactive`
+    pub fn bounce(&self, address: usize) -> Result<()> {
+        if address == 0 {
+            Ok(())
+        } else {
+            let context_lock = self.context.upgrade().ok_or(Error::new(ESRCH))?;
+            let context = context_lock.read();
+
+            let mut grants = context.grants.lock();
+
+            let mut new_table = unsafe { InactivePageTable::from_address(context.arch.get_page_table()) };
+            let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(::USER_TMP_GRANT_OFFSET)));
+
+            for grant in grants.iter_mut() {
+                let start = grant.start_address().get();
+                let end = start + grant.size();
+                if address >= start && address < end {
+                    return grant.bounce_inactive(&mut new_table, &mut temporary_page);
+                }
+            }
+
+            Err(Error::new(EFAULT))
+        }
     }
 
     pub fn capture(&self, buf: &[u8]) -> Result<usize> {
@@ -166,9 +739,23 @@ impl UserInner {
         }
     }
 
+    /// Read requests that did not fit in the shared buffer. With a shared buffer, this waits
+    /// for a request in either queue, and may return none if they are all in the buffer
     pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
         let packet_buf = unsafe { slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut Packet, buf.len()/mem::size_of::<Packet>()) };
-        Ok(self.todo.receive_into(packet_buf, self.flags & O_NONBLOCK != O_NONBLOCK) * mem::size_of::<Packet>())
+        let block = self.flags & O_NONBLOCK != O_NONBLOCK;
+        let count = if self.buffer.read().is_some() {
+            while block && ! self.has_requests() {
+                let _ = self.todo.condition.wait();
+            }
+            self.todo.receive_into(packet_buf, false)
+        } else {
+            self.todo.receive_into(packet_buf, block)
+        };
+        if ! self.has_requests() {
+            event::clear(self.root_id, self.handle_id, EVENT_READ);
+        }
+        Ok(count * mem::size_of::<Packet>())
     }
 
     pub fn write(&self, buf: &[u8]) -> Result<usize> {
@@ -176,33 +763,90 @@ impl UserInner {
         let len = buf.len()/packet_size;
         let mut i = 0;
         while i < len {
-            let mut packet = unsafe { *(buf.as_ptr() as *const Packet).offset(i as isize) };
-            if packet.id == 0 {
-                match packet.a {
-                    SYS_FEVENT => event::trigger(self.scheme_id.load(Ordering::SeqCst), packet.b, packet.c),
-                    _ => println!("Unknown scheme -> kernel message {}", packet.a)
+            let packet = unsafe { *(buf.as_ptr() as *const Packet).offset(i as isize) };
+            self.handle_answer(packet);
+            i += 1;
+        }
+
+        Ok(i * packet_size)
+    }
+
+    /// Handle an answer or a message from the scheme daemon
+    fn handle_answer(&self, mut packet: Packet) {
+        if packet.id == 0 {
+            match packet.a {
+                SYS_FEVENT => {
+                    // `d` holds the flags the file is no longer ready for
+                    if packet.d != 0 {
+                        event::clear(self.scheme_id.load(Ordering::SeqCst), packet.b, packet.d);
+                    }
+                    if packet.c != 0 {
+                        event::trigger(self.scheme_id.load(Ordering::SeqCst), packet.b, packet.c);
+                    }
+                },
+                _ => println!("Unknown scheme -> kernel message {}", packet.a)
+            }
+        } else {
+            if let Some((context_weak, size)) = self.fmap.lock().remove(&packet.id) {
+                if let Ok(address) = Error::demux(packet.a) {
+                    packet.a = Error::mux(UserInner::capture_inner(&context_weak, address, size, true));
                 }
+            }
+
+            let completion_opt = self.pending.lock().remove(&packet.id);
+            if let Some(completion) = completion_opt {
+                completion.complete(self, packet.a);
             } else {
-                if let Some((context_weak, size)) = self.fmap.lock().remove(&packet.id) {
-                    if let Ok(address) = Error::demux(packet.a) {
-                        packet.a = Error::mux(UserInner::capture_inner(&context_weak, address, size, true));
+                let lents = {
+                    let mut cancelled = self.cancelled.lock();
+                    // Dropped if the caller gave up waiting, cancel packets are answered too.
+                    // Answers to forgotten requests are dropped as well
+                    if let Some(entry) = cancelled.remove(&packet.id) {
+                        let partner_lent = cancelled.get_mut(&entry.partner).and_then(|partner| partner.lent.take());
+                        let mut lents = Vec::new();
+                        lents.extend(entry.lent);
+                        lents.extend(partner_lent);
+                        lents
+                    } else {
+                        if self.waiting.lock().contains(&packet.id) {
+                            self.done.send(packet.id, packet.a);
+                        }
+                        Vec::new()
                     }
+                };
+                for lent in lents {
+                    self.give_back(lent);
                 }
-
-                self.done.send(packet.id, packet.a);
             }
-            i += 1;
         }
-
-        Ok(i * packet_size)
     }
 
     pub fn fevent(&self, _flags: usize) -> Result<usize> {
+        // Report requests sent before the registration
+        if self.has_requests() {
+            event::trigger(self.root_id, self.handle_id, EVENT_READ);
+        }
         Ok(self.handle_id)
     }
 
+    /// Handle the answers in the answer queue of the shared buffer, returning their number
     pub fn fsync(&self) -> Result<usize> {
-        Ok(0)
+        let buffer_opt = self.buffer.read().clone();
+        let answers = match buffer_opt {
+            Some(buffer) => buffer.pop_answers()?,
+            None => return Ok(0)
+        };
+        let count = answers.len();
+        for packet in answers {
+            self.handle_answer(packet);
+        }
+        Ok(count)
+    }
+}
+
+impl Drop for UserInner {
+    fn drop(&mut self) {
+        USER_SCHEMES.call_once(init_user_schemes).write().remove(&self.scheme_id.load(Ordering::SeqCst));
     }
 }
 
@@ -221,88 +865,68 @@ impl UserScheme {
 
 impl Scheme for UserScheme {
     fn open(&self, path: &[u8], flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture(path)?;
-        let result = inner.call(SYS_OPEN, address, path.len(), flags);
-        let _ = inner.release(address);
-        result
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
+        inner.call_buf(path, |address| (SYS_OPEN, address, path.len(), flags))
     }
 
     fn chmod(&self, path: &[u8], mode: u16, _uid: u32, _gid: u32) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture(path)?;
-        let result = inner.call(SYS_CHMOD, address, path.len(), mode as usize);
-        let _ = inner.release(address);
-        result
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
+        inner.call_buf(path, |address| (SYS_CHMOD, address, path.len(), mode as usize))
     }
 
     fn rmdir(&self, path: &[u8], _uid: u32, _gid: u32) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture(path)?;
-        let result = inner.call(SYS_RMDIR, address, path.len(), 0);
-        let _ = inner.release(address);
-        result
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
+        inner.call_buf(path, |address| (SYS_RMDIR, address, path.len(), 0))
     }
 
     fn unlink(&self, path: &[u8], _uid: u32, _gid: u32) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture(path)?;
-        let result = inner.call(SYS_UNLINK, address, path.len(), 0);
-        let _ = inner.release(address);
-        result
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
+        inner.call_buf(path, |address| (SYS_UNLINK, address, path.len(), 0))
     }
 
     fn dup(&self, file: usize, buf: &[u8]) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture(buf)?;
-        let result = inner.call(SYS_DUP, file, address, buf.len());
-        let _ = inner.release(address);
-        result
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
+        inner.call_buf(buf, |address| (SYS_DUP, file, address, buf.len()))
     }
 
     fn read(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture_mut(buf)?;
-        let result = inner.call(SYS_READ, file, address, buf.len());
-        let _ = inner.release(address);
-        result
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
+        let len = buf.len();
+        inner.call_buf_mut(buf, |address| (SYS_READ, file, address, len))
     }
 
     fn write(&self, file: usize, buf: &[u8]) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture(buf)?;
-        let result = inner.call(SYS_WRITE, file, address, buf.len());
-        let _ = inner.release(address);
-        result
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
+        inner.call_buf(buf, |address| (SYS_WRITE, file, address, buf.len()))
     }
 
     fn seek(&self, file: usize, position: usize, whence: usize) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
         inner.call(SYS_LSEEK, file, position, whence)
     }
 
     fn fchmod(&self, file: usize, mode: u16) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
         inner.call(SYS_FCHMOD, file, mode as usize, 0)
     }
 
     fn fchown(&self, file: usize, uid: u32, gid: u32) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
         inner.call(SYS_FCHOWN, file, uid as usize, gid as usize)
     }
 
     fn fcntl(&self, file: usize, cmd: usize, arg: usize) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
         inner.call(SYS_FCNTL, file, cmd, arg)
     }
 
     fn fevent(&self, file: usize, flags: usize) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
         inner.call(SYS_FEVENT, file, flags, 0)
     }
 
     fn fmap(&self, file: usize, offset: usize, size: usize) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
 
         let (pid, uid, gid, context_lock) = {
             let contexts = context::contexts();
@@ -324,62 +948,51 @@ impl Scheme for UserScheme {
             b: file,
             c: offset,
             d: size
-        })
+        }, &mut None, true)
     }
 
     fn fpath(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture_mut(buf)?;
-        let result = inner.call(SYS_FPATH, file, address, buf.len());
-        let _ = inner.release(address);
-        result
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
+        let len = buf.len();
+        inner.call_buf_mut(buf, |address| (SYS_FPATH, file, address, len))
     }
 
     fn frename(&self, file: usize, path: &[u8], _uid: u32, _gid: u32) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture(path)?;
-        let result = inner.call(SYS_FRENAME, file, address, path.len());
-        let _ = inner.release(address);
-        result
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
+        inner.call_buf(path, |address| (SYS_FRENAME, file, address, path.len()))
     }
 
     fn fstat(&self, file: usize, stat: &mut Stat) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture_mut(stat)?;
-        let result = inner.call(SYS_FSTAT, file, address, mem::size_of::<Stat>());
-        let _ = inner.release(address);
-        result
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
+        inner.call_buf_mut(stat, |address| (SYS_FSTAT, file, address, mem::size_of::<Stat>()))
     }
 
     fn fstatvfs(&self, file: usize, stat: &mut StatVfs) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture_mut(stat)?;
-        let result = inner.call(SYS_FSTATVFS, file, address, mem::size_of::<StatVfs>());
-        let _ = inner.release(address);
-        result
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
+        inner.call_buf_mut(stat, |address| (SYS_FSTATVFS, file, address, mem::size_of::<StatVfs>()))
     }
 
     fn fsync(&self, file: usize) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
         inner.call(SYS_FSYNC, file, 0, 0)
     }
 
     fn ftruncate(&self, file: usize, len: usize) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
         inner.call(SYS_FTRUNCATE, file, len, 0)
     }
 
     fn futimens(&self, file: usize, times: &[TimeSpec]) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EPIPE))?;
         let buf = unsafe { slice::from_raw_parts(times.as_ptr() as *const u8, mem::size_of::<TimeSpec>() * times.len()) };
-        let address = inner.capture(buf)?;
-        let result = inner.call(SYS_FUTIMENS, file, address, buf.len());
-        let _ = inner.release(address);
-        result
+        inner.call_buf(buf, |address| (SYS_FUTIMENS, file, address, buf.len()))
     }
 
     fn close(&self, file: usize) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        inner.call(SYS_CLOSE, file, 0, 0)
+        // Descriptors of a dead scheme have nothing left to release
+        match self.inner.upgrade() {
+            Some(ref inner) if ! inner.is_dead() => inner.call_uninterruptible(SYS_CLOSE, file, 0, 0),
+            _ => Ok(0)
+        }
     }
 }
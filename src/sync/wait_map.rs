@@ -31,6 +31,18 @@ impl<K, V> WaitMap<K, V> where K: Clone + Ord {
         }
     }
 
+    /// Like `receive`, but returns `None` if the wait was interrupted by a signal or timeout
+    pub fn receive_interruptible(&self, key: &K) -> Option<V> {
+        loop {
+            if let Some(value) = self.receive_nonblock(key) {
+                return Some(value);
+            }
+            if ! self.condition.wait() {
+                return self.receive_nonblock(key);
+            }
+        }
+    }
+
     pub fn receive_any_nonblock(&self) -> Option<(K, V)> {
         let mut inner = self.inner.lock();
         if let Some(key) = inner.keys().next().cloned() {
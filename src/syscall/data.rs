@@ -0,0 +1,113 @@
+//! Syscall data structures
+//!
+//! Re-exports the structures from the `syscall` crate, along with those for kernel
+//! interfaces that have not been published there yet
+
+pub use super::syscall::data::*;
+
+/// Head of a thread's robust futex list, registered with `SYS_SET_ROBUST_LIST`
+///
+/// `list` points to the first entry, and each entry starts with a pointer to the next one.
+/// The list ends when an entry points back to `list`. The futex word of an entry is
+/// found at the entry address plus `futex_offset`.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct RobustListHead {
+    pub list: usize,
+    pub futex_offset: isize,
+    pub list_op_pending: usize,
+}
+
+/// Byte-range lock description, used by `fcntl` with `F_GETLK`, `F_SETLK` and `F_SETLKW`
+///
+/// The range starts `l_start` bytes from the position given by `l_whence`. A zero `l_len`
+/// extends the range to the end of the file.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct Flock {
+    pub l_type: i16,
+    pub l_whence: i16,
+    pub l_start: i64,
+    pub l_len: i64,
+    pub l_pid: i32,
+}
+
+/// Entry of the array passed to `SYS_POLL`
+///
+/// `events` holds the `EVENT_*` flags to wait for, and `revents` is filled with the ones
+/// that occurred. Entries with a negative `fd` are ignored, and entries with a descriptor that
+/// is not open get `EVENT_INVALID`.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct PollFd {
+    pub fd: usize,
+    pub events: usize,
+    pub revents: usize,
+}
+
+/// Header of the memory mapped from a `ring:` handle with `fmap`
+///
+/// The submission queue of `RING_SQ_ENTRIES` `RingSqe` starts at `RING_SQ_OFFSET`, and the
+/// completion queue of `RING_CQ_ENTRIES` `RingCqe` at `RING_CQ_OFFSET`. Userspace advances
+/// `sq_tail` and `cq_head`, the kernel advances `sq_head` and `cq_tail`. Indices increase
+/// forever and are wrapped by the queue size on access.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct RingHeader {
+    pub sq_head: usize,
+    pub sq_tail: usize,
+    pub cq_head: usize,
+    pub cq_tail: usize,
+    /// Number of completions dropped because the completion queue was full
+    pub cq_overflow: usize,
+}
+
+/// Submission queue entry of a `ring:` handle
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct RingSqe {
+    /// One of the `RING_OP_*` values
+    pub opcode: usize,
+    pub fd: usize,
+    pub addr: usize,
+    pub len: usize,
+    /// Flags for `RING_OP_OPEN`
+    pub flags: usize,
+    /// Returned unchanged in the completion
+    pub user_data: usize,
+}
+
+/// Completion queue entry of a `ring:` handle
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct RingCqe {
+    pub user_data: usize,
+    /// Result of the request, a negative errno on failure
+    pub result: usize,
+}
+
+/// Record read from a `signalfd:` handle for each signal received
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct SigInfo {
+    pub si_signo: usize,
+    /// Context that sent the signal, zero if unknown
+    pub si_pid: usize,
+    /// Real user of the context that sent the signal
+    pub si_uid: usize,
+}
+
+/// Header of the buffer a scheme daemon registers by mapping its scheme handle with `fmap`
+///
+/// The request queue of `USER_BUFFER_RQ_ENTRIES` `Packet` starts at `USER_BUFFER_RQ_OFFSET`,
+/// and the answer queue of `USER_BUFFER_AQ_ENTRIES` `Packet` at `USER_BUFFER_AQ_OFFSET`. The
+/// kernel advances `rq_tail` and `aq_head`, the daemon advances `rq_head` and `aq_tail`.
+/// Indices increase forever and are wrapped by the queue size on access.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct UserBufferHeader {
+    pub rq_head: usize,
+    pub rq_tail: usize,
+    pub aq_head: usize,
+    pub aq_tail: usize,
+}
@@ -78,6 +78,31 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
             "unlink({:?})",
             validate_slice(b as *const u8, c).map(ByteStr)
         ),
+        SYS_OPENAT => format!(
+            "openat({}, {:?}, {:#X})",
+            b as isize,
+            validate_slice(c as *const u8, d).map(ByteStr),
+            e
+        ),
+        SYS_MKDIRAT => format!(
+            "mkdirat({}, {:?}, {:#o})",
+            b as isize,
+            validate_slice(c as *const u8, d).map(ByteStr),
+            e
+        ),
+        SYS_FSTATAT => format!(
+            "fstatat({}, {:?}, {:#X}, {:#X})",
+            b as isize,
+            validate_slice(c as *const u8, d).map(ByteStr),
+            e,
+            f
+        ),
+        SYS_UNLINKAT => format!(
+            "unlinkat({}, {:?}, {:#X})",
+            b as isize,
+            validate_slice(c as *const u8, d).map(ByteStr),
+            e
+        ),
         SYS_CLOSE => format!(
             "close({})", b
         ),
@@ -125,11 +150,29 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
                 F_SETFD => "F_SETFD",
                 F_SETFL => "F_SETFL",
                 F_GETFL => "F_GETFL",
+                F_GETLK => "F_GETLK",
+                F_SETLK => "F_SETLK",
+                F_SETLKW => "F_SETLKW",
+                F_GETTIMEOUT => "F_GETTIMEOUT",
+                F_SETTIMEOUT => "F_SETTIMEOUT",
+                F_SETPIPE_SZ => "F_SETPIPE_SZ",
+                F_GETPIPE_SZ => "F_GETPIPE_SZ",
                 _ => "UNKNOWN"
             },
             c,
             d
         ),
+        SYS_FLOCK => format!(
+            "flock({}, {:#X})",
+            b,
+            c
+        ),
+        SYS_SENDFD => format!(
+            "sendfd({}, {}, {:#X})",
+            b,
+            c,
+            d
+        ),
         SYS_FMAP => format!(
             "fmap({}, {:#X}, {})",
             b,
@@ -223,6 +266,35 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
             e,
             f
         ),
+        SYS_SET_ROBUST_LIST => format!(
+            "set_robust_list({:#X}, {})",
+            b,
+            c
+        ),
+        SYS_POLL => format!(
+            "poll({:#X}, {}, {:?})",
+            b,
+            c,
+            if d == 0 {
+                None
+            } else {
+                Some(validate_slice(d as *const TimeSpec, 1))
+            }
+        ),
+        SYS_SPLICE => format!(
+            "splice({}, {}, {}, {:#X})",
+            b,
+            c,
+            d,
+            e
+        ),
+        SYS_TEE => format!(
+            "tee({}, {}, {}, {:#X})",
+            b,
+            c,
+            d,
+            e
+        ),
         SYS_GETCWD => format!(
             "getcwd({:#X}, {})",
             b,
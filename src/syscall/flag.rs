@@ -0,0 +1,122 @@
+//! Syscall flags
+//!
+//! Re-exports the flags from the `syscall` crate, along with those for kernel
+//! interfaces that have not been published there yet
+
+pub use super::syscall::flag::*;
+
+/// Mask of the owner context ID stored in a robust futex word
+pub const FUTEX_TID_MASK: u32 = 0x3FFF_FFFF;
+/// Set by the kernel when the owner of a robust futex exited without unlocking it
+pub const FUTEX_OWNER_DIED: u32 = 0x4000_0000;
+/// Set by userspace when there are waiters on a robust futex
+pub const FUTEX_WAITERS: u32 = 0x8000_0000;
+
+/// Get the first lock that would block a `Flock` request
+pub const F_GETLK: usize = 5;
+/// Set or clear a byte-range lock, failing with `EAGAIN` on conflict
+pub const F_SETLK: usize = 6;
+/// Set or clear a byte-range lock, waiting on conflict
+pub const F_SETLKW: usize = 7;
+
+/// Get the timeout of requests to a user scheme, in milliseconds, on its scheme handle
+pub const F_GETTIMEOUT: usize = 1100;
+/// Set the timeout of requests to a user scheme, in milliseconds, on its scheme handle. Zero waits forever
+pub const F_SETTIMEOUT: usize = 1101;
+
+/// Get the number of connections a `chan:` listener holds before refusing more
+pub const F_GETBACKLOG: usize = 1102;
+/// Set the number of connections a `chan:` listener holds before refusing more
+pub const F_SETBACKLOG: usize = 1103;
+
+/// Set the capacity of a pipe, rounded up to whole pages
+pub const F_SETPIPE_SZ: usize = 1031;
+/// Get the capacity of a pipe
+pub const F_GETPIPE_SZ: usize = 1032;
+
+/// Writes to a pipe of up to this many bytes are not interleaved with other writes
+pub const PIPE_BUF: usize = 4096;
+
+/// Do not block on pipes in `splice` and `tee`
+pub const SPLICE_F_NONBLOCK: usize = 2;
+
+/// Mask of the file type in a mode
+pub const MODE_TYPE: u16 = 0xF000;
+/// File type of a symbolic link
+pub const MODE_SYMLINK: u16 = 0xA000;
+/// File type of a local socket
+pub const MODE_SOCK: u16 = 0xC000;
+
+/// Open a symbolic link itself, instead of the file it points to
+pub const O_SYMLINK: usize = 0x4000_0000;
+/// Fail with `ELOOP` if the path is a symbolic link
+pub const O_NOFOLLOW: usize = 0x8000_0000;
+
+/// Shared byte-range lock
+pub const F_RDLCK: usize = 0;
+/// Exclusive byte-range lock
+pub const F_WRLCK: usize = 1;
+/// Remove a byte-range lock
+pub const F_UNLCK: usize = 2;
+
+/// Shared `flock` lock
+pub const LOCK_SH: usize = 1;
+/// Exclusive `flock` lock
+pub const LOCK_EX: usize = 2;
+/// Do not block when locking with `flock`
+pub const LOCK_NB: usize = 4;
+/// Remove a `flock` lock
+pub const LOCK_UN: usize = 8;
+
+/// Resolve relative paths of `*at` syscalls against the current working directory
+pub const AT_FDCWD: usize = -100isize as usize;
+/// Get the status of a symbolic link itself with `fstatat`
+pub const AT_SYMLINK_NOFOLLOW: usize = 0x100;
+/// Remove a directory with `unlinkat`
+pub const AT_REMOVEDIR: usize = 0x200;
+
+/// Mask of the readiness flags of an `Event`
+pub const EVENT_INTEREST: usize = 0xFFFF;
+/// Merge events of a registration until they are read, instead of queueing one per signal
+pub const EVENT_EDGE: usize = 0x1_0000;
+/// Like `EVENT_EDGE`, and queue the event again after it is read, until the file is no longer ready
+pub const EVENT_LEVEL: usize = 0x2_0000;
+/// Disarm the registration after its first event, until it is modified
+pub const EVENT_ONESHOT: usize = 0x4_0000;
+/// Mask of the event modes
+pub const EVENT_MODE: usize = EVENT_EDGE | EVENT_LEVEL | EVENT_ONESHOT;
+/// Change an existing registration, fails with `ENOENT` if there is none
+pub const EVENT_MODIFY: usize = 0x10_0000;
+/// Remove an existing registration, fails with `ENOENT` if there is none
+pub const EVENT_REMOVE: usize = 0x20_0000;
+/// Set in `PollFd::revents` when the descriptor is not open
+pub const EVENT_INVALID: usize = 0x40_0000;
+
+/// Number of entries in the submission queue of a `ring:` handle
+pub const RING_SQ_ENTRIES: usize = 128;
+/// Number of entries in the completion queue of a `ring:` handle
+pub const RING_CQ_ENTRIES: usize = 256;
+/// Offset of the submission queue in the memory of a `ring:` handle
+pub const RING_SQ_OFFSET: usize = 4096;
+/// Offset of the completion queue in the memory of a `ring:` handle
+pub const RING_CQ_OFFSET: usize = 3 * 4096;
+/// Size of the memory of a `ring:` handle
+pub const RING_SIZE: usize = 4 * 4096;
+
+/// Number of packets in the request queue of a user scheme buffer
+pub const USER_BUFFER_RQ_ENTRIES: usize = 64;
+/// Number of packets in the answer queue of a user scheme buffer
+pub const USER_BUFFER_AQ_ENTRIES: usize = 64;
+/// Offset of the request queue in a user scheme buffer
+pub const USER_BUFFER_RQ_OFFSET: usize = 4096;
+/// Offset of the answer queue in a user scheme buffer
+pub const USER_BUFFER_AQ_OFFSET: usize = 2 * 4096;
+/// Offset of the first data slot in a user scheme buffer
+pub const USER_BUFFER_SLOT_OFFSET: usize = 3 * 4096;
+
+pub const RING_OP_NOP: usize = 0;
+pub const RING_OP_READ: usize = 1;
+pub const RING_OP_WRITE: usize = 2;
+pub const RING_OP_FSYNC: usize = 3;
+pub const RING_OP_OPEN: usize = 4;
+pub const RING_OP_CLOSE: usize = 5;
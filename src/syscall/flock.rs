@@ -0,0 +1,434 @@
+//! # File locking
+//! Advisory whole-file locks (`flock`) and byte-range locks (`fcntl` with `F_SETLK`).
+//!
+//! Locks are keyed by the scheme and the device and inode reported by `fstat`, so that
+//! different descriptions of the same file see each other's locks. `flock` locks belong to
+//! a file description and are released when it is closed for the last time. Byte-range
+//! locks belong to a process, identified by its file table, and are released when it
+//! closes any descriptor to the file or exits.
+use alloc::sync::Arc;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::u64;
+use spin::{Mutex, MutexGuard, Once};
+
+use context::{self, ContextId};
+use context::file::{FileDescription, FileDescriptor};
+use scheme::{self, SchemeId};
+use sync::WaitCondition;
+use syscall::data::{Flock, Stat};
+use syscall::error::*;
+use syscall::flag::{F_GETLK, F_SETLKW, F_RDLCK, F_WRLCK, F_UNLCK, LOCK_SH, LOCK_EX, LOCK_NB, LOCK_UN,
+                    O_ACCMODE, O_RDONLY, O_RDWR, O_WRONLY, SEEK_SET, SEEK_CUR, SEEK_END};
+use syscall::validate::validate_slice_mut;
+
+/// Identifies a locked file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LockKey {
+    pub scheme: SchemeId,
+    pub dev: u64,
+    pub ino: u64,
+}
+
+/// Owner of a lock
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockOwner {
+    /// `flock` lock, owned by a file description
+    Description(usize),
+    /// `fcntl` lock, owned by the file table of a process
+    Process(usize),
+}
+
+impl LockOwner {
+    fn same_kind(&self, other: &LockOwner) -> bool {
+        match (*self, *other) {
+            (LockOwner::Description(_), LockOwner::Description(_)) => true,
+            (LockOwner::Process(_), LockOwner::Process(_)) => true,
+            _ => false
+        }
+    }
+}
+
+/// A held or requested lock on the byte range `start..end`
+#[derive(Clone, Copy, Debug)]
+struct FileLock {
+    owner: LockOwner,
+    pid: ContextId,
+    exclusive: bool,
+    start: u64,
+    end: u64,
+}
+
+impl FileLock {
+    fn conflicts(&self, other: &FileLock) -> bool {
+        self.owner != other.owner
+        && self.owner.same_kind(&other.owner)
+        && (self.exclusive || other.exclusive)
+        && self.start < other.end
+        && other.start < self.end
+    }
+}
+
+struct LockFile {
+    locks: Vec<FileLock>,
+    condition: Arc<WaitCondition>,
+}
+
+struct LockRegistry {
+    files: BTreeMap<LockKey, LockFile>,
+    /// Requests of owners blocked in `F_SETLKW` or `flock`, used for deadlock detection
+    waiting: BTreeMap<LockOwner, (LockKey, FileLock)>,
+}
+
+impl LockRegistry {
+    fn conflicts(&self, key: &LockKey, lock: &FileLock) -> Vec<FileLock> {
+        match self.files.get(key) {
+            Some(file) => file.locks.iter().filter(|other| other.conflicts(lock)).cloned().collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Check if waiting for `blockers` would make `owner` wait on itself
+    fn deadlock(&self, owner: LockOwner, blockers: &[FileLock]) -> bool {
+        let mut visited = Vec::new();
+        let mut stack: Vec<LockOwner> = blockers.iter().map(|lock| lock.owner).collect();
+        while let Some(blocker) = stack.pop() {
+            if blocker == owner {
+                return true;
+            }
+            if visited.contains(&blocker) {
+                continue;
+            }
+            visited.push(blocker);
+
+            if let Some(&(key, request)) = self.waiting.get(&blocker) {
+                for lock in self.conflicts(&key, &request) {
+                    stack.push(lock.owner);
+                }
+            }
+        }
+        false
+    }
+
+    /// Remove the part of `owner`'s locks that overlaps `start..end`
+    fn unlock(&mut self, key: &LockKey, owner: LockOwner, start: u64, end: u64) {
+        let remove = if let Some(file) = self.files.get_mut(key) {
+            let mut locks = Vec::with_capacity(file.locks.len());
+            for lock in file.locks.drain(..) {
+                if lock.owner != owner || lock.end <= start || end <= lock.start {
+                    locks.push(lock);
+                    continue;
+                }
+
+                if lock.start < start {
+                    locks.push(FileLock { end: start, ..lock });
+                }
+                if end < lock.end {
+                    locks.push(FileLock { start: end, ..lock });
+                }
+            }
+            file.locks = locks;
+            file.condition.notify();
+            file.locks.is_empty()
+        } else {
+            false
+        };
+
+        if remove {
+            self.files.remove(key);
+        }
+    }
+
+    fn unlock_all(&mut self, owner: LockOwner) {
+        let keys: Vec<LockKey> = self.files.iter()
+            .filter(|&(_key, file)| file.locks.iter().any(|lock| lock.owner == owner))
+            .map(|(key, _file)| *key)
+            .collect();
+
+        for key in keys.iter() {
+            self.unlock(key, owner, 0, u64::MAX);
+        }
+    }
+
+    fn owns_any(&self, owner: LockOwner) -> bool {
+        self.files.values().any(|file| file.locks.iter().any(|lock| lock.owner == owner))
+    }
+}
+
+static LOCKS: Once<Mutex<LockRegistry>> = Once::new();
+
+/// Initialize the lock registry, called if needed
+fn init_locks() -> Mutex<LockRegistry> {
+    Mutex::new(LockRegistry {
+        files: BTreeMap::new(),
+        waiting: BTreeMap::new(),
+    })
+}
+
+/// Get the global lock registry
+fn locks() -> MutexGuard<'static, LockRegistry> {
+    LOCKS.call_once(init_locks).lock()
+}
+
+/// Acquire `lock`, optionally blocking until conflicting locks are released
+fn acquire(key: LockKey, lock: FileLock, block: bool) -> Result<()> {
+    loop {
+        let condition = {
+            let mut registry = locks();
+
+            let blockers = registry.conflicts(&key, &lock);
+            if blockers.is_empty() {
+                // Converting or replacing existing locks of the same owner
+                registry.unlock(&key, lock.owner, lock.start, lock.end);
+                registry.files.entry(key).or_insert_with(|| LockFile {
+                    locks: Vec::new(),
+                    condition: Arc::new(WaitCondition::new()),
+                }).locks.push(lock);
+                return Ok(());
+            }
+
+            if ! block {
+                return Err(Error::new(EAGAIN));
+            }
+
+            if registry.deadlock(lock.owner, &blockers) {
+                return Err(Error::new(EDEADLK));
+            }
+
+            let condition = match registry.files.get(&key) {
+                Some(file) => Arc::clone(&file.condition),
+                None => continue
+            };
+
+            registry.waiting.insert(lock.owner, (key, lock));
+
+            condition
+        };
+
+        let waited = condition.wait();
+
+        locks().waiting.remove(&lock.owner);
+
+        if ! waited {
+            return Err(Error::new(EINTR));
+        }
+    }
+}
+
+fn file_key(description: &FileDescription) -> Result<LockKey> {
+    let scheme = {
+        let schemes = scheme::schemes();
+        let scheme = schemes.get(description.scheme).ok_or(Error::new(EBADF))?;
+        Arc::clone(&scheme)
+    };
+
+    let mut stat = Stat::default();
+    scheme.fstat(description.number, &mut stat)?;
+
+    Ok(LockKey {
+        scheme: description.scheme,
+        dev: stat.st_dev,
+        ino: stat.st_ino,
+    })
+}
+
+fn description_owner(file: &FileDescriptor) -> LockOwner {
+    LockOwner::Description(&*file.description as *const _ as usize)
+}
+
+fn current_owner() -> Result<(LockOwner, ContextId)> {
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    Ok((LockOwner::Process(&*context.files as *const _ as usize), context.id))
+}
+
+/// Apply or remove a `flock` style lock on the whole file
+pub fn flock(file: &FileDescriptor, operation: usize) -> Result<usize> {
+    let owner = description_owner(file);
+    let key = file_key(&file.description.read())?;
+
+    let exclusive = match operation & !LOCK_NB {
+        LOCK_SH => false,
+        LOCK_EX => true,
+        LOCK_UN => {
+            locks().unlock(&key, owner, 0, u64::MAX);
+            return Ok(0);
+        },
+        _ => return Err(Error::new(EINVAL))
+    };
+
+    let (_process, pid) = current_owner()?;
+
+    acquire(key, FileLock {
+        owner: owner,
+        pid: pid,
+        exclusive: exclusive,
+        start: 0,
+        end: u64::MAX,
+    }, operation & LOCK_NB != LOCK_NB)?;
+
+    Ok(0)
+}
+
+/// The byte range `start..end` locked by `l_start` and `l_len` from `base`. A length of zero
+/// extends to the end of the file, which is `u64::MAX`, a negative length locks before start
+fn lock_range(base: i64, l_start: i64, l_len: i64) -> Result<(u64, u64)> {
+    let mut start = base.checked_add(l_start).ok_or(Error::new(EINVAL))?;
+    if l_len == 0 {
+        if start < 0 {
+            return Err(Error::new(EINVAL));
+        }
+        return Ok((start as u64, u64::MAX));
+    }
+
+    let mut end = start.checked_add(l_len).ok_or(Error::new(EINVAL))?;
+    if end < start {
+        let tmp = start;
+        start = end;
+        end = tmp;
+    }
+    if start < 0 {
+        return Err(Error::new(EINVAL));
+    }
+
+    Ok((start as u64, end as u64))
+}
+
+/// Handle `F_GETLK`, `F_SETLK` and `F_SETLKW`, with `arg` pointing to a `Flock`
+pub fn fcntl_lock(file: &FileDescriptor, cmd: usize, arg: usize) -> Result<usize> {
+    let flock = validate_slice_mut(arg as *mut Flock, 1).map(|flock| &mut flock[0])?;
+
+    let (owner, pid) = current_owner()?;
+
+    let (key, base, accmode) = {
+        let description = file.description.read();
+
+        let base = match flock.l_whence as usize {
+            SEEK_SET => 0,
+            SEEK_CUR | SEEK_END => {
+                let scheme = {
+                    let schemes = scheme::schemes();
+                    let scheme = schemes.get(description.scheme).ok_or(Error::new(EBADF))?;
+                    Arc::clone(&scheme)
+                };
+                if flock.l_whence as usize == SEEK_CUR {
+                    scheme.seek(description.number, 0, SEEK_CUR)? as i64
+                } else {
+                    let mut stat = Stat::default();
+                    scheme.fstat(description.number, &mut stat)?;
+                    stat.st_size as i64
+                }
+            },
+            _ => return Err(Error::new(EINVAL))
+        };
+
+        (file_key(&description)?, base, description.flags & O_ACCMODE)
+    };
+
+    let (start, end) = lock_range(base, flock.l_start, flock.l_len)?;
+
+    let exclusive = match flock.l_type as usize {
+        F_RDLCK => {
+            if accmode != O_RDONLY && accmode != O_RDWR {
+                return Err(Error::new(EBADF));
+            }
+            false
+        },
+        F_WRLCK => {
+            if accmode != O_WRONLY && accmode != O_RDWR {
+                return Err(Error::new(EBADF));
+            }
+            true
+        },
+        F_UNLCK => {
+            if cmd != F_GETLK {
+                locks().unlock(&key, owner, start, end);
+                return Ok(0);
+            }
+            false
+        },
+        _ => return Err(Error::new(EINVAL))
+    };
+
+    let lock = FileLock {
+        owner: owner,
+        pid: pid,
+        exclusive: exclusive,
+        start: start,
+        end: end,
+    };
+
+    if cmd == F_GETLK {
+        let blockers = locks().conflicts(&key, &lock);
+        if let Some(blocker) = blockers.first() {
+            flock.l_type = (if blocker.exclusive { F_WRLCK } else { F_RDLCK }) as i16;
+            flock.l_whence = SEEK_SET as i16;
+            flock.l_start = blocker.start as i64;
+            flock.l_len = if blocker.end == u64::MAX { 0 } else { (blocker.end - blocker.start) as i64 };
+            flock.l_pid = blocker.pid.into() as i32;
+        } else {
+            flock.l_type = F_UNLCK as i16;
+        }
+        Ok(0)
+    } else {
+        acquire(key, lock, cmd == F_SETLKW)?;
+        Ok(0)
+    }
+}
+
+/// Release the `flock` locks of a file description that is closed for the last time
+pub fn release_description(description: usize) {
+    let owner = LockOwner::Description(description);
+    let mut registry = locks();
+    if registry.owns_any(owner) {
+        registry.unlock_all(owner);
+    }
+}
+
+/// Release the current process' byte-range locks on the file behind `description`
+pub fn release_process_file(description: &FileDescription) {
+    let owner = match current_owner() {
+        Ok((owner, _pid)) => owner,
+        Err(_) => return
+    };
+
+    if ! locks().owns_any(owner) {
+        return;
+    }
+
+    if let Ok(key) = file_key(description) {
+        locks().unlock(&key, owner, 0, u64::MAX);
+    }
+}
+
+/// Release all byte-range locks owned by the file table at `files`, used on exit
+pub fn release_process(files: usize) {
+    let owner = LockOwner::Process(files);
+    let mut registry = locks();
+    if registry.owns_any(owner) {
+        registry.unlock_all(owner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::lock_range;
+    use syscall::error::{Error, EINVAL};
+
+    #[test]
+    fn lock_range_to_end() {
+        assert_eq!(lock_range(0, 0, 0), Ok((0, u64::max_value())));
+        assert_eq!(lock_range(100, 10, 0), Ok((110, u64::max_value())));
+        assert_eq!(lock_range(0, -1, 0), Err(Error::new(EINVAL)));
+    }
+
+    #[test]
+    fn lock_range_len() {
+        assert_eq!(lock_range(0, 10, 5), Ok((10, 15)));
+        assert_eq!(lock_range(20, 0, -5), Ok((15, 20)));
+        assert_eq!(lock_range(0, 3, -5), Err(Error::new(EINVAL)));
+        assert_eq!(lock_range(i64::max_value(), 1, 1), Err(Error::new(EINVAL)));
+        assert_eq!(lock_range(0, i64::max_value(), 1), Err(Error::new(EINVAL)));
+    }
+}
@@ -1,16 +1,28 @@
 //! Filesystem syscalls
+use core::cmp;
 use core::sync::atomic::Ordering;
 use alloc::sync::Arc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use spin::RwLock;
 
 use context;
-use scheme::{self, FileHandle};
+use scheme::{self, FileHandle, SchemeId, SchemeNamespace};
+use scheme::acl::{self, ACL_WRITE};
+use scheme::pipe;
+use scheme::user;
 use syscall;
+use syscall::flock;
 use syscall::data::{Packet, Stat};
 use syscall::error::*;
-use syscall::flag::{F_GETFD, F_SETFD, F_GETFL, F_SETFL, F_DUPFD, O_ACCMODE, O_DIRECTORY, O_RDONLY, O_WRONLY, MODE_DIR, MODE_FILE, O_CLOEXEC};
+use syscall::scheme::Scheme;
+use syscall::flag::{MODE_SYMLINK, MODE_TYPE, O_NOFOLLOW, O_STAT, O_SYMLINK, AT_FDCWD, AT_REMOVEDIR, AT_SYMLINK_NOFOLLOW, F_GETFD, F_SETFD, F_GETFL, F_SETFL, F_DUPFD, F_GETLK, F_SETLK, F_SETLKW, F_GETTIMEOUT, F_SETTIMEOUT, F_GETPIPE_SZ, F_SETPIPE_SZ, SPLICE_F_NONBLOCK, O_ACCMODE, O_CREAT, O_DIRECTORY, O_EXCL, O_RDONLY, O_WRONLY, MODE_DIR, MODE_FILE, O_CLOEXEC};
+use context::Context;
 use context::file::{FileDescriptor, FileDescription};
 
+/// Symbolic links followed by one open before failing with `ELOOP`
+pub const SYMLINK_MAX_HOPS: usize = 40;
+
 pub fn file_op(a: usize, fd: FileHandle, c: usize, d: usize) -> Result<usize> {
     let (file, pid, uid, gid) = {
         let contexts = context::contexts();
@@ -83,32 +95,144 @@ pub fn getcwd(buf: &mut [u8]) -> Result<usize> {
     Ok(i)
 }
 
+/// Canonicalize `path` relative to the directory open at `dirfd`, or to the
+/// current working directory if `dirfd` is `AT_FDCWD`
+fn canonicalize_at(dirfd: FileHandle, path: &[u8]) -> Result<Vec<u8>> {
+    let (file, scheme_ns) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        if dirfd.into() == AT_FDCWD || path.contains(&b':') {
+            return Ok(context.canonicalize(path));
+        }
+        (context.get_file(dirfd).ok_or(Error::new(EBADF))?, context.ens)
+    };
+
+    let (scheme_id, scheme, number) = {
+        let description = file.description.read();
+        let schemes = scheme::schemes();
+        let scheme = schemes.get(description.scheme).ok_or(Error::new(EBADF))?;
+        (description.scheme, Arc::clone(&scheme), description.number)
+    };
+
+    let mut stat = Stat::default();
+    scheme.fstat(number, &mut stat)?;
+    if stat.st_mode & (MODE_FILE | MODE_DIR) != MODE_DIR {
+        return Err(Error::new(ENOTDIR));
+    }
+
+    let mut dir = vec![0; 4096];
+    let count = scheme.fpath(number, &mut dir)?;
+    dir.truncate(count);
+    let dir = unresolve(scheme_ns, scheme_id, dir);
+
+    Ok(Context::canonicalize_in(&dir, path))
+}
+
+/// Rewrite a path reported by the scheme `scheme_id` to how it is reached from `scheme_ns`
+fn unresolve(scheme_ns: SchemeNamespace, scheme_id: SchemeId, path: Vec<u8>) -> Vec<u8> {
+    let schemes = scheme::schemes();
+    schemes.unresolve(scheme_ns, scheme_id, &path).unwrap_or(path)
+}
+
+/// Get the path of a file, as it is reached from the namespace of the current context
+pub fn fpath(fd: FileHandle, buf: &mut [u8]) -> Result<usize> {
+    let (file, scheme_ns) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        (context.get_file(fd).ok_or(Error::new(EBADF))?, context.ens)
+    };
+
+    let (scheme_id, scheme, number) = {
+        let description = file.description.read();
+        let schemes = scheme::schemes();
+        let scheme = schemes.get(description.scheme).ok_or(Error::new(EBADF))?;
+        (description.scheme, Arc::clone(&scheme), description.number)
+    };
+
+    // Remapping may make the path longer than the buffer
+    let mut path = vec![0; cmp::max(buf.len(), 4096)];
+    let count = scheme.fpath(number, &mut path)?;
+    path.truncate(count);
+    let path = unresolve(scheme_ns, scheme_id, path);
+
+    let mut i = 0;
+    while i < buf.len() && i < path.len() {
+        buf[i] = path[i];
+        i += 1;
+    }
+    Ok(i)
+}
+
 /// Open syscall
 pub fn open(path: &[u8], flags: usize) -> Result<FileHandle> {
-    let (path_canon, uid, gid, scheme_ns, umask) = {
+    openat(FileHandle::from(AT_FDCWD), path, flags)
+}
+
+/// Open a path relative to a directory
+pub fn openat(dirfd: FileHandle, path: &[u8], flags: usize) -> Result<FileHandle> {
+    let mut path_canon = canonicalize_at(dirfd, path)?;
+    let (uid, gid, scheme_ns, umask) = {
         let contexts = context::contexts();
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
-        (context.canonicalize(path), context.euid, context.egid, context.ens, context.umask)
+        (context.euid, context.egid, context.ens, context.umask)
     };
 
     let flags = (flags & (!0o777)) | (flags & 0o777) & (!(umask & 0o777));
 
     //println!("open {}", unsafe { ::core::str::from_utf8_unchecked(&path_canon) });
 
-    let mut parts = path_canon.splitn(2, |&b| b == b':');
-    let scheme_name_opt = parts.next();
-    let reference_opt = parts.next();
+    let mut hops = 0;
+    let (scheme_id, file_id) = loop {
+        let target = {
+            let mut parts = path_canon.splitn(2, |&b| b == b':');
+            let scheme_name_opt = parts.next();
+            let reference_opt = parts.next();
 
-    let (scheme_id, file_id) = {
-        let scheme_name = scheme_name_opt.ok_or(Error::new(ENODEV))?;
-        let (scheme_id, scheme) = {
-            let schemes = scheme::schemes();
-            let (scheme_id, scheme) = schemes.get_name(scheme_ns, scheme_name).ok_or(Error::new(ENODEV))?;
-            (scheme_id, Arc::clone(&scheme))
+            let scheme_name = scheme_name_opt.ok_or(Error::new(ENODEV))?;
+            let (scheme_id, scheme, reference) = {
+                let schemes = scheme::schemes();
+                let (scheme_id, scheme, reference) = schemes.resolve(scheme_ns, scheme_name, reference_opt.unwrap_or(b"")).ok_or(Error::new(ENODEV))?;
+                schemes.check_access(scheme_ns, scheme_name, uid, gid, acl::open_access(flags))?;
+                (scheme_id, Arc::clone(&scheme), reference)
+            };
+
+            // Links are followed here, so that they may point to other schemes. On schemes that
+            // can serve links, every open first probes the path with a stat-only open of the
+            // link itself, which costs an open, an fstat and a close more. The flags of the
+            // caller only apply to the open of the file that is not a link. An exclusive create
+            // fails on a link instead of following it
+            let follow = flags & O_SYMLINK != O_SYMLINK
+                && flags & (O_CREAT | O_EXCL) != (O_CREAT | O_EXCL)
+                && may_link(scheme_id);
+            let target_opt = if ! follow {
+                None
+            } else {
+                // A path that cannot be probed, for example one about to be created, is no link
+                match scheme.open(&reference, O_STAT | O_SYMLINK, uid, gid) {
+                    Ok(probe_id) => {
+                        let result = read_link(&scheme, probe_id, flags, hops);
+                        let _ = scheme.close(probe_id);
+                        result?
+                    },
+                    Err(_) => None
+                }
+            };
+
+            match target_opt {
+                Some(target) => target,
+                None => break (scheme_id, scheme.open(&reference, flags, uid, gid)?)
+            }
         };
-        let file_id = scheme.open(reference_opt.unwrap_or(b""), flags, uid, gid)?;
-        (scheme_id, file_id)
+
+        let dir = match path_canon.iter().rposition(|&b| b == b'/' || b == b':') {
+            Some(i) => path_canon[..i + 1].to_vec(),
+            None => Vec::new()
+        };
+        path_canon = Context::canonicalize_in(&dir, &target);
+        hops += 1;
     };
 
     let contexts = context::contexts();
@@ -124,6 +248,35 @@ pub fn open(path: &[u8], flags: usize) -> Result<FileHandle> {
     }).ok_or(Error::new(EMFILE))
 }
 
+/// Whether files of a scheme may be symbolic links, which is the case for `initfs:` and the
+/// schemes of daemons
+fn may_link(scheme_id: SchemeId) -> bool {
+    scheme_id == ::scheme::initfs::INITFS_SCHEME_ID.load(Ordering::SeqCst) || user::user_scheme(scheme_id).is_some()
+}
+
+/// If `file_id` is a symbolic link, return its target
+///
+/// A scheme marks a link by reporting `MODE_SYMLINK` from `fstat`, and reading the link
+/// returns the target. Relative targets are resolved against the directory of the link. A
+/// scheme that finds a link in the middle of a path appends the rest of the path to the
+/// target it returns
+fn read_link(scheme: &Arc<Box<Scheme + Send + Sync>>, file_id: usize, flags: usize, hops: usize) -> Result<Option<Vec<u8>>> {
+    let mut stat = Stat::default();
+    if scheme.fstat(file_id, &mut stat).is_err() || stat.st_mode & MODE_TYPE != MODE_SYMLINK {
+        return Ok(None);
+    }
+
+    if flags & O_NOFOLLOW == O_NOFOLLOW || hops >= SYMLINK_MAX_HOPS {
+        return Err(Error::new(ELOOP));
+    }
+
+    let mut target = vec![0; 4096];
+    let count = scheme.read(file_id, &mut target)?;
+    target.truncate(count);
+
+    Ok(Some(target))
+}
+
 pub fn pipe2(fds: &mut [usize], flags: usize) -> Result<usize> {
     if fds.len() >= 2 {
         let scheme_id = ::scheme::pipe::PIPE_SCHEME_ID.load(Ordering::SeqCst);
@@ -162,11 +315,17 @@ pub fn pipe2(fds: &mut [usize], flags: usize) -> Result<usize> {
 
 /// chmod syscall
 pub fn chmod(path: &[u8], mode: u16) -> Result<usize> {
-    let (path_canon, uid, gid, scheme_ns) = {
+    chmodat(FileHandle::from(AT_FDCWD), path, mode)
+}
+
+/// Change the mode of a path relative to a directory
+fn chmodat(dirfd: FileHandle, path: &[u8], mode: u16) -> Result<usize> {
+    let path_canon = canonicalize_at(dirfd, path)?;
+    let (uid, gid, scheme_ns) = {
         let contexts = context::contexts();
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
-        (context.canonicalize(path), context.euid, context.egid, context.ens)
+        (context.euid, context.egid, context.ens)
     };
 
     let mut parts = path_canon.splitn(2, |&b| b == b':');
@@ -174,21 +333,28 @@ pub fn chmod(path: &[u8], mode: u16) -> Result<usize> {
     let reference_opt = parts.next();
 
     let scheme_name = scheme_name_opt.ok_or(Error::new(ENODEV))?;
-    let scheme = {
+    let (scheme, reference) = {
         let schemes = scheme::schemes();
-        let (_scheme_id, scheme) = schemes.get_name(scheme_ns, scheme_name).ok_or(Error::new(ENODEV))?;
-        Arc::clone(&scheme)
+        let (_scheme_id, scheme, reference) = schemes.resolve(scheme_ns, scheme_name, reference_opt.unwrap_or(b"")).ok_or(Error::new(ENODEV))?;
+        schemes.check_access(scheme_ns, scheme_name, uid, gid, ACL_WRITE)?;
+        (Arc::clone(&scheme), reference)
     };
-    scheme.chmod(reference_opt.unwrap_or(b""), mode, uid, gid)
+    scheme.chmod(&reference, mode, uid, gid)
 }
 
 /// rmdir syscall
 pub fn rmdir(path: &[u8]) -> Result<usize> {
-    let (path_canon, uid, gid, scheme_ns) = {
+    rmdirat(FileHandle::from(AT_FDCWD), path)
+}
+
+/// Remove a directory relative to a directory
+fn rmdirat(dirfd: FileHandle, path: &[u8]) -> Result<usize> {
+    let path_canon = canonicalize_at(dirfd, path)?;
+    let (uid, gid, scheme_ns) = {
         let contexts = context::contexts();
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
-        (context.canonicalize(path), context.euid, context.egid, context.ens)
+        (context.euid, context.egid, context.ens)
     };
 
     let mut parts = path_canon.splitn(2, |&b| b == b':');
@@ -196,21 +362,28 @@ pub fn rmdir(path: &[u8]) -> Result<usize> {
     let reference_opt = parts.next();
 
     let scheme_name = scheme_name_opt.ok_or(Error::new(ENODEV))?;
-    let scheme = {
+    let (scheme, reference) = {
         let schemes = scheme::schemes();
-        let (_scheme_id, scheme) = schemes.get_name(scheme_ns, scheme_name).ok_or(Error::new(ENODEV))?;
-        Arc::clone(&scheme)
+        let (_scheme_id, scheme, reference) = schemes.resolve(scheme_ns, scheme_name, reference_opt.unwrap_or(b"")).ok_or(Error::new(ENODEV))?;
+        schemes.check_access(scheme_ns, scheme_name, uid, gid, ACL_WRITE)?;
+        (Arc::clone(&scheme), reference)
     };
-    scheme.rmdir(reference_opt.unwrap_or(b""), uid, gid)
+    scheme.rmdir(&reference, uid, gid)
 }
 
 /// Unlink syscall
 pub fn unlink(path: &[u8]) -> Result<usize> {
-    let (path_canon, uid, gid, scheme_ns) = {
+    unlinkat_file(FileHandle::from(AT_FDCWD), path)
+}
+
+/// Unlink a path relative to a directory
+fn unlinkat_file(dirfd: FileHandle, path: &[u8]) -> Result<usize> {
+    let path_canon = canonicalize_at(dirfd, path)?;
+    let (uid, gid, scheme_ns) = {
         let contexts = context::contexts();
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
-        (context.canonicalize(path), context.euid, context.egid, context.ens)
+        (context.euid, context.egid, context.ens)
     };
 
     let mut parts = path_canon.splitn(2, |&b| b == b':');
@@ -218,12 +391,51 @@ pub fn unlink(path: &[u8]) -> Result<usize> {
     let reference_opt = parts.next();
 
     let scheme_name = scheme_name_opt.ok_or(Error::new(ENODEV))?;
-    let scheme = {
+    let (scheme, reference) = {
         let schemes = scheme::schemes();
-        let (_scheme_id, scheme) = schemes.get_name(scheme_ns, scheme_name).ok_or(Error::new(ENODEV))?;
-        Arc::clone(&scheme)
+        let (_scheme_id, scheme, reference) = schemes.resolve(scheme_ns, scheme_name, reference_opt.unwrap_or(b"")).ok_or(Error::new(ENODEV))?;
+        schemes.check_access(scheme_ns, scheme_name, uid, gid, ACL_WRITE)?;
+        (Arc::clone(&scheme), reference)
     };
-    scheme.unlink(reference_opt.unwrap_or(b""), uid, gid)
+    scheme.unlink(&reference, uid, gid)
+}
+
+/// Unlink a path relative to a directory, or remove it as a directory with `AT_REMOVEDIR`
+pub fn unlinkat(dirfd: FileHandle, path: &[u8], flags: usize) -> Result<usize> {
+    if flags & !AT_REMOVEDIR != 0 {
+        return Err(Error::new(EINVAL));
+    }
+
+    if flags & AT_REMOVEDIR == AT_REMOVEDIR {
+        rmdirat(dirfd, path)
+    } else {
+        unlinkat_file(dirfd, path)
+    }
+}
+
+/// Create a directory relative to a directory
+pub fn mkdirat(dirfd: FileHandle, path: &[u8], mode: u16) -> Result<usize> {
+    let fd = openat(dirfd, path, O_CREAT | O_EXCL | O_DIRECTORY | O_RDONLY | (mode as usize & 0o777))?;
+    close(fd)
+}
+
+/// Get the status of a path relative to a directory
+pub fn fstatat(dirfd: FileHandle, path: &[u8], stat: &mut Stat, flags: usize) -> Result<usize> {
+    if flags & ! AT_SYMLINK_NOFOLLOW != 0 {
+        return Err(Error::new(EINVAL));
+    }
+
+    // Only the status is needed, so files that cannot be read can be inspected too
+    let open_flags = if flags & AT_SYMLINK_NOFOLLOW == AT_SYMLINK_NOFOLLOW {
+        O_STAT | O_SYMLINK
+    } else {
+        O_STAT
+    };
+
+    let fd = openat(dirfd, path, open_flags)?;
+    let stat_res = file_op_mut_slice(syscall::number::SYS_FSTAT, fd, stat);
+    let _ = close(fd);
+    stat_res
 }
 
 /// Close syscall
@@ -310,6 +522,11 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize) -> Result<usize> {
         context.get_file(fd).ok_or(Error::new(EBADF))?
     };
 
+    // Locks are tracked by the kernel, not the scheme
+    if cmd == F_GETLK || cmd == F_SETLK || cmd == F_SETLKW {
+        return flock::fcntl_lock(&file, cmd, arg);
+    }
+
     let description = file.description.read();
 
     // Communicate fcntl with scheme
@@ -319,7 +536,12 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize) -> Result<usize> {
             let scheme = schemes.get(description.scheme).ok_or(Error::new(EBADF))?;
             Arc::clone(&scheme)
         };
-        scheme.fcntl(description.number, cmd, arg)?;
+        let result = scheme.fcntl(description.number, cmd, arg)?;
+
+        // Handled by the scheme alone
+        if cmd == F_GETTIMEOUT || cmd == F_SETTIMEOUT || cmd == F_GETPIPE_SZ || cmd == F_SETPIPE_SZ {
+            return Ok(result);
+        }
     };
 
     // Perform kernel operation if scheme agrees
@@ -373,6 +595,123 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize) -> Result<usize> {
     }
 }
 
+/// Apply or remove an advisory lock on an open file
+pub fn flock(fd: FileHandle, operation: usize) -> Result<usize> {
+    let file = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        context.get_file(fd).ok_or(Error::new(EBADF))?
+    };
+
+    flock::flock(&file, operation)
+}
+
+/// Send a copy of `fd` to the scheme behind `socket`, which receives it as a new descriptor
+/// sharing the same file description. Returns the answer of the scheme
+pub fn sendfd(socket: FileHandle, fd: FileHandle, flags: usize) -> Result<usize> {
+    let (socket_file, file) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        let socket_file = context.get_file(socket).ok_or(Error::new(EBADF))?;
+        let file = context.get_file(fd).ok_or(Error::new(EBADF))?;
+        (socket_file, file)
+    };
+
+    let (scheme_id, number) = {
+        let description = socket_file.description.read();
+        (description.scheme, description.number)
+    };
+
+    let sent = FileDescriptor {
+        description: file.description,
+        cloexec: false,
+    };
+
+    if let Some(inner) = user::user_scheme(scheme_id) {
+        inner.send_fd(number, sent, flags)
+    } else {
+        Err(Error::new(EOPNOTSUPP))
+    }
+}
+
+/// Get the scheme and number of an open file
+fn file_number(fd: FileHandle) -> Result<(scheme::SchemeId, usize)> {
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    let file = context.get_file(fd).ok_or(Error::new(EBADF))?;
+    let description = file.description.read();
+    Ok((description.scheme, description.number))
+}
+
+/// Move up to `len` bytes from `fd_in` to `fd_out` inside the kernel. One of them must be a pipe
+pub fn splice(fd_in: FileHandle, fd_out: FileHandle, len: usize, flags: usize) -> Result<usize> {
+    let (scheme_in, number_in) = file_number(fd_in)?;
+    let (scheme_out, number_out) = file_number(fd_out)?;
+    let pipe_scheme = pipe::PIPE_SCHEME_ID.load(Ordering::SeqCst);
+    let nonblock = flags & SPLICE_F_NONBLOCK == SPLICE_F_NONBLOCK;
+
+    let read_opt = if scheme_in == pipe_scheme { pipe::pipe_read(number_in) } else { None };
+    let write_opt = if scheme_out == pipe_scheme { pipe::pipe_write(number_out) } else { None };
+
+    if let Some(read) = read_opt {
+        let data = read.take(len, nonblock)?;
+
+        let result = if let Some(write) = write_opt {
+            write.write_inner(&data, nonblock)
+        } else {
+            let scheme = {
+                let schemes = scheme::schemes();
+                let scheme = schemes.get(scheme_out).ok_or(Error::new(EBADF))?;
+                Arc::clone(&scheme)
+            };
+            scheme.write(number_out, &data)
+        };
+
+        // Whatever was not written stays in the pipe
+        let written = *result.as_ref().unwrap_or(&0);
+        if written < data.len() {
+            read.untake(&data[written..]);
+        }
+        result
+    } else if let Some(write) = write_opt {
+        let scheme = {
+            let schemes = scheme::schemes();
+            let scheme = schemes.get(scheme_in).ok_or(Error::new(EBADF))?;
+            Arc::clone(&scheme)
+        };
+
+        // Only as much is read as there is space for, so that nothing read is lost even if
+        // another writer fills the pipe in the meantime
+        let space = write.wait_space(nonblock)?;
+        let mut data = vec![0; cmp::min(len, space)];
+        let count = scheme.read(number_in, &mut data)?;
+        write.push(&data[..count])
+    } else {
+        Err(Error::new(EINVAL))
+    }
+}
+
+/// Copy up to `len` bytes from pipe `fd_in` to pipe `fd_out`, without consuming them
+pub fn tee(fd_in: FileHandle, fd_out: FileHandle, len: usize, flags: usize) -> Result<usize> {
+    let (scheme_in, number_in) = file_number(fd_in)?;
+    let (scheme_out, number_out) = file_number(fd_out)?;
+    let pipe_scheme = pipe::PIPE_SCHEME_ID.load(Ordering::SeqCst);
+    let nonblock = flags & SPLICE_F_NONBLOCK == SPLICE_F_NONBLOCK;
+
+    if scheme_in != pipe_scheme || scheme_out != pipe_scheme {
+        return Err(Error::new(EINVAL));
+    }
+
+    let read = pipe::pipe_read(number_in).ok_or(Error::new(EINVAL))?;
+    let write = pipe::pipe_write(number_out).ok_or(Error::new(EINVAL))?;
+
+    let data = read.peek(len, nonblock)?;
+    write.write_inner(&data, nonblock)
+}
+
 pub fn frename(fd: FileHandle, path: &[u8]) -> Result<usize> {
     let file = {
         let contexts = context::contexts();
@@ -394,16 +733,17 @@ pub fn frename(fd: FileHandle, path: &[u8]) -> Result<usize> {
     let reference_opt = parts.next();
 
     let scheme_name = scheme_name_opt.ok_or(Error::new(ENODEV))?;
-    let (scheme_id, scheme) = {
+    let (scheme_id, scheme, reference) = {
         let schemes = scheme::schemes();
-        let (scheme_id, scheme) = schemes.get_name(scheme_ns, scheme_name).ok_or(Error::new(ENODEV))?;
-        (scheme_id, scheme.clone())
+        let (scheme_id, scheme, reference) = schemes.resolve(scheme_ns, scheme_name, reference_opt.unwrap_or(b"")).ok_or(Error::new(ENODEV))?;
+        schemes.check_access(scheme_ns, scheme_name, uid, gid, ACL_WRITE)?;
+        (scheme_id, scheme.clone(), reference)
     };
 
     let description = file.description.read();
 
     if scheme_id == description.scheme {
-        scheme.frename(description.number, reference_opt.unwrap_or(b""), uid, gid)
+        scheme.frename(description.number, &reference, uid, gid)
     } else {
         Err(Error::new(EXDEV))
     }
@@ -4,37 +4,143 @@
 //! For more information about futexes, please read [this](https://eli.thegreenplace.net/2018/basics-of-futexes/) blog post, and the [futex(2)](http://man7.org/linux/man-pages/man2/futex.2.html) man page
 use alloc::sync::Arc;
 use alloc::collections::VecDeque;
-use core::intrinsics;
-use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use alloc::vec::Vec;
+use core::{intrinsics, mem};
+use spin::{Mutex, MutexGuard, Once, RwLock};
 
 use context::{self, Context};
+use paging::{ActivePageTable, VirtualAddress};
 use time;
-use syscall::data::TimeSpec;
-use syscall::error::{Error, Result, ESRCH, EAGAIN, EINVAL};
-use syscall::flag::{FUTEX_WAIT, FUTEX_WAKE, FUTEX_REQUEUE};
+use syscall::data::{RobustListHead, TimeSpec};
+use syscall::error::{Error, Result, ESRCH, EAGAIN, EFAULT, EINVAL};
+use syscall::flag::{FUTEX_WAIT, FUTEX_WAKE, FUTEX_REQUEUE, FUTEX_OWNER_DIED, FUTEX_TID_MASK, FUTEX_WAITERS};
 use syscall::validate::{validate_slice, validate_slice_mut};
 
-type FutexList = VecDeque<(usize, Arc<RwLock<Context>>)>;
+/// Number of buckets in the futex hash table, must be a power of two
+pub const FUTEX_BUCKETS: usize = 256;
 
-/// Fast userspace mutex list
-static FUTEXES: Once<RwLock<FutexList>> = Once::new();
+/// Limit on robust list entries walked on exit, protects against circular lists
+pub const ROBUST_LIST_LIMIT: usize = 2048;
+
+/// Identifies the word a futex waiter is sleeping on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FutexKey {
+    /// Word inside a grant, which may be mapped by other address spaces at other addresses
+    Shared {
+        frame: usize,
+        offset: usize
+    },
+    /// Word inside memory private to one address space
+    Private {
+        space: usize,
+        address: usize
+    }
+}
+
+impl FutexKey {
+    /// Resolve the key for `address` in the current context
+    pub fn current(address: usize) -> Result<FutexKey> {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+
+        let shared = {
+            let grants = context.grants.lock();
+            grants.iter().any(|grant| {
+                let start = grant.start_address().get();
+                address >= start && address < start + grant.size()
+            })
+        };
+
+        if shared {
+            let active_table = unsafe { ActivePageTable::new() };
+            let physical = active_table.translate(VirtualAddress::new(address)).ok_or(Error::new(EFAULT))?;
+            Ok(FutexKey::Shared {
+                frame: (physical.get() / 4096) * 4096,
+                offset: physical.get() % 4096
+            })
+        } else {
+            // Threads created with CLONE_VM share the grants list, so it identifies the address space
+            Ok(FutexKey::Private {
+                space: &*context.grants as *const _ as usize,
+                address: address
+            })
+        }
+    }
+
+    fn bucket(&self) -> usize {
+        let hash = match *self {
+            FutexKey::Shared { frame, offset } => frame.wrapping_mul(31).wrapping_add(offset),
+            FutexKey::Private { space, address } => space.wrapping_mul(31).wrapping_add(address),
+        };
+        // Futex words are 4 byte aligned, so the low bits carry no information
+        ((hash >> 2) ^ (hash >> 12)) & (FUTEX_BUCKETS - 1)
+    }
+}
+
+/// Waiters, with the key each one currently waits on. The key is only changed with the bucket
+/// it is queued in locked, and the bucket of the new key locked as well
+type FutexBucket = VecDeque<(Arc<Mutex<FutexKey>>, Arc<RwLock<Context>>)>;
+
+/// Fast userspace mutex table
+static FUTEXES: Once<Vec<Mutex<FutexBucket>>> = Once::new();
 
 /// Initialize futexes, called if needed
-fn init_futexes() -> RwLock<FutexList> {
-    RwLock::new(VecDeque::new())
+fn init_futexes() -> Vec<Mutex<FutexBucket>> {
+    let mut buckets = Vec::with_capacity(FUTEX_BUCKETS);
+    for _ in 0..FUTEX_BUCKETS {
+        buckets.push(Mutex::new(VecDeque::new()));
+    }
+    buckets
 }
 
-/// Get the global futexes list, const
-pub fn futexes() -> RwLockReadGuard<'static, FutexList> {
-    FUTEXES.call_once(init_futexes).read()
+/// Get the bucket that waiters on `key` are stored in
+pub fn futex_bucket(key: &FutexKey) -> MutexGuard<'static, FutexBucket> {
+    FUTEXES.call_once(init_futexes)[key.bucket()].lock()
 }
 
-/// Get the global futexes list, mutable
-pub fn futexes_mut() -> RwLockWriteGuard<'static, FutexList> {
-    FUTEXES.call_once(init_futexes).write()
+/// Get two buckets at once, locking in a consistent order to prevent deadlocks.
+/// If both keys hash to the same bucket, only one guard is returned
+fn futex_buckets(first: &FutexKey, second: &FutexKey) -> (MutexGuard<'static, FutexBucket>, Option<MutexGuard<'static, FutexBucket>>) {
+    let futexes = FUTEXES.call_once(init_futexes);
+    let (a, b) = (first.bucket(), second.bucket());
+    if a == b {
+        (futexes[a].lock(), None)
+    } else if a < b {
+        let guard_a = futexes[a].lock();
+        let guard_b = futexes[b].lock();
+        (guard_a, Some(guard_b))
+    } else {
+        let guard_b = futexes[b].lock();
+        let guard_a = futexes[a].lock();
+        (guard_a, Some(guard_b))
+    }
+}
+
+/// Wake up to `count` waiters on `key`, returning the number woken
+pub fn futex_wake(key: FutexKey, count: usize) -> usize {
+    let mut woken = 0;
+
+    let mut bucket = futex_bucket(&key);
+
+    let mut i = 0;
+    while i < bucket.len() && woken < count {
+        if *bucket[i].0.lock() == key {
+            if let Some(futex) = bucket.remove(i) {
+                futex.1.write().unblock();
+                woken += 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+
+    woken
 }
 
 pub fn futex(addr: &mut i32, op: usize, val: i32, val2: usize, addr2: *mut i32) -> Result<usize> {
+    let key = FutexKey::current(addr as *mut i32 as usize)?;
+
     match op {
         FUTEX_WAIT => {
             let timeout_opt = if val2 != 0 {
@@ -43,14 +149,17 @@ pub fn futex(addr: &mut i32, op: usize, val: i32, val2: usize, addr2: *mut i32)
                 None
             };
 
-            {
-                let mut futexes = futexes_mut();
+            let context_lock = {
+                let contexts = context::contexts();
+                let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+                Arc::clone(&context_lock)
+            };
+
+            // Changed by FUTEX_REQUEUE
+            let waiter_key = Arc::new(Mutex::new(key));
 
-                let context_lock = {
-                    let contexts = context::contexts();
-                    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
-                    Arc::clone(&context_lock)
-                };
+            {
+                let mut bucket = futex_bucket(&key);
 
                 if unsafe { intrinsics::atomic_load(addr) != val } {
                     return Err(Error::new(EAGAIN));
@@ -69,73 +178,75 @@ pub fn futex(addr: &mut i32, op: usize, val: i32, val2: usize, addr2: *mut i32)
                     context.block();
                 }
 
-                futexes.push_back((addr as *mut i32 as usize, context_lock));
+                bucket.push_back((Arc::clone(&waiter_key), Arc::clone(&context_lock)));
             }
 
             unsafe { context::switch(); }
 
-            if timeout_opt.is_some() {
-                let context_lock = {
-                    let contexts = context::contexts();
-                    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
-                    Arc::clone(&context_lock)
-                };
-
-                {
-                    let mut context = context_lock.write();
-                    context.wake = None;
+            // If woken by timeout or signal, the entry is still queued and must be removed from
+            // the bucket of the key it was last requeued to
+            loop {
+                let current = *waiter_key.lock();
+                let mut bucket = futex_bucket(&current);
+                if *waiter_key.lock() != current {
+                    continue;
                 }
+                if let Some(i) = bucket.iter().position(|futex| Arc::ptr_eq(&futex.1, &context_lock)) {
+                    bucket.remove(i);
+                }
+                break;
+            }
+
+            if timeout_opt.is_some() {
+                let mut context = context_lock.write();
+                context.wake = None;
             }
 
             Ok(0)
         },
         FUTEX_WAKE => {
-            let mut woken = 0;
-
-            {
-                let mut futexes = futexes_mut();
-
-                let mut i = 0;
-                while i < futexes.len() && (woken as i32) < val {
-                    if futexes[i].0 == addr as *mut i32 as usize {
-                        if let Some(futex) = futexes.swap_remove_back(i) {
-                            futex.1.write().unblock();
-                            woken += 1;
-                        }
-                    } else {
-                        i += 1;
-                    }
-                }
+            if val < 0 {
+                return Err(Error::new(EINVAL));
             }
-
-            Ok(woken)
+            Ok(futex_wake(key, val as usize))
         },
         FUTEX_REQUEUE => {
+            if val < 0 {
+                return Err(Error::new(EINVAL));
+            }
+
             let addr2_safe = validate_slice_mut(addr2, 1).map(|addr2_safe| &mut addr2_safe[0])?;
+            let key2 = FutexKey::current(addr2_safe as *mut i32 as usize)?;
 
             let mut woken = 0;
             let mut requeued = 0;
 
             {
-                let mut futexes = futexes_mut();
+                let (mut bucket, mut bucket2_opt) = futex_buckets(&key, &key2);
 
                 let mut i = 0;
-                while i < futexes.len() && (woken as i32) < val {
-                    if futexes[i].0 == addr as *mut i32 as usize {
-                        if let Some(futex) = futexes.swap_remove_back(i) {
+                while i < bucket.len() {
+                    if *bucket[i].0.lock() != key {
+                        i += 1;
+                    } else if (woken as i32) < val {
+                        if let Some(futex) = bucket.remove(i) {
                             futex.1.write().unblock();
                             woken += 1;
                         }
-                    } else {
-                        i += 1;
-                    }
-                }
-                while i < futexes.len() && requeued < val2 {
-                    if futexes[i].0 == addr as *mut i32 as usize {
-                        futexes[i].0 = addr2_safe as *mut i32 as usize;
+                    } else if requeued < val2 {
                         requeued += 1;
+                        if let Some(ref mut bucket2) = bucket2_opt {
+                            if let Some(futex) = bucket.remove(i) {
+                                *futex.0.lock() = key2;
+                                bucket2.push_back(futex);
+                            }
+                        } else {
+                            *bucket[i].0.lock() = key2;
+                            i += 1;
+                        }
+                    } else {
+                        break;
                     }
-                    i += 1;
                 }
             }
 
@@ -144,3 +255,84 @@ pub fn futex(addr: &mut i32, op: usize, val: i32, val2: usize, addr2: *mut i32)
         _ => Err(Error::new(EINVAL))
     }
 }
+
+/// Register the robust futex list of the current context
+pub fn set_robust_list(head: usize, len: usize) -> Result<usize> {
+    if len != mem::size_of::<RobustListHead>() {
+        return Err(Error::new(EINVAL));
+    }
+
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let mut context = context_lock.write();
+    context.robust_list = if head == 0 {
+        None
+    } else {
+        Some(head)
+    };
+
+    Ok(0)
+}
+
+/// Mark a robust futex owned by `tid` as abandoned, and wake one waiter
+fn robust_release(address: usize, tid: u32) -> Result<()> {
+    let word = validate_slice_mut(address as *mut u32, 1).map(|word| &mut word[0])?;
+
+    loop {
+        let old = unsafe { intrinsics::atomic_load(word) };
+        if old & FUTEX_TID_MASK != tid {
+            return Ok(());
+        }
+
+        let new = (old & FUTEX_WAITERS) | FUTEX_OWNER_DIED;
+        let (_, exchanged) = unsafe { intrinsics::atomic_cxchg(word, old, new) };
+        if exchanged {
+            if old & FUTEX_WAITERS == FUTEX_WAITERS {
+                futex_wake(FutexKey::current(address)?, 1);
+            }
+            return Ok(());
+        }
+    }
+}
+
+/// Release the robust futexes held by the current context. Must be called while the
+/// user memory is still mapped, before the context exits or execs
+pub fn robust_list_release() -> Result<()> {
+    let (tid, head_address) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let mut context = context_lock.write();
+        match context.robust_list.take() {
+            Some(head_address) => (context.id.into() as u32 & FUTEX_TID_MASK, head_address),
+            None => return Ok(())
+        }
+    };
+
+    let head = validate_slice(head_address as *const RobustListHead, 1).map(|head| head[0])?;
+
+    // Futexes that cannot be released are skipped, the walk only stops at a link that cannot
+    // be followed. The low bit of entry pointers is reserved for flags
+    let mut entry = head.list & !1;
+    let mut walked = 0;
+    while entry != head_address && entry != 0 && walked < ROBUST_LIST_LIMIT {
+        let next = match validate_slice(entry as *const usize, 1) {
+            Ok(next) => next[0] & !1,
+            Err(_) => break
+        };
+
+        // The pending entry is handled below, it may not be fully linked
+        if entry != head.list_op_pending & !1 {
+            let _ = robust_release((entry as isize).wrapping_add(head.futex_offset) as usize, tid);
+        }
+
+        entry = next;
+        walked += 1;
+    }
+
+    let pending = head.list_op_pending & !1;
+    if pending != 0 {
+        let _ = robust_release((pending as isize).wrapping_add(head.futex_offset) as usize, tid);
+    }
+
+    Ok(())
+}
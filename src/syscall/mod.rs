@@ -4,17 +4,18 @@
 
 extern crate syscall;
 
-pub use self::syscall::{data, error, flag, io, number, scheme};
+pub use self::syscall::{error, io, scheme};
 
 pub use self::driver::*;
 pub use self::fs::*;
-pub use self::futex::futex;
+pub use self::futex::{futex, set_robust_list};
+pub use self::poll::poll;
 pub use self::privilege::*;
 pub use self::process::*;
 pub use self::time::*;
 pub use self::validate::*;
 
-use self::data::{SigAction, TimeSpec};
+use self::data::{PollFd, SigAction, Stat, TimeSpec};
 use self::error::{Error, Result, ENOSYS};
 use self::number::*;
 
@@ -22,21 +23,36 @@ use context::ContextId;
 use interrupt::syscall::SyscallStack;
 use scheme::{FileHandle, SchemeNamespace};
 
+/// Syscall data structures
+pub mod data;
+
 /// Debug
 pub mod debug;
 
 /// Driver syscalls
 pub mod driver;
 
+/// Syscall flags
+pub mod flag;
+
+/// File locking
+pub mod flock;
+
 /// Filesystem syscalls
 pub mod fs;
 
 /// Fast userspace mutex
 pub mod futex;
 
+/// Syscall numbers
+pub mod number;
+
 /// Privilege syscalls
 pub mod privilege;
 
+/// Poll for readiness on several files
+pub mod poll;
+
 /// Process syscalls
 pub mod process;
 
@@ -57,12 +73,17 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize, bp: u
                 let fd = FileHandle::from(b);
                 match a & SYS_ARG {
                     SYS_ARG_SLICE => file_op_slice(a, fd, validate_slice(c as *const u8, d)?),
-                    SYS_ARG_MSLICE => file_op_mut_slice(a, fd, validate_slice_mut(c as *mut u8, d)?),
+                    SYS_ARG_MSLICE => match a {
+                        SYS_FPATH => fpath(fd, validate_slice_mut(c as *mut u8, d)?),
+                        _ => file_op_mut_slice(a, fd, validate_slice_mut(c as *mut u8, d)?)
+                    },
                     _ => match a {
                         SYS_CLOSE => close(fd),
                         SYS_DUP => dup(fd, validate_slice(c as *const u8, d)?).map(FileHandle::into),
                         SYS_DUP2 => dup2(fd, FileHandle::from(c), validate_slice(d as *const u8, e)?).map(FileHandle::into),
                         SYS_FCNTL => fcntl(fd, c, d),
+                        SYS_FLOCK => flock(fd, c),
+                        SYS_SENDFD => sendfd(fd, FileHandle::from(c), d),
                         SYS_FEXEC => fexec(fd, validate_slice(c as *const [usize; 2], d)?, validate_slice(e as *const [usize; 2], f)?),
                         SYS_FRENAME => frename(fd, validate_slice(c as *const u8, d)?),
                         SYS_FUNMAP => funmap(b),
@@ -87,8 +108,23 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize, bp: u
                         Some(validate_slice_mut(c as *mut TimeSpec, 1).map(|rem| &mut rem[0])?)
                     }
                 ),
+                SYS_OPENAT => openat(FileHandle::from(b), validate_slice(c as *const u8, d)?, e).map(FileHandle::into),
+                SYS_MKDIRAT => mkdirat(FileHandle::from(b), validate_slice(c as *const u8, d)?, e as u16),
+                SYS_FSTATAT => fstatat(FileHandle::from(b), validate_slice(c as *const u8, d)?, validate_slice_mut(e as *mut Stat, 1).map(|stat| &mut stat[0])?, f),
+                SYS_UNLINKAT => unlinkat(FileHandle::from(b), validate_slice(c as *const u8, d)?, e),
                 SYS_CLOCK_GETTIME => clock_gettime(b, validate_slice_mut(c as *mut TimeSpec, 1).map(|time| &mut time[0])?),
                 SYS_FUTEX => futex(validate_slice_mut(b as *mut i32, 1).map(|uaddr| &mut uaddr[0])?, c, d as i32, e, f as *mut i32),
+                SYS_SET_ROBUST_LIST => set_robust_list(b, c),
+                SYS_SPLICE => splice(FileHandle::from(b), FileHandle::from(c), d, e),
+                SYS_TEE => tee(FileHandle::from(b), FileHandle::from(c), d, e),
+                SYS_POLL => poll(
+                    validate_slice_mut(b as *mut PollFd, c)?,
+                    if d == 0 {
+                        None
+                    } else {
+                        Some(validate_slice(d as *const TimeSpec, 1).map(|timeout| &timeout[0])?)
+                    }
+                ),
                 SYS_BRK => brk(b),
                 SYS_GETPID => getpid().map(ContextId::into),
                 SYS_GETPGID => getpgid(ContextId::from(b)).map(ContextId::into),
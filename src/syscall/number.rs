@@ -0,0 +1,20 @@
+//! Syscall numbers
+//!
+//! Re-exports the numbers from the `syscall` crate, along with those for kernel
+//! interfaces that have not been published there yet
+
+pub use super::syscall::number::*;
+
+pub const SYS_SET_ROBUST_LIST: usize = 273;
+pub const SYS_FLOCK: usize = SYS_CLASS_FILE | 73;
+pub const SYS_SENDFD: usize = SYS_CLASS_FILE | 34;
+pub const SYS_OPENAT: usize = 257;
+pub const SYS_MKDIRAT: usize = 258;
+pub const SYS_FSTATAT: usize = 262;
+pub const SYS_UNLINKAT: usize = 263;
+pub const SYS_POLL: usize = 271;
+pub const SYS_SPLICE: usize = 275;
+pub const SYS_TEE: usize = 276;
+
+/// Sent by the kernel to a scheme daemon when the caller of request `b` gave up waiting
+pub const KSMSG_CANCEL: usize = SYS_CLASS_FILE | 0xFFFF;
@@ -0,0 +1,123 @@
+//! # Poll
+//! Wait for several file descriptors to become ready, using a temporary event queue that is
+//! registered on each file and removed again before returning
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use context;
+use event::{self, EventQueue, QueueKey, RegKey};
+use scheme::FileHandle;
+use syscall::data::{Event, PollFd, TimeSpec};
+use syscall::error::*;
+use syscall::flag::{EVENT_INTEREST, EVENT_INVALID};
+use time;
+
+/// Poll syscall, returns the number of entries in `fds` with events
+pub fn poll(fds: &mut [PollFd], timeout_opt: Option<&TimeSpec>) -> Result<usize> {
+    let mut reg_keys = Vec::with_capacity(fds.len());
+    {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        for pollfd in fds.iter_mut() {
+            pollfd.revents = 0;
+
+            // Negative descriptors are ignored, to allow disabling entries
+            if (pollfd.fd as isize) < 0 || pollfd.events & EVENT_INTEREST == 0 {
+                reg_keys.push(None);
+                continue;
+            }
+
+            let file = match context.get_file(FileHandle::from(pollfd.fd)) {
+                Some(file) => file,
+                None => {
+                    pollfd.revents = EVENT_INVALID;
+                    reg_keys.push(None);
+                    continue;
+                }
+            };
+            let description = file.description.read();
+            reg_keys.push(Some(RegKey {
+                scheme: description.scheme,
+                number: description.number
+            }));
+        }
+    }
+
+    let queue_id = event::next_queue_id();
+    let queue = Arc::new(EventQueue::new(queue_id));
+    event::queues_mut().insert(queue_id, Arc::clone(&queue));
+
+    let result = poll_queue(&queue, fds, &reg_keys, timeout_opt);
+
+    for reg_key in event::unregister_queue(queue_id) {
+        let _ = event::send_flags(reg_key);
+    }
+    event::queues_mut().remove(&queue_id);
+
+    result
+}
+
+fn poll_queue(queue: &EventQueue, fds: &mut [PollFd], reg_keys: &[Option<RegKey>], timeout_opt: Option<&TimeSpec>) -> Result<usize> {
+    for (i, reg_key_opt) in reg_keys.iter().enumerate() {
+        if let Some(reg_key) = *reg_key_opt {
+            event::register(reg_key, QueueKey { queue: queue.id(), id: i, data: i }, fds[i].events & EVENT_INTEREST);
+            event::send_flags(reg_key)?;
+        }
+    }
+
+    let end_opt = timeout_opt.map(|timeout| {
+        let start = time::monotonic();
+        let sum = start.1 + timeout.tv_nsec as u64;
+        (start.0 + timeout.tv_sec as u64 + sum / 1_000_000_000, sum % 1_000_000_000)
+    });
+
+    let context_lock = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        Arc::clone(&context_lock)
+    };
+
+    // Descriptors that are not open are reported right away
+    let invalid = fds.iter().filter(|pollfd| pollfd.revents & EVENT_INVALID == EVENT_INVALID).count();
+
+    let mut events = [Event::default(); 16];
+    loop {
+        let mut ready = invalid;
+        loop {
+            let count = queue.read_nonblock(&mut events);
+            for event in events[..count].iter() {
+                if let Some(pollfd) = fds.get_mut(event.data) {
+                    if pollfd.revents == 0 {
+                        ready += 1;
+                    }
+                    pollfd.revents |= event.flags;
+                }
+            }
+            if count < events.len() {
+                break;
+            }
+        }
+
+        if ready > 0 {
+            return Ok(ready);
+        }
+
+        if let Some(end) = end_opt {
+            if time::monotonic() >= end {
+                return Ok(0);
+            }
+            context_lock.write().wake = Some(end);
+        }
+
+        let waited = queue.wait();
+
+        if end_opt.is_some() {
+            context_lock.write().wake = None;
+        }
+
+        if ! waited && end_opt.map_or(true, |end| time::monotonic() < end) && queue.is_empty() {
+            return Err(Error::new(EINTR));
+        }
+    }
+}
@@ -60,12 +60,13 @@ pub fn mkns(name_ptrs: &[[usize; 2]]) -> Result<usize> {
         (context.euid, context.ens)
     };
 
-    if uid == 0 {
-        let to = scheme::schemes_mut().make_ns(from, &names)?;
-        Ok(to.into())
-    } else {
-        Err(Error::new(EACCES))
+    // A namespace can only narrow access, so anyone may make one from their own
+    let mut schemes = scheme::schemes_mut();
+    let to = schemes.make_ns(from, &names)?;
+    if uid != 0 {
+        schemes.restrict(to);
     }
+    Ok(to.into())
 }
 
 pub fn setregid(rgid: u32, egid: u32) -> Result<usize> {
@@ -121,6 +122,22 @@ pub fn setregid(rgid: u32, egid: u32) -> Result<usize> {
 }
 
 pub fn setrens(rns: SchemeNamespace, ens: SchemeNamespace) -> Result<usize> {
+    let (current_rns, current_ens) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        (context.rns, context.ens)
+    };
+
+    // Namespaces made from the current ones can be entered, but not left
+    let (rns_within, ens_within) = {
+        let schemes = scheme::schemes();
+        (
+            schemes.is_within(rns, current_ens) || schemes.is_within(rns, current_rns),
+            schemes.is_within(ens, current_ens) || schemes.is_within(ens, current_rns)
+        )
+    };
+
     let contexts = context::contexts();
     let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
     let mut context = context_lock.write();
@@ -141,6 +158,9 @@ pub fn setrens(rns: SchemeNamespace, ens: SchemeNamespace) -> Result<usize> {
         } else if rns == context.rns {
             // Allow setting RNS if used for RNS
             true
+        } else if rns_within {
+            // Allow setting RNS to a namespace made from ENS or RNS
+            true
         } else if rns.into() as isize == -1 {
             // Ignore RNS if -1 is passed
             false
@@ -165,6 +185,9 @@ pub fn setrens(rns: SchemeNamespace, ens: SchemeNamespace) -> Result<usize> {
         } else if ens == context.rns {
             // Allow setting ENS if used for RNS
             true
+        } else if ens_within {
+            // Allow setting ENS to a namespace made from ENS or RNS
+            true
         } else if ens.into() as isize == -1 {
             // Ignore ENS if -1 is passed
             false
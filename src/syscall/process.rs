@@ -4,9 +4,10 @@ use alloc::vec::Vec;
 use core::alloc::{GlobalAlloc, Layout};
 use core::{intrinsics, mem};
 use core::ops::DerefMut;
+use core::sync::atomic::Ordering;
 use spin::Mutex;
 
-use memory::allocate_frames;
+use memory::{allocate_frames, PAGE_TABLE_FRAMES};
 use paging::{ActivePageTable, InactivePageTable, Page, VirtualAddress};
 use paging::entry::EntryFlags;
 use paging::temporary_page::TemporaryPage;
@@ -18,9 +19,10 @@ use context::file::FileDescriptor;
 #[cfg(not(feature="doc"))]
 use elf::{self, program_header};
 use ipi::{ipi, IpiKind, IpiTarget};
-use scheme::FileHandle;
+use scheme::{self, FileHandle};
+use scheme::signalfd;
 use syscall;
-use syscall::data::{SigAction, Stat};
+use syscall::data::{SigAction, SigInfo, Stat};
 use syscall::error::*;
 use syscall::flag::{CLONE_VFORK, CLONE_VM, CLONE_FS, CLONE_FILES, CLONE_SIGHAND, SIG_DFL, SIGCONT, SIGTERM, WCONTINUED, WNOHANG, WUNTRACED, wifcontinued, wifstopped};
 use syscall::validate::{validate_slice, validate_slice_mut};
@@ -90,6 +92,7 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<ContextId> {
         let cwd;
         let files;
         let actions;
+        let signalfds;
 
         // Copy from old process
         {
@@ -278,6 +281,9 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<ContextId> {
             } else {
                 actions = Arc::new(Mutex::new(context.actions.lock().clone()));
             }
+
+            // The child shares the signal descriptors, so it has them receive its signals too
+            signalfds = context.signalfds.clone();
         }
 
         // If not cloning files, dup to get a new number from scheme
@@ -341,6 +347,7 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<ContextId> {
 
             let mut new_table = {
                 let frame = allocate_frames(1).expect("no more frames in syscall::clone new_table");
+                PAGE_TABLE_FRAMES.fetch_add(1, Ordering::SeqCst);
                 InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
             };
 
@@ -478,6 +485,8 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<ContextId> {
             context.files = files;
 
             context.actions = actions;
+
+            context.signalfds = signalfds;
         }
     }
 
@@ -780,11 +789,11 @@ fn fexec_noreturn(
 }
 
 pub fn fexec_kernel(fd: FileHandle, args: Box<[Box<[u8]>]>, vars: Box<[Box<[u8]>]>) -> Result<usize> {
-    let (uid, gid) = {
+    let (uid, gid, scheme_ns) = {
         let contexts = context::contexts();
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
-        (context.euid, context.egid)
+        (context.euid, context.egid, context.ens)
     };
 
     let mut stat: Stat;
@@ -812,7 +821,7 @@ pub fn fexec_kernel(fd: FileHandle, args: Box<[Box<[u8]>]>, vars: Box<[Box<[u8]>
         }
 
         name = vec![0; 4096];
-        let len = syscall::file_op_mut_slice(syscall::number::SYS_FPATH, file.0, &mut name)?;
+        let len = syscall::fpath(file.0, &mut name)?;
         name.truncate(len);
 
         //TODO: Only read elf header, not entire file. Then read required segments
@@ -821,14 +830,21 @@ pub fn fexec_kernel(fd: FileHandle, args: Box<[Box<[u8]>]>, vars: Box<[Box<[u8]>
         drop(file);
     }
 
-    // Set UID and GID are determined after resolving any hashbangs
-    let setuid = if stat.st_mode & syscall::flag::MODE_SETUID == syscall::flag::MODE_SETUID {
+    // Set UID and GID are determined after resolving any hashbangs, and ignored in
+    // namespaces made without privileges
+    let restricted = scheme::schemes().is_restricted(scheme_ns);
+
+    let setuid = if restricted {
+        None
+    } else if stat.st_mode & syscall::flag::MODE_SETUID == syscall::flag::MODE_SETUID {
         Some(stat.st_uid)
     } else {
         None
     };
 
-    let setgid = if stat.st_mode & syscall::flag::MODE_SETGID == syscall::flag::MODE_SETGID {
+    let setgid = if restricted {
+        None
+    } else if stat.st_mode & syscall::flag::MODE_SETGID == syscall::flag::MODE_SETGID {
         Some(stat.st_gid)
     } else {
         None
@@ -874,6 +890,7 @@ pub fn fexec_kernel(fd: FileHandle, args: Box<[Box<[u8]>]>, vars: Box<[Box<[u8]>
     // This is the point of no return, quite literaly. Any checks for validity need
     // to be done before, and appropriate errors returned. Otherwise, we have nothing
     // to return to.
+    let _ = syscall::futex::robust_list_release();
     fexec_noreturn(setuid, setgid, name.into_boxed_slice(), data.into_boxed_slice(), args, vars);
 }
 
@@ -898,6 +915,9 @@ pub fn fexec(fd: FileHandle, arg_ptrs: &[[usize; 2]], var_ptrs: &[[usize; 2]]) -
 }
 
 pub fn exit(status: usize) -> ! {
+    // Robust futexes must be released while user memory is still mapped
+    let _ = syscall::futex::robust_list_release();
+
     {
         let context_lock = {
             let contexts = context::contexts();
@@ -912,6 +932,7 @@ pub fn exit(status: usize) -> ! {
             // Is it possible for Arc::strong_count to return 1 to two contexts that exit at the
             // same time, or return 2 to both, thus either double closing or leaking the files?
             if Arc::strong_count(&context.files) == 1 {
+                syscall::flock::release_process(&*context.files as *const _ as usize);
                 mem::swap(context.files.lock().deref_mut(), &mut close_files);
             }
             context.files = Arc::new(Mutex::new(Vec::new()));
@@ -1031,21 +1052,24 @@ pub fn getppid() -> Result<ContextId> {
 }
 
 pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
-    let (ruid, euid, current_pgid) = {
+    let (current_pid, ruid, euid, current_pgid) = {
         let contexts = context::contexts();
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
-        (context.ruid, context.euid, context.pgid)
+        (context.id, context.ruid, context.euid, context.pgid)
     };
 
     if sig < 0x7F {
         let mut found = 0;
         let mut sent = 0;
 
+        // Signals for signal descriptors, queued once no context is locked
+        let mut routed = Vec::new();
+
         {
             let contexts = context::contexts();
 
-            let send = |context: &mut context::Context| -> bool {
+            let mut send = |context: &mut context::Context| -> bool {
                 if euid == 0
                 || euid == context.ruid
                 || ruid == context.ruid
@@ -1053,7 +1077,11 @@ pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
                     // If sig = 0, test that process exists and can be
                     // signalled, but don't send any signal.
                     if sig != 0 {
-                        context.pending.push_back(sig as u8);
+                        if let Some(signalfd) = signalfd::route(context, sig) {
+                            routed.push(signalfd);
+                        } else {
+                            context.pending.push_back(sig as u8);
+                        }
                         // Convert stopped processes to blocked if sending SIGCONT
                         if sig == SIGCONT {
                             if let context::Status::Stopped(_sig) = context.status {
@@ -1112,6 +1140,14 @@ pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
             }
         }
 
+        for signalfd in routed {
+            signalfd.send(SigInfo {
+                si_signo: sig,
+                si_pid: current_pid.into(),
+                si_uid: ruid as usize,
+            });
+        }
+
         if found == 0 {
             Err(Error::new(ESRCH))
         } else if sent == 0 {